@@ -0,0 +1,107 @@
+use libactionkv::net::Client;
+
+mod cli;
+
+#[cfg(target_os = "windows")]
+const USAGE: &str = "
+Usage:
+    akv_client.exe ADDR get KEY
+    akv_client.exe ADDR delete KEY
+    akv_client.exe ADDR insert KEY VALUE
+    akv_client.exe ADDR update KEY VALUE
+    akv_client.exe ADDR get-with-version KEY
+    akv_client.exe ADDR cas KEY VERSION VALUE
+    akv_client.exe ADDR insert-if-absent KEY VALUE
+    akv_client.exe ADDR delete-if-version KEY VERSION
+    akv_client.exe ADDR list
+    akv_client.exe ADDR scan START [END]
+";
+
+#[cfg(not(target_os = "windows"))]
+const USAGE: &str = "
+Usage:
+    akv_client ADDR get KEY
+    akv_client ADDR delete KEY
+    akv_client ADDR insert KEY VALUE
+    akv_client ADDR update KEY VALUE
+    akv_client ADDR get-with-version KEY
+    akv_client ADDR cas KEY VERSION VALUE
+    akv_client ADDR insert-if-absent KEY VALUE
+    akv_client ADDR delete-if-version KEY VERSION
+    akv_client ADDR list
+    akv_client ADDR scan START [END]
+";
+
+fn main() {
+  let args: Vec<String> = std::env::args().collect();
+  let addr = args.get(1).unwrap_or_else(|| cli::usage(USAGE));
+  let action = args.get(2).unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+  let maybe_key = args.get(3);
+  let maybe_value = args.get(4);
+
+  let mut client = cli::or_exit(Client::connect(addr.as_str()));
+
+  match action {
+    "get" => {
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      match cli::or_exit(client.get(key)) {
+        None => eprintln!("{:?} not found", key),
+        Some(value) => println!("{:?}", value),
+      }
+    },
+
+    "delete" => {
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      cli::or_exit(client.delete(key))
+    },
+
+    "insert" => {
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      let value = maybe_value.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      cli::or_exit(client.insert(key, value))
+    },
+
+    "update" => {
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      let value = maybe_value.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      cli::or_exit(client.update(key, value))
+    },
+
+    "get-with-version" => {
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      match cli::or_exit(client.get_with_version(key)) {
+        None => eprintln!("{:?} not found", key),
+        Some((value, version)) => println!("{} {:?}", version, value),
+      }
+    },
+
+    "cas" => {
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      let version = cli::version(maybe_value, USAGE);
+      let value = args.get(5).unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      println!("{}", cli::or_exit(client.compare_and_swap(key, version, value)))
+    },
+
+    "insert-if-absent" => {
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      let value = maybe_value.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      println!("{}", cli::or_exit(client.insert_if_absent(key, value)))
+    },
+
+    "delete-if-version" => {
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      let version = cli::version(maybe_value, USAGE);
+      cli::or_exit(client.delete_if_version(key, version))
+    },
+
+    "list" => cli::print_pairs(cli::or_exit(client.scan(b"", None)).into_iter().map(Ok)),
+
+    "scan" => {
+      let start = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_bytes();
+      let end = maybe_value.map(|end| end.as_bytes());
+      cli::print_pairs(cli::or_exit(client.scan(start, end)).into_iter().map(Ok))
+    },
+
+    _ => cli::usage(USAGE),
+  }
+}
@@ -1,13 +1,70 @@
-use libactionkv::ActionKV;
-use std::collections::HashMap;
+mod cli;
 
 #[cfg(target_os = "windows")]
 const USAGE: &str = "
 Usage:
   akv_disk.exe FILE get KEY
   akv_disk.exe FILE delete KEY
-  akv_disk.exe FILE insert KEY VALUE
+  akv_disk.exe FILE insert KEY VALUE [--ttl DURATION]
   akv_disk.exe FILE update KEY VALUE
+  akv_disk.exe FILE get-with-version KEY
+  akv_disk.exe FILE cas KEY VERSION VALUE
+  akv_disk.exe FILE insert-if-absent KEY VALUE
+  akv_disk.exe FILE delete-if-version KEY VERSION
+  akv_disk.exe FILE list
+  akv_disk.exe FILE scan START [END]
+  akv_disk.exe FILE tail [PREFIX]
+  akv_disk.exe FILE history KEY
+  akv_disk.exe FILE stats
+  akv_disk.exe FILE export FORMAT [OUT]
+  akv_disk.exe FILE import FORMAT [IN]
+  akv_disk.exe FILE compact
+  akv_disk.exe FILE check
+  akv_disk.exe FILE repair
+
+FORMAT is jsonl, csv or bincode. OUT and IN default to stdout and stdin.
+tail prints each change as it is written, until interrupted. It opens FILE
+for reading without locking it, so that another process can write to it
+meanwhile. history prints every version of KEY still in the log, oldest
+first.
+
+cas, insert-if-absent and delete-if-version only write if KEY is still at
+VERSION, as printed by get-with-version, or absent. cas and
+insert-if-absent print the new version. A conflict exits with status 3.
+
+Options:
+  --sync POLICY          always, never (the default), an interval such as
+                         100ms or 2s, or a write count such as 50writes
+  --engine ENGINE        log (the default) or lsm, which keeps FILE as
+                         a directory of sorted tables; an existing LSM
+                         store is always opened this way
+  --segment-size BYTES   keep FILE as a directory of segments that roll
+                         over at BYTES; an existing directory is always
+                         opened this way
+  --compression ALGO     compress new values with lz4, zstd or zstd:LEVEL
+                         (none by default)
+  --bloom RATE           keep a bloom filter per segment with false
+                         positive rate RATE, such as 0.01, so that
+                         history can skip segments without KEY
+  --key-file PATH        encrypt FILE with the key in PATH, either 32
+                         raw bytes or 64 hex digits; without it, the key
+                         is taken from AKV_KEY if that is set
+  --read-only            open FILE for reading only, sharing it with
+                         other readers; without it, FILE is locked
+                         against any other process that opens it
+  --ttl DURATION         expire the key after DURATION, such as 500ms,
+                         30s, 10m or 2h
+
+Exit status:
+  1  check found damage
+  2  the arguments are invalid
+  3  a conditional write found a conflict
+  4  reading or writing failed
+  5  FILE is damaged; see check and repair
+  6  the encryption key is wrong
+  7  a key or value is too large for a record
+  8  FILE is open in another process
+  9  FILE needs a newer version of akv, or an upgrade with compact
 ";
 
 #[cfg(not(target_os = "windows"))]
@@ -15,65 +72,208 @@ const USAGE: &str = "
 Usage:
   akv_disk FILE get KEY
   akv_disk FILE delete KEY
-  avk_disk FILE insert KEY VALUE
-  avk_disk FILE update KEY VALUE
-";
+  akv_disk FILE insert KEY VALUE [--ttl DURATION]
+  akv_disk FILE update KEY VALUE
+  akv_disk FILE get-with-version KEY
+  akv_disk FILE cas KEY VERSION VALUE
+  akv_disk FILE insert-if-absent KEY VALUE
+  akv_disk FILE delete-if-version KEY VERSION
+  akv_disk FILE list
+  akv_disk FILE scan START [END]
+  akv_disk FILE tail [PREFIX]
+  akv_disk FILE history KEY
+  akv_disk FILE stats
+  akv_disk FILE export FORMAT [OUT]
+  akv_disk FILE import FORMAT [IN]
+  akv_disk FILE compact
+  akv_disk FILE check
+  akv_disk FILE repair
+
+FORMAT is jsonl, csv or bincode. OUT and IN default to stdout and stdin.
+tail prints each change as it is written, until interrupted. It opens FILE
+for reading without locking it, so that another process can write to it
+meanwhile. history prints every version of KEY still in the log, oldest
+first.
 
-type ByteString = Vec<u8>;
-type ByteStr = [u8];
+cas, insert-if-absent and delete-if-version only write if KEY is still at
+VERSION, as printed by get-with-version, or absent. cas and
+insert-if-absent print the new version. A conflict exits with status 3.
 
-fn store_index_on_disk(a: &mut ActionKV, index_key: &ByteStr) {
-  a.index.remove(index_key);
-  let index_as_bytes = bincode::serialize(&a.index).unwrap();
-  a.index = std::collections::HashMap::new();
-  a.insert(index_key, &index_as_bytes).unwrap();
-}
+Options:
+  --sync POLICY          always, never (the default), an interval such as
+                         100ms or 2s, or a write count such as 50writes
+  --engine ENGINE        log (the default) or lsm, which keeps FILE as
+                         a directory of sorted tables; an existing LSM
+                         store is always opened this way
+  --segment-size BYTES   keep FILE as a directory of segments that roll
+                         over at BYTES; an existing directory is always
+                         opened this way
+  --compression ALGO     compress new values with lz4, zstd or zstd:LEVEL
+                         (none by default)
+  --bloom RATE           keep a bloom filter per segment with false
+                         positive rate RATE, such as 0.01, so that
+                         history can skip segments without KEY
+  --key-file PATH        encrypt FILE with the key in PATH, either 32
+                         raw bytes or 64 hex digits; without it, the key
+                         is taken from AKV_KEY if that is set
+  --read-only            open FILE for reading only, sharing it with
+                         other readers; without it, FILE is locked
+                         against any other process that opens it
+  --ttl DURATION         expire the key after DURATION, such as 500ms,
+                         30s, 10m or 2h
 
+Exit status:
+  1  check found damage
+  2  the arguments are invalid
+  3  a conditional write found a conflict
+  4  reading or writing failed
+  5  FILE is damaged; see check and repair
+  6  the encryption key is wrong
+  7  a key or value is too large for a record
+  8  FILE is open in another process
+  9  FILE needs a newer version of akv, or an upgrade with compact
+";
+
+/// Where akv_disk kept its index before it had hint files.
+const LEGACY_INDEX_KEY: &[u8] = b"+index";
+
+// akv_disk closes the store after every change, which refreshes the hint
+// file next to FILE so that the next run can skip scanning the log.
 fn main() {
-  const INDEX_KEY: &ByteStr = b"+index";
+  let mut args: Vec<String> = std::env::args().collect();
+  let mut options = cli::open_options(&mut args, USAGE);
+  let ttl = cli::ttl(&mut args, USAGE);
 
-  let args: Vec<String> = std::env::args().collect();
-  let fname = args.get(1).expect(&USAGE);
-  let action = args.get(2).expect(&USAGE).as_ref();
-  let key = args.get(3).expect(&USAGE).as_ref();
+  let fname = args.get(1).unwrap_or_else(|| cli::usage(USAGE));
+  let action = args.get(2).unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+  let maybe_key = args.get(3);
   let maybe_value = args.get(4);
 
   let path = std::path::Path::new(&fname);
-  let mut a = ActionKV::open(path).expect("unable to open file");
-
-  a.load().expect("unable to load data");
+  options.follow(action == "tail");
+  let mut a = cli::open(options.ordered(true), path);
 
+  // These work on damaged files, so they run before `load()`
   match action {
-    "get" => {
-      let index_as_bytes = a.get(&INDEX_KEY).unwrap().unwrap();
+    "check" => {
+      let report = cli::or_exit(a.check());
+      print!("{}", report);
+      if !report.is_clean() {
+        std::process::exit(cli::EXIT_DAMAGED);
+      }
+      return;
+    }
+
+    "repair" => {
+      let report = cli::or_exit(a.repair());
+      print!("{}", report);
+      cli::or_exit(a.close());
+      return;
+    }
 
-      let index_decoded = bincode::deserialize(&index_as_bytes);
-      
-      let index: HashMap<ByteString, u64> = index_decoded.unwrap();
+    _ => {}
+  }
+
+  cli::or_exit(a.load());
 
-      match index.get(key) {
+  match action {
+    "get" => {
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      match cli::or_exit(a.get(key)) {
         None => eprintln!("{:?} not found", key),
-        Some(&i) => {
-          let kv = a.get_at(i).unwrap();
-          println!("{:?}", kv.value)
-        }
+        Some(value) => println!("{:?}", value),
       }
     }
 
-    "delete" => a.delete(key).unwrap(),
+    "delete" => {
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      cli::or_exit(a.delete(key));
+      cli::or_exit(a.close());
+    }
 
     "insert" => {
-      let value = maybe_value.expect(&USAGE).as_ref();
-      a.update(key, value).unwrap();
-      store_index_on_disk(&mut a, INDEX_KEY);
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      let value = maybe_value.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      match ttl {
+        Some(ttl) => cli::or_exit(a.insert_with_ttl(key, value, ttl)),
+        None => cli::or_exit(a.update(key, value)),
+      }
+      cli::or_exit(a.close());
     }
 
     "update" => {
-      let value = maybe_value.expect(&USAGE).as_ref();
-      a.update(key, value).unwrap();
-      store_index_on_disk(&mut a, INDEX_KEY);
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      let value = maybe_value.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      cli::or_exit(a.update(key, value));
+      cli::or_exit(a.close());
+    }
+
+    "get-with-version" => {
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      match cli::or_exit(a.get_with_version(key)) {
+        None => eprintln!("{:?} not found", key),
+        Some((value, version)) => println!("{} {:?}", version, value),
+      }
+    }
+
+    "cas" => {
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      let version = cli::version(maybe_value, USAGE);
+      let value = args.get(5).unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      println!("{}", cli::or_exit(a.compare_and_swap(key, version, value)));
+      cli::or_exit(a.close());
+    }
+
+    "insert-if-absent" => {
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      let value = maybe_value.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      println!("{}", cli::or_exit(a.insert_if_absent(key, value)));
+      cli::or_exit(a.close());
+    }
+
+    "delete-if-version" => {
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      let version = cli::version(maybe_value, USAGE);
+      cli::or_exit(a.delete_if_version(key, version));
+      cli::or_exit(a.close());
+    }
+
+    "list" => cli::print_pairs(a.iter()),
+
+    "scan" => {
+      let start = maybe_key.unwrap_or_else(|| cli::usage(USAGE));
+      cli::print_pairs(a.scan(cli::scan_range(start, maybe_value)))
+    }
+
+    "tail" => cli::or_exit(cli::tail(&mut a, maybe_key)),
+
+    "history" => {
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      cli::or_exit(cli::history(&mut a, key))
+    }
+
+    "stats" => print!("{}", cli::or_exit(a.stats())),
+
+    "export" => {
+      let format = maybe_key.unwrap_or_else(|| cli::usage(USAGE));
+      cli::or_exit(cli::export(&a, format, maybe_value, USAGE))
+    }
+
+    "import" => {
+      let format = maybe_key.unwrap_or_else(|| cli::usage(USAGE));
+      cli::or_exit(cli::import(&mut a, format, maybe_value, USAGE));
+      cli::or_exit(a.close());
+    }
+
+    "compact" => {
+      // Before hint files, akv_disk kept its index under this key. It is
+      // of no use now, so it goes with the first compaction
+      if cli::or_exit(a.get(LEGACY_INDEX_KEY)).is_some() {
+        cli::or_exit(a.delete(LEGACY_INDEX_KEY));
+      }
+      cli::or_exit(a.compact())
     }
 
-    _ => eprintln!("{}", &USAGE),
+    _ => cli::usage(USAGE),
   }
 }
\ No newline at end of file
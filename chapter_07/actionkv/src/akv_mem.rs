@@ -1,52 +1,263 @@
-use libactionkv::ActionKV;
+mod cli;
 
 #[cfg(target_os = "windows")]
 const USAGE: &str = "
 Usage:
-    akv_mem FILE get KEY
-    akv_mem FILE delete KEY
-    akv_mem FILE insert KEY VALUE
-    akv_mem FILE update KEY VALUE
+    akv_mem.exe FILE get KEY
+    akv_mem.exe FILE delete KEY
+    akv_mem.exe FILE insert KEY VALUE [--ttl DURATION]
+    akv_mem.exe FILE update KEY VALUE
+    akv_mem.exe FILE get-with-version KEY
+    akv_mem.exe FILE cas KEY VERSION VALUE
+    akv_mem.exe FILE insert-if-absent KEY VALUE
+    akv_mem.exe FILE delete-if-version KEY VERSION
+    akv_mem.exe FILE list
+    akv_mem.exe FILE scan START [END]
+    akv_mem.exe FILE tail [PREFIX]
+    akv_mem.exe FILE history KEY
+    akv_mem.exe FILE stats
+    akv_mem.exe FILE export FORMAT [OUT]
+    akv_mem.exe FILE import FORMAT [IN]
+    akv_mem.exe FILE compact
+    akv_mem.exe FILE check
+    akv_mem.exe FILE repair
+
+FORMAT is jsonl, csv or bincode. OUT and IN default to stdout and stdin.
+tail prints each change as it is written, until interrupted. It opens FILE
+for reading without locking it, so that another process can write to it
+meanwhile. history prints every version of KEY still in the log, oldest
+first.
+
+cas, insert-if-absent and delete-if-version only write if KEY is still at
+VERSION, as printed by get-with-version, or absent. cas and
+insert-if-absent print the new version. A conflict exits with status 3.
+
+Options:
+    --sync POLICY          always, never (the default), an interval such as
+                           100ms or 2s, or a write count such as 50writes
+    --engine ENGINE        log (the default) or lsm, which keeps FILE as
+                           a directory of sorted tables; an existing LSM
+                           store is always opened this way
+    --segment-size BYTES   keep FILE as a directory of segments that roll
+                           over at BYTES; an existing directory is always
+                           opened this way
+    --compression ALGO     compress new values with lz4, zstd or zstd:LEVEL
+                           (none by default)
+    --bloom RATE           keep a bloom filter per segment with false
+                           positive rate RATE, such as 0.01, so that
+                           history can skip segments without KEY
+    --key-file PATH        encrypt FILE with the key in PATH, either 32
+                           raw bytes or 64 hex digits; without it, the key
+                           is taken from AKV_KEY if that is set
+    --read-only            open FILE for reading only, sharing it with
+                           other readers; without it, FILE is locked
+                           against any other process that opens it
+    --ttl DURATION         expire the key after DURATION, such as 500ms,
+                           30s, 10m or 2h
+
+Exit status:
+    1  check found damage
+    2  the arguments are invalid
+    3  a conditional write found a conflict
+    4  reading or writing failed
+    5  FILE is damaged; see check and repair
+    6  the encryption key is wrong
+    7  a key or value is too large for a record
+    8  FILE is open in another process
+    9  FILE needs a newer version of akv, or an upgrade with compact
 ";
 
-#[cfg(not(target_os = "widnows"))]
+#[cfg(not(target_os = "windows"))]
 const USAGE: &str = "
 Usage:
     akv_mem FILE get KEY
     akv_mem FILE delete KEY
-    akv_mem FILE insert KEY VALUE
+    akv_mem FILE insert KEY VALUE [--ttl DURATION]
     akv_mem FILE update KEY VALUE
+    akv_mem FILE get-with-version KEY
+    akv_mem FILE cas KEY VERSION VALUE
+    akv_mem FILE insert-if-absent KEY VALUE
+    akv_mem FILE delete-if-version KEY VERSION
+    akv_mem FILE list
+    akv_mem FILE scan START [END]
+    akv_mem FILE tail [PREFIX]
+    akv_mem FILE history KEY
+    akv_mem FILE stats
+    akv_mem FILE export FORMAT [OUT]
+    akv_mem FILE import FORMAT [IN]
+    akv_mem FILE compact
+    akv_mem FILE check
+    akv_mem FILE repair
+
+FORMAT is jsonl, csv or bincode. OUT and IN default to stdout and stdin.
+tail prints each change as it is written, until interrupted. It opens FILE
+for reading without locking it, so that another process can write to it
+meanwhile. history prints every version of KEY still in the log, oldest
+first.
+
+cas, insert-if-absent and delete-if-version only write if KEY is still at
+VERSION, as printed by get-with-version, or absent. cas and
+insert-if-absent print the new version. A conflict exits with status 3.
+
+Options:
+    --sync POLICY          always, never (the default), an interval such as
+                           100ms or 2s, or a write count such as 50writes
+    --engine ENGINE        log (the default) or lsm, which keeps FILE as
+                           a directory of sorted tables; an existing LSM
+                           store is always opened this way
+    --segment-size BYTES   keep FILE as a directory of segments that roll
+                           over at BYTES; an existing directory is always
+                           opened this way
+    --compression ALGO     compress new values with lz4, zstd or zstd:LEVEL
+                           (none by default)
+    --bloom RATE           keep a bloom filter per segment with false
+                           positive rate RATE, such as 0.01, so that
+                           history can skip segments without KEY
+    --key-file PATH        encrypt FILE with the key in PATH, either 32
+                           raw bytes or 64 hex digits; without it, the key
+                           is taken from AKV_KEY if that is set
+    --read-only            open FILE for reading only, sharing it with
+                           other readers; without it, FILE is locked
+                           against any other process that opens it
+    --ttl DURATION         expire the key after DURATION, such as 500ms,
+                           30s, 10m or 2h
+
+Exit status:
+    1  check found damage
+    2  the arguments are invalid
+    3  a conditional write found a conflict
+    4  reading or writing failed
+    5  FILE is damaged; see check and repair
+    6  the encryption key is wrong
+    7  a key or value is too large for a record
+    8  FILE is open in another process
+    9  FILE needs a newer version of akv, or an upgrade with compact
 ";
 
 fn main() {
-  let args: Vec<String> = std::env::args().collect();
-  let fname = args.get(1).expect(&USAGE);
-  let action = args.get(2).expect(&USAGE).as_ref();
-  let key = args.get(3).expect(&USAGE).as_ref();
+  let mut args: Vec<String> = std::env::args().collect();
+  let mut options = cli::open_options(&mut args, USAGE);
+  let ttl = cli::ttl(&mut args, USAGE);
+
+  let fname = args.get(1).unwrap_or_else(|| cli::usage(USAGE));
+  let action = args.get(2).unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+  let maybe_key = args.get(3);
   let maybe_value = args.get(4);
 
   let path = std::path::Path::new(&fname);
-  let mut store = ActionKV::open(path).expect("unable to open file");
-  store.load().expect("unable to load data");
+  options.follow(action == "tail");
+  let mut store = cli::open(options.ordered(true), path);
 
+  // These work on damaged files, so they run before `load()`
   match action {
-    "get" => match store.get(key).unwrap() {
-      None => eprintln!("{:?} not found", key),
-      Some(value) => println!("{:?}", value),
+    "check" => {
+      let report = cli::or_exit(store.check());
+      print!("{}", report);
+      if !report.is_clean() {
+        std::process::exit(cli::EXIT_DAMAGED);
+      }
+      return;
+    },
+
+    "repair" => {
+      let report = cli::or_exit(store.repair());
+      print!("{}", report);
+      cli::or_exit(store.close());
+      return;
     },
 
-    "delete" => store.delete(key).unwrap(),
+    _ => {},
+  }
+
+  cli::or_exit(store.load());
+
+  match action {
+    "get" => {
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      match cli::or_exit(store.get(key)) {
+        None => eprintln!("{:?} not found", key),
+        Some(value) => println!("{:?}", value),
+      }
+    },
+
+    "delete" => {
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      cli::or_exit(store.delete(key))
+    },
 
     "insert" => {
-      let value = maybe_value.expect(&USAGE).as_ref();
-      store.insert(key, value).unwrap()
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      let value = maybe_value.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      match ttl {
+        Some(ttl) => cli::or_exit(store.insert_with_ttl(key, value, ttl)),
+        None => cli::or_exit(store.insert(key, value)),
+      }
     },
 
     "update" => {
-      let value = maybe_value.expect(&USAGE).as_ref();
-      store.update(key, value).unwrap()
-    }
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      let value = maybe_value.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      cli::or_exit(store.update(key, value))
+    },
 
-    _ => eprintln!("{}", &USAGE),
+    "get-with-version" => {
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      match cli::or_exit(store.get_with_version(key)) {
+        None => eprintln!("{:?} not found", key),
+        Some((value, version)) => println!("{} {:?}", version, value),
+      }
+    },
+
+    "cas" => {
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      let version = cli::version(maybe_value, USAGE);
+      let value = args.get(5).unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      println!("{}", cli::or_exit(store.compare_and_swap(key, version, value)))
+    },
+
+    "insert-if-absent" => {
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      let value = maybe_value.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      println!("{}", cli::or_exit(store.insert_if_absent(key, value)))
+    },
+
+    "delete-if-version" => {
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      let version = cli::version(maybe_value, USAGE);
+      cli::or_exit(store.delete_if_version(key, version))
+    },
+
+    "list" => cli::print_pairs(store.iter()),
+
+    "scan" => {
+      let start = maybe_key.unwrap_or_else(|| cli::usage(USAGE));
+      cli::print_pairs(store.scan(cli::scan_range(start, maybe_value)))
+    },
+
+    "tail" => cli::or_exit(cli::tail(&mut store, maybe_key)),
+
+    "history" => {
+      let key = maybe_key.unwrap_or_else(|| cli::usage(USAGE)).as_ref();
+      cli::or_exit(cli::history(&mut store, key))
+    },
+
+    "stats" => print!("{}", cli::or_exit(store.stats())),
+
+    "export" => {
+      let format = maybe_key.unwrap_or_else(|| cli::usage(USAGE));
+      cli::or_exit(cli::export(&store, format, maybe_value, USAGE))
+    },
+
+    "import" => {
+      let format = maybe_key.unwrap_or_else(|| cli::usage(USAGE));
+      cli::or_exit(cli::import(&mut store, format, maybe_value, USAGE))
+    },
+
+    "compact" => cli::or_exit(store.compact()),
+
+    _ => cli::usage(USAGE),
   }
-}
\ No newline at end of file
+
+  // Writes a hint file, so that the next run can skip scanning the log
+  cli::or_exit(store.close());
+}
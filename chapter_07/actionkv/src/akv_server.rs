@@ -0,0 +1,75 @@
+use std::net::TcpListener;
+
+use libactionkv::net::Server;
+use libactionkv::replication::Follower;
+
+mod cli;
+
+#[cfg(target_os = "windows")]
+const USAGE: &str = "
+Usage:
+    akv_server.exe FILE [--addr ADDR] [--sync POLICY] [--segment-size BYTES]
+                   [--compression ALGO] [--bloom RATE] [--key-file PATH]
+                   [--follow LEADER | --read-only]
+
+Serves FILE to akv_client and other users of libactionkv::net::Client.
+ADDR defaults to 127.0.0.1:4000. The encryption key is read from PATH,
+or else from AKV_KEY.
+
+With --follow, FILE is kept as a copy of the store served at LEADER, and
+is served read-only.
+With --read-only, FILE is opened read-only, so that other read-only
+servers and akv_disk --read-only can open it too, and writes are refused.
+";
+
+#[cfg(not(target_os = "windows"))]
+const USAGE: &str = "
+Usage:
+    akv_server FILE [--addr ADDR] [--sync POLICY] [--segment-size BYTES]
+               [--compression ALGO] [--bloom RATE] [--key-file PATH]
+               [--follow LEADER | --read-only]
+
+Serves FILE to akv_client and other users of libactionkv::net::Client.
+ADDR defaults to 127.0.0.1:4000. The encryption key is read from PATH,
+or else from AKV_KEY.
+
+With --follow, FILE is kept as a copy of the store served at LEADER, and
+is served read-only.
+With --read-only, FILE is opened read-only, so that other read-only
+servers and akv_disk --read-only can open it too, and writes are refused.
+";
+
+fn main() {
+  let mut args: Vec<String> = std::env::args().collect();
+  let mut options = cli::open_options(&mut args, USAGE);
+  let addr = cli::take_flag(&mut args, "--addr").unwrap_or_else(|| "127.0.0.1:4000".to_string());
+  let leader = cli::take_flag(&mut args, "--follow");
+
+  let fname = args.get(1).unwrap_or_else(|| cli::usage(USAGE));
+
+  let path = std::path::Path::new(&fname);
+  let mut store = cli::open(options.ordered(true), path);
+  cli::or_exit(store.load());
+
+  let listener = cli::or_exit(TcpListener::bind(&addr));
+  eprintln!("serving {} on {}", fname, cli::or_exit(listener.local_addr()));
+
+  let store = store.into_handle();
+  let server = match leader {
+    Some(leader) => {
+      eprintln!("following {}", leader);
+      let follower = Follower::new(store.clone(), &leader);
+      std::thread::spawn(move || {
+        follower.run(|err| eprintln!("replication from {} failed: {}", follower.leader(), err))
+      });
+      Server::with_handle(store).read_only()
+    },
+    None if store.read().is_read_only() => Server::with_handle(store).read_only(),
+    None => Server::with_handle(store),
+  };
+
+  server.serve(listener, |peer, err| match peer {
+    Some(peer) => eprintln!("connection from {} failed: {}", peer, err),
+    None => eprintln!("unable to accept a connection: {}", err),
+  });
+}
@@ -0,0 +1,299 @@
+//! Groups of writes that become visible together.
+
+use std::io;
+use std::time::Duration;
+
+use crate::record::{self, Record, RecordKind, BATCHED, LEGACY_VERSION};
+use crate::segment;
+use crate::{ActionKV, ActionKvError, ByteStr, ByteString, Corruption, Entry, Position, Result};
+
+/// A set of inserts and deletes to apply with `ActionKV::write_batch()`.
+#[derive(Debug, Default, Clone)]
+pub struct WriteBatch {
+  ops: Vec<(RecordKind, ByteString, ByteString, Expiry)>,
+}
+
+/// When an insert in a batch expires.
+#[derive(Debug, Clone, Copy)]
+enum Expiry {
+  Never,
+
+  /// A TTL, counted from when the batch is written.
+  After(Duration),
+
+  /// A time in milliseconds since the Unix epoch, as replicated from
+  /// another store.
+  At(u64),
+}
+
+impl WriteBatch {
+  pub fn new() -> Self {
+    WriteBatch::default()
+  }
+
+  pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) -> &mut Self {
+    self.ops.push((RecordKind::Value, key.to_vec(), value.to_vec(), Expiry::Never));
+    self
+  }
+
+  /// See `ActionKV::insert_with_ttl()`. The TTL counts from when the
+  /// batch is written.
+  pub fn insert_with_ttl(&mut self, key: &ByteStr, value: &ByteStr, ttl: Duration) -> &mut Self {
+    self.ops.push((RecordKind::Value, key.to_vec(), value.to_vec(), Expiry::After(ttl)));
+    self
+  }
+
+  pub fn delete(&mut self, key: &ByteStr) -> &mut Self {
+    self.ops.push((RecordKind::Tombstone, key.to_vec(), ByteString::new(), Expiry::Never));
+    self
+  }
+
+  /// An insert that expires at a fixed time, in milliseconds since the
+  /// Unix epoch, rather than after a TTL.
+  pub(crate) fn insert_expiring_at(&mut self, key: &ByteStr, value: &ByteStr, expires_at: Option<u64>) -> &mut Self {
+    let expiry = match expires_at {
+      Some(expires_at) => Expiry::At(expires_at),
+      None => Expiry::Never,
+    };
+    self.ops.push((RecordKind::Value, key.to_vec(), value.to_vec(), expiry));
+    self
+  }
+
+  pub fn len(&self) -> usize {
+    self.ops.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.ops.is_empty()
+  }
+
+  pub fn clear(&mut self) {
+    self.ops.clear();
+  }
+}
+
+impl ActionKV {
+  /// Appends every write in `batch`, followed by a commit record.
+  ///
+  /// The whole batch is written with a single call to the OS. If it is
+  /// interrupted before the commit record reaches the disk, even partway
+  /// through one of the batch's records, `load()` ignores the partial
+  /// batch and cuts it off. Either all of its writes are visible or none
+  /// are.
+  ///
+  /// Values are compressed as set by `OpenOptions::compression()`.
+  pub fn write_batch(&mut self, batch: &WriteBatch) -> Result<()> {
+    self.check_writable()?;
+    self.check_lsm_intact()?;
+    if batch.is_empty() {
+      return Ok(());
+    }
+
+    // A compaction that finished in the background is installed first,
+    // as with any other write
+    self.finish_compaction(false)?;
+
+    let version = self.version();
+    if version == LEGACY_VERSION {
+      return Err(ActionKvError::NeedsUpgrade { feature: "batches", found: version });
+    }
+
+    let now = record::now();
+    let mut seq = self.last_seq()?;
+    let mut buf = ByteString::new();
+    let mut offsets = Vec::with_capacity(batch.len());
+    let mut expiries = Vec::with_capacity(batch.len());
+    for (kind, key, value, expiry) in &batch.ops {
+      let expires_at = match expiry {
+        Expiry::Never => None,
+        Expiry::After(ttl) => Some(now.saturating_add(ttl.as_millis() as u64)),
+        Expiry::At(expires_at) => Some(*expires_at),
+      };
+      offsets.push(buf.len() as u64);
+      expiries.push(expires_at);
+      let (flags, value) = match kind {
+        RecordKind::Value => ActionKV::compress(version, self.compression, value)?,
+        _ => (0, value.clone()),
+      };
+      seq += 1;
+      let record = Record { kind: *kind, flags: BATCHED | flags, key: key.clone(), value, expires_at, seq: Some(seq) };
+      let record = self.seal(record)?;
+      ActionKV::write_record(
+        &mut buf,
+        version,
+        *kind,
+        record.flags,
+        expires_at,
+        record.seq,
+        &record.key,
+        &record.value,
+      )?;
+    }
+
+    // A batch is never split across segments, so a segment may run past
+    // the segment size by the length of its commit record
+    self.roll_if_needed(buf.len() as u64)?;
+    let segment = self.active();
+    let start = segment.end()?;
+
+    let commit = record::encode_commit(start, batch.len() as u32);
+    ActionKV::write_record(&mut buf, version, RecordKind::Commit, 0, None, None, b"", &commit)?;
+
+    segment.append(&buf)?;
+    let positions: Vec<Position> = offsets
+      .into_iter()
+      .map(|offset| segment.position(start + offset))
+      .collect();
+    self.seq = Some(seq);
+    for (_, key, _, _) in &batch.ops {
+      self.bloom_insert(key)?;
+    }
+    self.sync_after_write()?;
+
+    for (((kind, key, _, _), position), expires_at) in batch.ops.iter().zip(positions).zip(expiries) {
+      match kind {
+        RecordKind::Tombstone => {
+          self.index.remove(key);
+          self.memtable_delete(key, position);
+        },
+        _ => {
+          self.index.insert(key.clone(), Entry { position, expires_at });
+          self.memtable_insert(key);
+        },
+      }
+    }
+
+    Ok(self.flush_if_full()?)
+  }
+
+  /// Cuts off the batches that a scan found cut short before their commit
+  /// record, so that the next record is not written after one. None of
+  /// their writes were visible, so nothing is lost.
+  pub(crate) fn cut_torn_batches(&mut self, torn: Vec<Corruption>) -> io::Result<()> {
+    if self.read_only {
+      return Ok(());
+    }
+    for problem in torn {
+      let segment = segment::lookup_mut(&mut self.segments, problem.segment())?;
+      segment.f.truncate(problem.offset())?;
+      segment.len = problem.offset();
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::record::FORMAT_VERSION;
+  use crate::{FaultyStorage, MemoryStorage, OpenOptions};
+
+  fn batch() -> WriteBatch {
+    let mut batch = WriteBatch::new();
+    batch.insert(b"b", b"2").insert(b"c", b"3").delete(b"a");
+    batch
+  }
+
+  fn assert_untouched(store: &ActionKV) {
+    assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(store.get(b"b").unwrap(), None);
+    assert_eq!(store.get(b"c").unwrap(), None);
+  }
+
+  #[test]
+  fn batch_without_commit_is_ignored() {
+    let mem = MemoryStorage::new();
+    let mut store = OpenOptions::new().open_storage(mem.clone()).unwrap();
+    store.load().unwrap();
+    store.insert(b"a", b"1").unwrap();
+    let before_batch = mem.to_vec().len();
+    store.write_batch(&batch()).unwrap();
+    drop(store);
+
+    // Cut the file off just before the commit record, as if the process
+    // had stopped part way through the write
+    let mut commit = Vec::new();
+    let commit_value = record::encode_commit(0, 3);
+    let commit_len =
+      ActionKV::write_record(&mut commit, FORMAT_VERSION, RecordKind::Commit, 0, None, None, b"", &commit_value)
+        .unwrap();
+    let mut bytes = mem.to_vec();
+    bytes.truncate(bytes.len() - commit_len as usize);
+    assert!(bytes.len() > before_batch);
+
+    let mem = MemoryStorage::from_bytes(bytes);
+    let mut store = OpenOptions::new().open_storage(mem.clone()).unwrap();
+    store.load().unwrap();
+    assert_untouched(&store);
+
+    // A later write must not bring the batch back
+    store.insert(b"d", b"4").unwrap();
+    drop(store);
+
+    let mut store = OpenOptions::new().open_storage(mem).unwrap();
+    store.load().unwrap();
+    assert_untouched(&store);
+    assert_eq!(store.get(b"d").unwrap(), Some(b"4".to_vec()));
+  }
+
+  #[test]
+  fn batch_cut_inside_a_record_is_ignored() {
+    let mem = MemoryStorage::new();
+    let mut store = OpenOptions::new().open_storage(mem.clone()).unwrap();
+    store.load().unwrap();
+    store.insert(b"a", b"1").unwrap();
+    let before_batch = mem.to_vec().len();
+    store.write_batch(&batch()).unwrap();
+    drop(store);
+    let written = mem.to_vec();
+    let commit_len = ActionKV::write_record(
+      &mut Vec::new(),
+      FORMAT_VERSION,
+      RecordKind::Commit,
+      0,
+      None,
+      None,
+      b"",
+      &record::encode_commit(0, 3),
+    )
+    .unwrap() as usize;
+
+    // Inside the first record, just past its flags, and inside the last
+    // record before the commit
+    for cut in [before_batch + 8, written.len() - commit_len - 3] {
+      let mem = MemoryStorage::from_bytes(written[..cut].to_vec());
+      let mut store = OpenOptions::new().open_storage(mem.clone()).unwrap();
+      store.load().unwrap();
+      assert_untouched(&store);
+      assert!(store.check().unwrap().is_clean());
+      assert_eq!(mem.to_vec().len(), before_batch);
+
+      store.insert(b"d", b"4").unwrap();
+      drop(store);
+
+      let mut store = OpenOptions::new().open_storage(mem).unwrap();
+      store.load().unwrap();
+      assert_untouched(&store);
+      assert_eq!(store.get(b"d").unwrap(), Some(b"4".to_vec()));
+    }
+  }
+
+  #[test]
+  fn failed_batch_write_is_invisible() {
+    let mem = MemoryStorage::new();
+    let storage = FaultyStorage::new(mem.clone());
+    let faults = storage.faults();
+    let mut store = OpenOptions::new().open_storage(storage).unwrap();
+    store.load().unwrap();
+    store.insert(b"a", b"1").unwrap();
+
+    faults.short_write(0, 40);
+    assert!(store.write_batch(&batch()).is_err());
+    assert_untouched(&store);
+    drop(store);
+
+    let mut store = OpenOptions::new().open_storage(mem).unwrap();
+    store.load().unwrap();
+    assert_untouched(&store);
+  }
+}
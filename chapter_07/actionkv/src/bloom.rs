@@ -0,0 +1,477 @@
+//! Bloom filters, which let lookups in the log skip segments that cannot
+//! hold a key.
+//!
+//! `get()` answers from `index` once the store is loaded, but `find()`,
+//! `get_as_of()` and `get()` on a store that has not been loaded read the
+//! log itself. With `OpenOptions::bloom_filter()`, each segment gets a
+//! filter of the keys written to it, and those lookups pass over any
+//! segment whose filter rules the key out.
+//!
+//! Filters are built when the store is opened and kept up to date as
+//! records are written. A filter is saved next to its segment
+//! (`capitals.bloom` for `capitals`, or `0000000001.akv.bloom` in a
+//! segmented store) when the segment is sealed and when the store is
+//! closed, and read back on the next open rather than being rebuilt:
+//!
+//! ```text
+//! +------------------+---------+---------+-------------+----------+----------+-------+
+//! | "AKVBLOOM"       | version | covered | tail_digest | body_len | checksum | body  |
+//! +------------------+---------+---------+-------------+----------+----------+-------+
+//!       8 bytes         u32       u64       u32           u64        u32
+//! ```
+//!
+//! `covered` is how long the segment was when the filter was saved, and
+//! `tail_digest` a CRC32 of the bytes before that, as in a hint. Records
+//! appended since are added to the filter as it is read back; a filter
+//! that no longer matches its segment is rebuilt. `body` is the bincode
+//! encoding of the filter, sealed with the store's key in an encrypted
+//! store, since a filter gives away which keys might be present.
+
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::encryption::{self, Cipher};
+use crate::segment::Segment;
+use crate::{ActionKV, ByteStr, ByteString};
+
+const BLOOM_MAGIC: &[u8; 8] = b"AKVBLOOM";
+const BLOOM_VERSION: u32 = 1;
+
+/// Keys a filter makes room for before it has to be rebuilt larger.
+const MIN_CAPACITY: u64 = 1024;
+
+/// A set of keys that answers "definitely not" or "maybe".
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct BloomFilter {
+  bits: Vec<u64>,
+  hashes: u32,
+
+  /// How many keys the filter was sized for, and how many it holds.
+  /// Past `capacity`, false positives become more likely than asked for.
+  capacity: u64,
+  count: u64,
+  false_positive_rate: f64,
+
+  /// Whether the filter has changed since it was saved.
+  #[serde(skip)]
+  dirty: bool,
+}
+
+impl BloomFilter {
+  pub fn new(capacity: u64, false_positive_rate: f64) -> Self {
+    let ln2 = std::f64::consts::LN_2;
+    let capacity = capacity.max(1);
+    let bits = (-(capacity as f64) * false_positive_rate.ln() / (ln2 * ln2)).ceil().max(64.0);
+    let words = (bits / 64.0).ceil() as usize;
+    let hashes = ((words * 64) as f64 / capacity as f64 * ln2).round().clamp(1.0, 30.0) as u32;
+
+    BloomFilter {
+      bits: vec![0; words],
+      hashes,
+      capacity,
+      count: 0,
+      false_positive_rate,
+      dirty: true,
+    }
+  }
+
+  /// The bits for a key, by double hashing two CRC32s of it.
+  fn bit_indexes(&self, hash: (u32, u32)) -> impl Iterator<Item = usize> {
+    let len = self.bits.len() as u64 * 64;
+    let (h1, h2) = (hash.0 as u64, hash.1 as u64 | 1);
+    (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+  }
+
+  /// Sets the bits for `hash`. Only a key that sets a new bit counts
+  /// towards `capacity`, so writing the same key again does not fill
+  /// the filter.
+  fn insert_hash(&mut self, hash: (u32, u32)) {
+    let indexes: Vec<usize> = self.bit_indexes(hash).collect();
+    let mut added = false;
+    for i in indexes {
+      added |= self.bits[i / 64] & (1 << (i % 64)) == 0;
+      self.bits[i / 64] |= 1 << (i % 64);
+    }
+    if added {
+      self.count += 1;
+      self.dirty = true;
+    }
+  }
+
+  pub fn insert(&mut self, key: &ByteStr) {
+    self.insert_hash(hash(key));
+  }
+
+  pub fn contains(&self, key: &ByteStr) -> bool {
+    self.bit_indexes(hash(key)).all(|i| self.bits[i / 64] & (1 << (i % 64)) != 0)
+  }
+
+  fn is_full(&self) -> bool {
+    self.count >= self.capacity
+  }
+}
+
+fn hash(key: &ByteStr) -> (u32, u32) {
+  (crc32::checksum_ieee(key), crc32::checksum_castagnoli(key))
+}
+
+/// How useful the bloom filters have been. See `ActionKV::bloom_stats()`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BloomStats {
+  /// Segments read because their filter said the key might be there.
+  pub hits: u64,
+
+  /// Segments skipped because their filter said the key was not there.
+  pub misses: u64,
+
+  /// Hits where the key turned out not to be there after all.
+  pub false_positives: u64,
+}
+
+/// Counts behind `BloomStats`, shared by every lookup on a store.
+#[derive(Debug, Default)]
+pub(crate) struct BloomCounters {
+  hits: AtomicU64,
+  misses: AtomicU64,
+  false_positives: AtomicU64,
+}
+
+impl BloomCounters {
+  /// Whether `segment` might hold `key`, counting the answer if the
+  /// segment has a filter.
+  pub fn check(&self, segment: &Segment, key: &ByteStr) -> bool {
+    let filter = match &segment.bloom {
+      Some(filter) => filter,
+      None => return true,
+    };
+
+    let maybe = filter.contains(key);
+    let counter = match maybe {
+      true => &self.hits,
+      false => &self.misses,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+    maybe
+  }
+
+  /// Notes that `segment` did not hold a key its filter said it might.
+  pub fn missed(&self, segment: &Segment) {
+    if segment.bloom.is_some() {
+      self.false_positives.fetch_add(1, Ordering::Relaxed);
+    }
+  }
+}
+
+impl Segment {
+  /// Whether the segment might hold `key`, as far as its filter can
+  /// tell, without counting the answer.
+  pub fn may_contain(&self, key: &ByteStr) -> bool {
+    self.bloom.as_ref().is_none_or(|filter| filter.contains(key))
+  }
+}
+
+impl ActionKV {
+  /// How often lookups in the log were spared a segment by its bloom
+  /// filter since the store was opened. All zero unless filters were
+  /// enabled with `OpenOptions::bloom_filter()`.
+  pub fn bloom_stats(&self) -> BloomStats {
+    let counters = &self.bloom_counters;
+    BloomStats {
+      hits: counters.hits.load(Ordering::Relaxed),
+      misses: counters.misses.load(Ordering::Relaxed),
+      false_positives: counters.false_positives.load(Ordering::Relaxed),
+    }
+  }
+
+  /// Gives every segment without a filter one, reading it from its file
+  /// or building it from the segment. A filter built for a sealed segment
+  /// is saved, unless the store is read-only.
+  pub(crate) fn load_blooms(&mut self) -> io::Result<()> {
+    let rate = match self.bloom {
+      Some(rate) => rate,
+      None => return Ok(()),
+    };
+
+    let active = self.active().id;
+    for segment in self.segments.iter_mut().filter(|segment| segment.bloom.is_none()) {
+      if let Some(filter) = read_bloom(segment, self.cipher.as_ref(), rate)? {
+        segment.bloom = Some(filter);
+        continue;
+      }
+
+      segment.bloom = Some(build_bloom(segment, self.cipher.as_ref(), rate, segment.id == active)?);
+      if segment.id != active && !self.read_only {
+        write_bloom(segment, self.cipher.as_ref())?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Adds `key` to the active segment's filter, rebuilding the filter
+  /// larger if it has filled up.
+  pub(crate) fn bloom_insert(&mut self, key: &ByteStr) -> io::Result<()> {
+    let cipher = self.cipher.clone();
+    let segment = self.active();
+    let filter = match &mut segment.bloom {
+      Some(filter) => filter,
+      None => return Ok(()),
+    };
+
+    if !filter.is_full() {
+      filter.insert(key);
+      return Ok(());
+    }
+
+    // The key was written before this is called, so the rebuilt filter
+    // holds it too
+    let rate = filter.false_positive_rate;
+    segment.bloom = Some(build_bloom(segment, cipher.as_ref(), rate, true)?);
+    Ok(())
+  }
+
+  /// Saves the filter of every segment that has changed since it was
+  /// last saved.
+  pub(crate) fn write_blooms(&mut self) -> io::Result<()> {
+    for segment in &mut self.segments {
+      if segment.bloom.as_ref().is_some_and(|filter| filter.dirty) {
+        write_bloom(segment, self.cipher.as_ref())?;
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Where `segment`'s filter is saved, if it has a file of its own.
+fn bloom_path(segment: &Segment) -> Option<PathBuf> {
+  segment.has_path().then(|| ActionKV::sibling_path(&segment.path, "bloom"))
+}
+
+/// Removes the saved filter of the segment at `path`, if there is one.
+pub(crate) fn remove_bloom(segment_path: &std::path::Path) -> io::Result<()> {
+  match fs::remove_file(ActionKV::sibling_path(segment_path, "bloom")) {
+    Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+    _ => Ok(()),
+  }
+}
+
+/// Builds a filter of every key in `segment`. The filter of the active
+/// segment is given room to grow.
+fn build_bloom(segment: &Segment, cipher: Option<&Cipher>, rate: f64, active: bool) -> io::Result<BloomFilter> {
+  let start = segment.data_start();
+  let mut hashes = Vec::new();
+  add_keys(segment, cipher, start, |key| hashes.push(hash(key)))?;
+  hashes.sort_unstable();
+  hashes.dedup();
+
+  let n = hashes.len() as u64;
+  let capacity = match active {
+    true => (n * 2).max(MIN_CAPACITY),
+    false => n,
+  };
+  let mut filter = BloomFilter::new(capacity, rate);
+  for hash in hashes {
+    filter.insert_hash(hash);
+  }
+  Ok(filter)
+}
+
+/// Calls `add` with the key of each record in `segment` from `start` on.
+/// Damaged records are stepped over, since nothing can be read from them.
+fn add_keys<F>(segment: &Segment, cipher: Option<&Cipher>, start: u64, mut add: F) -> io::Result<()>
+where
+  F: FnMut(&ByteStr),
+{
+  let mut failed = None;
+  segment.scan(start, true, |offset, record| {
+    if failed.is_some() {
+      return;
+    }
+    match encryption::unseal(cipher, segment.position(offset), record) {
+      Ok(record) => add(&record.key),
+      Err(err) => failed = Some(err),
+    }
+  })?;
+
+  match failed {
+    Some(err) => Err(err),
+    None => Ok(()),
+  }
+}
+
+fn write_bloom(segment: &mut Segment, cipher: Option<&Cipher>) -> io::Result<()> {
+  let path = match bloom_path(segment) {
+    Some(path) => path,
+    None => return Ok(()),
+  };
+  let covered = segment.end()?;
+  let tail_digest = segment.tail_digest(covered)?;
+  let filter = match &mut segment.bloom {
+    Some(filter) => filter,
+    None => return Ok(()),
+  };
+
+  let mut body = bincode::serialize(filter)
+    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+  if let Some(cipher) = cipher {
+    body = cipher.seal_bytes(BLOOM_MAGIC, body)?;
+  }
+
+  let tmp_path = ActionKV::sibling_path(&path, "tmp");
+  {
+    let mut f = BufWriter::new(File::create(&tmp_path)?);
+    f.write_all(BLOOM_MAGIC)?;
+    f.write_u32::<LittleEndian>(BLOOM_VERSION)?;
+    f.write_u64::<LittleEndian>(covered)?;
+    f.write_u32::<LittleEndian>(tail_digest)?;
+    f.write_u64::<LittleEndian>(body.len() as u64)?;
+    f.write_u32::<LittleEndian>(crc32::checksum_ieee(&body))?;
+    f.write_all(&body)?;
+    f.flush()?;
+    f.get_ref().sync_all()?;
+  }
+  fs::rename(&tmp_path, &path)?;
+
+  filter.dirty = false;
+  Ok(())
+}
+
+/// Reads `segment`'s saved filter and adds any records appended since.
+/// Returns `None` if there is none, it does not match the segment, or it
+/// was built for another false positive rate.
+fn read_bloom(segment: &Segment, cipher: Option<&Cipher>, rate: f64) -> io::Result<Option<BloomFilter>> {
+  let path = match bloom_path(segment) {
+    Some(path) => path,
+    None => return Ok(None),
+  };
+  let f = match File::open(path) {
+    Ok(f) => f,
+    Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+    Err(err) => return Err(err),
+  };
+
+  let (covered, mut filter) = match process_bloom(&mut BufReader::new(f), segment, cipher) {
+    Ok(Some(saved)) => saved,
+    Ok(None) => return Ok(None),
+    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+    Err(err) => return Err(err),
+  };
+  if filter.false_positive_rate != rate {
+    return Ok(None);
+  }
+
+  filter.dirty = false;
+  if covered < segment.len {
+    add_keys(segment, cipher, covered, |key| filter.insert(key))?;
+  }
+  Ok(Some(filter))
+}
+
+fn process_bloom<R: Read>(f: &mut R, segment: &Segment, cipher: Option<&Cipher>) -> io::Result<Option<(u64, BloomFilter)>> {
+  let mut magic = [0u8; 8];
+  f.read_exact(&mut magic)?;
+  if &magic != BLOOM_MAGIC || f.read_u32::<LittleEndian>()? != BLOOM_VERSION {
+    return Ok(None);
+  }
+
+  let covered = f.read_u64::<LittleEndian>()?;
+  let tail_digest = f.read_u32::<LittleEndian>()?;
+  if covered < segment.data_start() || covered > segment.len || segment.tail_digest(covered)? != tail_digest {
+    return Ok(None);
+  }
+
+  let body_len = f.read_u64::<LittleEndian>()?;
+  let saved_checksum = f.read_u32::<LittleEndian>()?;
+  let mut body = ByteString::new();
+  f.take(body_len).read_to_end(&mut body)?;
+  if body.len() as u64 != body_len || crc32::checksum_ieee(&body) != saved_checksum {
+    return Ok(None);
+  }
+  if let Some(cipher) = cipher {
+    body = match cipher.open_bytes(BLOOM_MAGIC, body) {
+      Some(body) => body,
+      None => return Ok(None),
+    };
+  }
+
+  match bincode::deserialize(&body) {
+    Ok(filter) => Ok(Some((covered, filter))),
+    Err(_) => Ok(None),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+  use std::path::Path;
+
+  use crate::{ActionKV, OpenOptions};
+
+  fn open(path: &Path) -> ActionKV {
+    OpenOptions::new().bloom_filter(0.01).open(path).unwrap()
+  }
+
+  fn fill(path: &Path, prefix: &str) {
+    let mut store = open(path);
+    store.load().unwrap();
+    for i in 0..100 {
+      store.insert(format!("{}{}", prefix, i).as_bytes(), b"1").unwrap();
+    }
+    store.close().unwrap();
+  }
+
+  /// Whether the filter lets through every key `fill()` wrote.
+  fn covers(store: &mut ActionKV, prefix: &str) -> bool {
+    let segment = store.active();
+    (0..100).all(|i| segment.may_contain(format!("{}{}", prefix, i).as_bytes()))
+  }
+
+  #[test]
+  fn missing_or_stale_filters_are_rebuilt() {
+    let dir = std::env::temp_dir().join(format!("actionkv-bloom-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let (path, other) = (dir.join("store"), dir.join("other"));
+    let bloom = ActionKV::sibling_path(&path, "bloom");
+    fill(&path, "a");
+    fill(&other, "b");
+    assert!(bloom.exists());
+
+    // A filter saved for another file does not match this one
+    let foreign = fs::read(ActionKV::sibling_path(&other, "bloom")).unwrap();
+    fs::write(&bloom, &foreign).unwrap();
+    let mut store = open(&path);
+    assert!(covers(&mut store, "a"));
+    store.close().unwrap();
+    assert_ne!(fs::read(&bloom).unwrap(), foreign);
+
+    fs::remove_file(&bloom).unwrap();
+    let mut store = open(&path);
+    assert!(covers(&mut store, "a"));
+    assert_eq!(store.get(b"a0").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(store.get(b"missing").unwrap(), None);
+    assert_eq!(store.bloom_stats().misses, 1);
+    store.close().unwrap();
+    assert!(bloom.exists());
+
+    // Records written after the filter was last saved are added to it
+    let mut store = open(&path);
+    store.load().unwrap();
+    for i in 0..100 {
+      store.insert(format!("c{}", i).as_bytes(), b"1").unwrap();
+    }
+    drop(store);
+    let mut store = open(&path);
+    assert!(covers(&mut store, "a") && covers(&mut store, "c"));
+    drop(store);
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,323 @@
+//! Helpers shared by the akv_* binaries.
+
+// Not every binary uses every helper
+#![allow(dead_code)]
+
+use std::io;
+use std::ops::Bound;
+use std::path::Path;
+use std::time::Duration;
+
+use libactionkv::{ActionKV, ActionKvError, Compression, Engine, ExportFormat, KeyValuePair, OpenOptions, Result, SyncPolicy, KEY_LEN};
+
+/// Exit statuses, so that scripts can tell failures apart. 2 is a usage
+/// error, as with `take_flag()` and friends.
+pub const EXIT_DAMAGED: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_CONFLICT: i32 = 3;
+pub const EXIT_IO: i32 = 4;
+pub const EXIT_CORRUPT: i32 = 5;
+pub const EXIT_WRONG_KEY: i32 = 6;
+pub const EXIT_TOO_LARGE: i32 = 7;
+pub const EXIT_LOCKED: i32 = 8;
+pub const EXIT_VERSION: i32 = 9;
+
+/// Prints `usage` and exits with `EXIT_USAGE`, for a missing or unknown
+/// argument.
+pub fn usage(usage: &str) -> ! {
+  eprintln!("{}", usage);
+  std::process::exit(EXIT_USAGE);
+}
+
+/// Removes `--name VALUE` from `args` and returns `VALUE`, so that the
+/// remaining arguments can be read by position.
+pub fn take_flag(args: &mut Vec<String>, name: &str) -> Option<String> {
+  let i = args.iter().position(|arg| arg == name)?;
+  if i + 1 >= args.len() {
+    return None;
+  }
+
+  let value = args.remove(i + 1);
+  args.remove(i);
+  Some(value)
+}
+
+/// Removes `--name` from `args` and returns whether it was there.
+pub fn take_switch(args: &mut Vec<String>, name: &str) -> bool {
+  match args.iter().position(|arg| arg == name) {
+    Some(i) => {
+      args.remove(i);
+      true
+    },
+    None => false,
+  }
+}
+
+/// The environment variable that `encryption_key()` falls back to.
+pub const KEY_VAR: &str = "AKV_KEY";
+
+/// Reads `--sync POLICY`, `--segment-size BYTES`, `--compression ALGO`,
+/// `--bloom RATE`, `--read-only` and the encryption key from `args`.
+/// Exits with `usage` if any of them is invalid.
+pub fn open_options(args: &mut Vec<String>, usage: &str) -> OpenOptions {
+  let mut options = OpenOptions::new();
+  options.read_only(take_switch(args, "--read-only"));
+
+  if let Some(policy) = take_flag(args, "--sync") {
+    let policy: SyncPolicy = policy.parse().unwrap_or_else(|err| {
+      eprintln!("{}\n{}", err, usage);
+      std::process::exit(EXIT_USAGE);
+    });
+    options.sync(policy);
+  }
+
+  if let Some(engine) = take_flag(args, "--engine") {
+    let engine: Engine = engine.parse().unwrap_or_else(|err| {
+      eprintln!("{}\n{}", err, usage);
+      std::process::exit(EXIT_USAGE);
+    });
+    options.engine(engine);
+  }
+
+  if let Some(bytes) = take_flag(args, "--segment-size") {
+    let bytes = bytes.parse().unwrap_or_else(|_| {
+      eprintln!("invalid segment size {:?}\n{}", bytes, usage);
+      std::process::exit(EXIT_USAGE);
+    });
+    options.segment_size(bytes);
+  }
+
+  if let Some(compression) = take_flag(args, "--compression") {
+    let compression: Compression = compression.parse().unwrap_or_else(|err| {
+      eprintln!("{}\n{}", err, usage);
+      std::process::exit(EXIT_USAGE);
+    });
+    options.compression(compression);
+  }
+
+  if let Some(rate) = take_flag(args, "--bloom") {
+    let rate = rate.parse().unwrap_or_else(|_| {
+      eprintln!("invalid false positive rate {:?}\n{}", rate, usage);
+      std::process::exit(EXIT_USAGE);
+    });
+    options.bloom_filter(rate);
+  }
+
+  if let Some(key) = encryption_key(args, usage) {
+    options.encryption_key(&key);
+  }
+
+  options
+}
+
+/// Reads the key from the file named by `--key-file PATH`, or else from
+/// the `AKV_KEY` environment variable. A key file holds either the 32 raw
+/// bytes of the key or 64 hex digits; the variable holds hex digits.
+/// Exits with `usage` if the key cannot be read.
+pub fn encryption_key(args: &mut Vec<String>, usage: &str) -> Option<[u8; KEY_LEN]> {
+  let fail = |msg: String| -> ! {
+    eprintln!("{}\n{}", msg, usage);
+    std::process::exit(EXIT_USAGE);
+  };
+
+  let (source, bytes) = match take_flag(args, "--key-file") {
+    Some(path) => match std::fs::read(&path) {
+      Ok(bytes) => (path, bytes),
+      Err(err) => fail(format!("unable to read key file {:?}: {}", path, err)),
+    },
+    None => match std::env::var(KEY_VAR) {
+      Ok(hex) => (KEY_VAR.to_string(), hex.into_bytes()),
+      Err(_) => return None,
+    },
+  };
+
+  if let Ok(key) = <[u8; KEY_LEN]>::try_from(bytes.as_slice()) {
+    return Some(key);
+  }
+
+  let hex = String::from_utf8_lossy(&bytes);
+  match parse_hex(hex.trim()) {
+    Some(key) => Some(key),
+    None => fail(format!("{} does not hold a {}-byte key", source, KEY_LEN)),
+  }
+}
+
+fn parse_hex(hex: &str) -> Option<[u8; KEY_LEN]> {
+  if hex.len() != KEY_LEN * 2 || !hex.is_ascii() {
+    return None;
+  }
+
+  let mut key = [0u8; KEY_LEN];
+  for (i, byte) in key.iter_mut().enumerate() {
+    *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+  }
+  Some(key)
+}
+
+/// Removes `--ttl DURATION` from `args` and parses it. DURATION is a
+/// number followed by `ms`, `s`, `m` or `h`, such as `30s`. Exits with
+/// `usage` if it is invalid.
+pub fn ttl(args: &mut Vec<String>, usage: &str) -> Option<Duration> {
+  let ttl = take_flag(args, "--ttl")?;
+
+  let split = ttl.find(|c: char| !c.is_ascii_digit()).unwrap_or(ttl.len());
+  let (n, unit) = ttl.split_at(split);
+  let parsed = n.parse::<u64>().ok().and_then(|n| match unit {
+    "ms" => Some(Duration::from_millis(n)),
+    "s" => Some(Duration::from_secs(n)),
+    "m" => Some(Duration::from_secs(n * 60)),
+    "h" => Some(Duration::from_secs(n * 60 * 60)),
+    _ => None,
+  });
+
+  match parsed {
+    Some(ttl) => Some(ttl),
+    None => {
+      eprintln!("invalid ttl {:?}\n{}", ttl, usage);
+      std::process::exit(EXIT_USAGE);
+    },
+  }
+}
+
+/// `export FORMAT [OUT]`: writes every live pair to OUT, or to stdout.
+pub fn export(store: &ActionKV, format: &str, out: Option<&String>, usage: &str) -> Result<()> {
+  let format = export_format(format, usage);
+  let count = match out {
+    Some(path) => store.export(std::fs::File::create(path)?, format)?,
+    None => store.export(io::stdout().lock(), format)?,
+  };
+  eprintln!("exported {} pairs", count);
+  Ok(())
+}
+
+/// `import FORMAT [IN]`: inserts every pair read from IN, or from stdin.
+pub fn import(store: &mut ActionKV, format: &str, input: Option<&String>, usage: &str) -> Result<()> {
+  let format = export_format(format, usage);
+  let count = match input {
+    Some(path) => store.import(std::fs::File::open(path)?, format)?,
+    None => store.import(io::stdin().lock(), format)?,
+  };
+  eprintln!("imported {} pairs", count);
+  Ok(())
+}
+
+fn export_format(format: &str, usage: &str) -> ExportFormat {
+  format.parse().unwrap_or_else(|err| {
+    eprintln!("{}\n{}", err, usage);
+    std::process::exit(EXIT_USAGE);
+  })
+}
+
+/// `tail [PREFIX]`: prints changes to keys starting with PREFIX as they
+/// are written, until interrupted.
+pub fn tail(store: &mut ActionKV, prefix: Option<&String>) -> Result<()> {
+  let prefix = prefix.map(|prefix| prefix.as_bytes()).unwrap_or_default();
+  let end = store.seek_to_end()?;
+
+  for change in store.watch(prefix, end)? {
+    let change = change?;
+    match change.value {
+      Some(value) => println!("{:?} {:?}", change.key, value),
+      None => println!("{:?} deleted", change.key),
+    }
+  }
+  Ok(())
+}
+
+/// Parses the VERSION of `cas` and `delete-if-version`. Exits with
+/// `usage` if it is missing or not a number.
+pub fn version(arg: Option<&String>, usage: &str) -> u64 {
+  let arg = arg.unwrap_or_else(|| self::usage(usage));
+  arg.parse().unwrap_or_else(|_| {
+    eprintln!("invalid version {:?}\n{}", arg, usage);
+    std::process::exit(EXIT_USAGE);
+  })
+}
+
+/// Opens the store at `path`, or exits with `fail()`'s message and
+/// status if it cannot be opened.
+pub fn open(options: &OpenOptions, path: &Path) -> ActionKV {
+  options.open(path).unwrap_or_else(|err| {
+    let (message, status) = describe(&err);
+    eprintln!("error: unable to open {}: {}", path.display(), message);
+    std::process::exit(status);
+  })
+}
+
+/// Unwraps `result`, or exits with `fail()`'s message and status.
+pub fn or_exit<T, E: Into<ActionKvError>>(result: Result<T, E>) -> T {
+  result.unwrap_or_else(|err| fail(err.into()))
+}
+
+/// Reports `err` and exits with the status for its kind, one of the
+/// `EXIT_*` constants.
+pub fn fail(err: ActionKvError) -> ! {
+  let (message, status) = describe(&err);
+  eprintln!("error: {}", message);
+  std::process::exit(status);
+}
+
+fn describe(err: &ActionKvError) -> (String, i32) {
+  match err {
+    ActionKvError::Io(err) => (err.to_string(), EXIT_IO),
+    ActionKvError::Corruption(err) => (
+      format!("the store is damaged: {}\nrun check to see the damage and repair to remove it", err),
+      EXIT_CORRUPT,
+    ),
+    ActionKvError::Authentication(err) => (
+      format!("{}\ncheck --key-file or {}", err, KEY_VAR),
+      EXIT_WRONG_KEY,
+    ),
+    ActionKvError::KeyTooLarge { .. } | ActionKvError::ValueTooLarge { .. } => {
+      (err.to_string(), EXIT_TOO_LARGE)
+    },
+    ActionKvError::Locked(locked) => (
+      format!("{}\nthe store is open in another process", locked),
+      EXIT_LOCKED,
+    ),
+    ActionKvError::UnsupportedVersion { .. } => (
+      format!("{}\nthe store was written by a newer version of actionkv", err),
+      EXIT_VERSION,
+    ),
+    ActionKvError::NeedsUpgrade { feature, found } => (
+      format!("format version {} does not support {}; run compact to upgrade the store", found, feature),
+      EXIT_VERSION,
+    ),
+    ActionKvError::Conflict(conflict) => (format!("conflict: {}", conflict), EXIT_CONFLICT),
+    err => (err.to_string(), EXIT_IO),
+  }
+}
+
+/// `history KEY`: prints every version of KEY in the log, oldest first.
+pub fn history(store: &mut ActionKV, key: &[u8]) -> Result<()> {
+  let end = store.seek_to_end()?;
+
+  for version in store.get_as_of(key, end)? {
+    let position = version.position;
+    match version.value {
+      Some(value) => println!("{}:{} {:?}", position.segment, position.offset, value),
+      None => println!("{}:{} deleted", position.segment, position.offset),
+    }
+  }
+  Ok(())
+}
+
+/// The range for `scan START [END]`: from START, up to but not including
+/// END.
+pub fn scan_range<'a>(start: &'a str, end: Option<&'a String>) -> (Bound<&'a [u8]>, Bound<&'a [u8]>) {
+  let end = match end {
+    Some(end) => Bound::Excluded(end.as_bytes()),
+    None => Bound::Unbounded,
+  };
+  (Bound::Included(start.as_bytes()), end)
+}
+
+pub fn print_pairs<I>(pairs: I)
+where
+  I: Iterator<Item = Result<KeyValuePair>>,
+{
+  for pair in pairs {
+    let kv = or_exit(pair);
+    println!("{:?} {:?}", kv.key, kv.value);
+  }
+}
@@ -0,0 +1,479 @@
+//! Reclaiming the space taken by overwritten and deleted records.
+//!
+//! A store kept in a single file is compacted by rewriting the file in
+//! place. A segmented store only ever compacts its sealed segments, which
+//! no longer change. They are merged into one new segment on a background
+//! thread while writes carry on into the active segment, and the result
+//! is installed into the store the next time it writes, or when
+//! `finish_compaction()` is called.
+//!
+//! The merged segment is only renamed into place when it is installed,
+//! after the store has closed its own handles to the segments it
+//! replaces, because Windows does not let an open file be replaced or
+//! removed. A `Snapshot` or `Watch` still reading those segments keeps
+//! them open, so there the install fails until it is dropped.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::thread::{self, JoinHandle};
+
+use crate::bloom;
+use crate::encryption::{self, Cipher};
+use crate::record::{self, RecordKind, FORMAT_VERSION};
+use crate::segment::{self, Segment, MERGED_FROM};
+use crate::{ActionKV, ByteString, Entry, Position, Result};
+
+/// The path of a merged segment, which is yet to be renamed into place,
+/// and the offset of each live key within it.
+type Merged = (PathBuf, Vec<(ByteString, u64)>);
+
+/// A merge running in the background.
+#[derive(Debug)]
+pub(crate) struct Compaction {
+  first: u32,
+  last: u32,
+
+  worker: JoinHandle<io::Result<Merged>>,
+}
+
+impl ActionKV {
+  /// Rewrites the store so that it only holds the latest value of each
+  /// key that has not been deleted, then rebuilds `index` to match.
+  ///
+  /// A single file is rewritten into a temporary file next to the
+  /// original, which is synced and then renamed over it. If anything
+  /// fails before the rename, the original file is left untouched.
+  /// The new file always uses `FORMAT_VERSION`, so compacting a legacy
+  /// file upgrades it.
+  ///
+  /// A segmented store seals its active segment and merges every segment
+  /// into one, waiting for the merge to finish. See `start_compaction()`
+  /// to merge in the background instead.
+  ///
+  /// Either way, a fresh hint file is written afterwards.
+  ///
+  /// A store that uses the LSM engine flushes its memtable and merges
+  /// every table into its lowest level instead. See the `lsm` module.
+  pub fn compact(&mut self) -> Result<()> {
+    self.check_writable()?;
+    if self.lsm.is_some() {
+      self.check_lsm_intact()?;
+      return Ok(self.compact_lsm()?);
+    }
+    if !self.is_segmented() {
+      return Ok(self.compact_file()?);
+    }
+
+    // The merge remaps `index` rather than rebuilding it, so it has to
+    // cover the whole log first
+    self.finish_compaction(true)?;
+    if !self.loaded {
+      self.load()?;
+    }
+
+    if !self.active().is_empty() {
+      self.roll()?;
+    }
+    // `roll()` may already have started a merge of its own
+    self.start_compaction()?;
+    self.finish_compaction(true)?;
+
+    Ok(self.write_hint()?)
+  }
+
+  fn compact_file(&mut self) -> io::Result<()> {
+    // Scan the log rather than trusting `index`, which may be partial
+    // if `load()` has not been called.
+    let now = record::now();
+    let mut latest = self.index.empty_like();
+    self.scan_log(|position, record| {
+      ActionKV::index_record(&mut latest, position, record, now);
+    })?;
+
+    // With an ordered index, the live records are written in key order
+    let mut index = self.index.empty_like();
+    self.active().rewrite(|segment, f, mut offset| {
+      for (key, old) in latest.iter() {
+        // Copied as stored, so an encrypted record stays sealed
+        let record = segment.read_record(old.position.offset)?;
+
+        let written = ActionKV::copy_record(f, &record)?;
+        let entry = Entry { position: segment.position(offset), expires_at: record.expires_at };
+        index.insert(key.clone(), entry);
+        offset += written;
+      }
+      Ok(())
+    })?;
+    self.index = index;
+    self.loaded = true;
+    self.retarget_flusher()?;
+    self.load_blooms()?;
+
+    self.write_hint()
+  }
+
+  /// Starts merging every sealed segment into one on a background
+  /// thread. Returns `false` without doing anything if the store is not
+  /// segmented, has no sealed segments, or is already compacting.
+  ///
+  /// Reads and writes carry on as normal in the meantime, and the store
+  /// keeps reading from the old segments until the merge is installed by
+  /// `finish_compaction()` or by the next write.
+  pub fn start_compaction(&mut self) -> Result<bool> {
+    self.check_writable()?;
+    if !self.is_segmented() || self.compaction.is_some() || self.segments.len() < 2 {
+      return Ok(false);
+    }
+
+    let sealed = &self.segments[..self.segments.len() - 1];
+    let inputs: Vec<(u32, PathBuf)> = sealed
+      .iter()
+      .map(|segment| (segment.id, segment.path.clone()))
+      .collect();
+    let first = sealed[0].id;
+    let last = sealed[sealed.len() - 1].id;
+    let cipher = self.cipher.clone();
+
+    let worker = thread::Builder::new()
+      .name("actionkv-compaction".to_string())
+      .spawn(move || merge(inputs, cipher))?;
+
+    self.compaction = Some(Compaction { first, last, worker });
+    Ok(true)
+  }
+
+  /// Whether a merge started by `start_compaction()` has yet to be
+  /// installed.
+  pub fn is_compacting(&self) -> bool {
+    self.compaction.is_some()
+  }
+
+  /// Installs a background merge once it has finished: the segments it
+  /// merged are swapped for the merged one and `index` is pointed at it.
+  /// With `wait`, blocks until the merge is done. Returns whether a merge
+  /// was installed, or the error that stopped it, in which case the
+  /// store carries on with the segments it had.
+  pub fn finish_compaction(&mut self, wait: bool) -> Result<bool> {
+    match &self.compaction {
+      None => return Ok(false),
+      Some(compaction) if !wait && !compaction.worker.is_finished() => return Ok(false),
+      Some(_) => {},
+    }
+
+    let Compaction { first, last, worker } = self.compaction.take().unwrap();
+    let (tmp_path, entries) = match worker.join() {
+      Ok(result) => result?,
+      Err(_) => return Err(io::Error::other("compaction thread panicked").into()),
+    };
+
+    // Let go of the merged segments, so that their files can be replaced
+    let count = self.segments.iter().take_while(|segment| segment.id <= last).count();
+    let replaced: Vec<(u32, PathBuf)> = self
+      .segments
+      .drain(..count)
+      .map(|segment| (segment.id, segment.path))
+      .collect();
+    let (_, last_path) = &replaced[replaced.len() - 1];
+    if let Err(err) = fs::rename(&tmp_path, last_path) {
+      // Nothing has changed on disk, so the store goes back to the
+      // segments it had
+      let _ = fs::remove_file(&tmp_path);
+      let mut reopened = Vec::with_capacity(replaced.len());
+      for (id, path) in &replaced {
+        reopened.push(Segment::open(*id, path)?);
+      }
+      self.segments.splice(..0, reopened);
+      self.load_blooms()?;
+      return Err(err.into());
+    }
+
+    // From here on the merged segment is in place. If the process stops
+    // before the older segments are deleted, its `merged-from` record gets
+    // them deleted when the store is next opened.
+    self.segments.insert(0, Segment::open(last, last_path)?);
+
+    // The merge left out keys that had expired, so their entries would
+    // otherwise point into segments that are gone
+    let now = record::now();
+    self.index.retain(|_, entry| {
+      !(first..=last).contains(&entry.position.segment) || !entry.is_expired(now)
+    });
+
+    // Keys written since the merge started already point at the active
+    // segment, and keep doing so
+    for (key, offset) in entries {
+      let entry = match self.index.get(&key) {
+        Some(entry) if (first..=last).contains(&entry.position.segment) => *entry,
+        _ => continue,
+      };
+      let position = Position { segment: last, offset };
+      self.index.insert(key, Entry { position, ..entry });
+    }
+
+    remove_merged(&replaced)?;
+    self.load_blooms()?;
+
+    if self.loaded {
+      self.write_hint()?;
+    }
+    Ok(true)
+  }
+}
+
+/// Merges `inputs`, which must be every sealed segment in order, into a
+/// single segment that is to take the place of the last of them. Returns
+/// the path it was written to and the offset of each live key in it.
+/// Records are copied as stored; `cipher` is only needed to read their
+/// keys.
+fn merge(inputs: Vec<(u32, PathBuf)>, cipher: Option<Cipher>) -> io::Result<Merged> {
+  let mut segments = Vec::with_capacity(inputs.len());
+  for (id, path) in &inputs {
+    segments.push(Segment::open(*id, path)?);
+  }
+
+  let now = record::now();
+  let mut latest: HashMap<ByteString, Option<(usize, u64)>> = HashMap::new();
+  let mut last_seq = 0;
+  let mut failed = None;
+  for (i, segment) in segments.iter_mut().enumerate() {
+    let start = segment.data_start();
+    let id = segment.id;
+    last_seq = last_seq.max(segment.last_seq()?);
+    segment.scan(start, false, |offset, record| {
+      if failed.is_some() {
+        return;
+      }
+      last_seq = last_seq.max(record.version());
+      let record = match encryption::unseal(cipher.as_ref(), Position { segment: id, offset }, record) {
+        Ok(record) => record,
+        Err(err) => {
+          failed = Some(err);
+          return;
+        },
+      };
+      match record.kind {
+        RecordKind::Value if !record.is_expired(now) => {
+          latest.insert(record.key, Some((i, offset)));
+        },
+        RecordKind::Value | RecordKind::Tombstone => {
+          latest.insert(record.key, None);
+        },
+        _ => {},
+      }
+    })?;
+    if let Some(err) = failed {
+      return Err(err);
+    }
+  }
+
+  // The oldest segment is always part of the merge, so a tombstone or an
+  // expired record has nothing left to hide and can be dropped
+  let mut live: Vec<(ByteString, (usize, u64))> = latest
+    .into_iter()
+    .filter_map(|(key, location)| location.map(|location| (key, location)))
+    .collect();
+  live.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+  let (first, _) = inputs[0];
+  let (_, last_path) = &inputs[inputs.len() - 1];
+  let tmp_path = ActionKV::sibling_path(last_path, "compact");
+  let mut tmp = fs::OpenOptions::new()
+    .write(true)
+    .create(true)
+    .truncate(true)
+    .open(&tmp_path)?;
+
+  let mut entries = Vec::with_capacity(live.len());
+  {
+    let mut f = BufWriter::new(&mut tmp);
+    let mut offset = segment::write_file_header(&mut f)?;
+    offset += ActionKV::write_record(
+      &mut f,
+      FORMAT_VERSION,
+      RecordKind::Meta,
+      0,
+      None,
+      None,
+      MERGED_FROM,
+      &first.to_le_bytes(),
+    )?;
+    if last_seq > 0 {
+      offset += segment::write_last_seq(&mut f, last_seq)?;
+    }
+
+    for (key, (i, old_offset)) in live {
+      let record = segments[i].read_record(old_offset)?;
+      let written = ActionKV::copy_record(&mut f, &record)?;
+      entries.push((key, offset));
+      offset += written;
+    }
+    f.flush()?;
+  }
+  tmp.sync_all()?;
+
+  Ok((tmp_path, entries))
+}
+
+/// Deletes the segments that were merged into the last of `replaced`,
+/// and the bloom filters of all of them, once the merged segment has been
+/// renamed into place.
+fn remove_merged(replaced: &[(u32, PathBuf)]) -> io::Result<()> {
+  let (_, last_path) = &replaced[replaced.len() - 1];
+  for (_, path) in &replaced[..replaced.len() - 1] {
+    fs::remove_file(path)?;
+    bloom::remove_bloom(path)?;
+  }
+  bloom::remove_bloom(last_path)?;
+  ActionKV::sync_parent_dir(last_path)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::BTreeMap;
+  use std::fs;
+  use std::path::Path;
+  use std::thread;
+  use std::time::Duration;
+
+  use crate::{ActionKV, MemoryStorage, OpenOptions, Storage};
+
+  fn check(store: &ActionKV, model: &BTreeMap<String, Option<String>>) {
+    for (key, value) in model {
+      let found = store.get(key.as_bytes()).unwrap();
+      assert_eq!(found, value.as_ref().map(|value| value.as_bytes().to_vec()), "key {}", key);
+    }
+    let live = model.values().filter(|value| value.is_some()).count();
+    assert_eq!(store.iter().count(), live);
+  }
+
+  fn segment_files(dir: &Path) -> usize {
+    fs::read_dir(dir)
+      .unwrap()
+      .filter(|entry| entry.as_ref().unwrap().path().extension().is_some_and(|ext| ext == "akv"))
+      .count()
+  }
+
+  #[test]
+  fn writes_carry_on_while_compacting() {
+    let dir = std::env::temp_dir().join(format!("actionkv-compaction-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+
+    let mut store = OpenOptions::new().segment_size(4096).open(&dir).unwrap();
+    store.load().unwrap();
+    let mut model = BTreeMap::new();
+    let mut write = |store: &mut ActionKV, i: usize| {
+      let key = format!("key{}", i % 300);
+      match i % 7 {
+        0 => {
+          store.delete(key.as_bytes()).unwrap();
+          model.insert(key, None);
+        },
+        _ => {
+          let value = format!("value{}", i);
+          store.insert(key.as_bytes(), value.as_bytes()).unwrap();
+          model.insert(key, Some(value));
+        },
+      }
+    };
+
+    for i in 0..3000 {
+      write(&mut store, i);
+    }
+    let sealed = store.segment_ids().len() - 1;
+    assert!(sealed > 2);
+
+    assert!(store.start_compaction().unwrap());
+    for i in 3000..4000 {
+      write(&mut store, i);
+    }
+    store.finish_compaction(true).unwrap();
+    assert!(!store.is_compacting());
+    check(&store, &model);
+    assert!(store.segment_ids().len() < sealed);
+    assert_eq!(segment_files(&dir), store.segment_ids().len());
+
+    store.close().unwrap();
+    let mut store = ActionKV::open(&dir).unwrap();
+    store.load().unwrap();
+    check(&store, &model);
+    drop(store);
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn compaction_keeps_only_live_records() {
+    let mem = MemoryStorage::new();
+    let mut store = OpenOptions::new().open_storage(mem.clone()).unwrap();
+    store.load().unwrap();
+    store.insert(b"a", b"1").unwrap();
+    store.insert(b"a", b"2").unwrap();
+    store.insert(b"b", b"1").unwrap();
+    store.delete(b"b").unwrap();
+    store.insert(b"c", b"1").unwrap();
+    let before = mem.len().unwrap();
+    assert_eq!(store.check().unwrap().records, 5);
+
+    // Only the latest insert of each live key is left: no overwritten
+    // values, and no delete or the value it hid
+    store.compact().unwrap();
+    assert_eq!(store.check().unwrap().records, 2);
+    assert!(mem.len().unwrap() < before);
+    drop(store);
+
+    let mut store = OpenOptions::new().open_storage(mem.clone()).unwrap();
+    store.load().unwrap();
+    let model = BTreeMap::from([
+      ("a".to_string(), Some("2".to_string())),
+      ("b".to_string(), None),
+      ("c".to_string(), Some("1".to_string())),
+    ]);
+    check(&store, &model);
+    assert_eq!(store.check().unwrap().records, 2);
+  }
+
+  #[test]
+  fn expired_keys_are_hidden_and_dropped() {
+    let mem = MemoryStorage::new();
+    let mut store = OpenOptions::new().open_storage(mem.clone()).unwrap();
+    store.load().unwrap();
+    store.insert_with_ttl(b"a", b"1", Duration::from_millis(1)).unwrap();
+    store.insert_with_ttl(b"b", b"1", Duration::from_secs(3600)).unwrap();
+    thread::sleep(Duration::from_millis(10));
+
+    assert_eq!(store.get(b"a").unwrap(), None);
+    assert_eq!(store.ttl(b"a"), None);
+    assert_eq!(store.iter().count(), 1);
+    drop(store);
+
+    let mut store = OpenOptions::new().open_storage(mem.clone()).unwrap();
+    store.load().unwrap();
+    assert!(!store.index.contains_key(b"a"));
+    store.compact().unwrap();
+    assert_eq!(store.check().unwrap().records, 1);
+    assert_eq!(store.get(b"b").unwrap(), Some(b"1".to_vec()));
+  }
+
+  #[test]
+  fn ttl_survives_compaction() {
+    let mem = MemoryStorage::new();
+    let mut store = OpenOptions::new().open_storage(mem.clone()).unwrap();
+    store.load().unwrap();
+    let ttl = Duration::from_secs(3600);
+    store.insert_with_ttl(b"a", b"1", ttl).unwrap();
+    store.insert(b"a", b"2").unwrap();
+    store.insert_with_ttl(b"a", b"3", ttl).unwrap();
+    store.compact().unwrap();
+    drop(store);
+
+    let mut store = OpenOptions::new().open_storage(mem).unwrap();
+    store.load().unwrap();
+    assert_eq!(store.get(b"a").unwrap(), Some(b"3".to_vec()));
+    let left = store.ttl(b"a").unwrap();
+    assert!(left <= ttl && left > ttl - Duration::from_secs(60), "{:?}", left);
+  }
+}
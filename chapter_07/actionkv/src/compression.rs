@@ -0,0 +1,181 @@
+//! Optional compression of values.
+//!
+//! A compressed record carries the `LZ4` or `ZSTD` flag, and its value
+//! holds the compressed bytes. The checksum covers those bytes as they
+//! are stored, so damage is caught before anything is decompressed. Keys
+//! are never compressed, and records with and without compression can sit
+//! side by side in one file.
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use crate::record::{Record, LZ4, ZSTD};
+use crate::{ByteString, KeyValuePair};
+
+/// How values are compressed when they are written. Choose one for the
+/// whole store with `OpenOptions::compression()`, or for a single write
+/// with `WriteOptions::compression()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+  #[default]
+  None,
+
+  Lz4,
+
+  /// Zstandard at the given level, from 1 to 22. 0 picks zstd's default.
+  Zstd(i32),
+}
+
+impl Compression {
+  /// Compresses `value`, returning the flag to store alongside it.
+  /// Values that would not get any smaller are stored as they are, with
+  /// no flag.
+  pub(crate) fn compress(self, value: &[u8]) -> io::Result<(u8, ByteString)> {
+    let compressed = match self {
+      Compression::None => return Ok((0, value.to_vec())),
+      Compression::Lz4 => (LZ4, lz4_flex::compress_prepend_size(value)),
+      Compression::Zstd(level) => (ZSTD, zstd::bulk::compress(value, level)?),
+    };
+
+    match compressed.1.len() < value.len() {
+      true => Ok(compressed),
+      false => Ok((0, value.to_vec())),
+    }
+  }
+}
+
+impl fmt::Display for Compression {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Compression::None => write!(f, "none"),
+      Compression::Lz4 => write!(f, "lz4"),
+      Compression::Zstd(0) => write!(f, "zstd"),
+      Compression::Zstd(level) => write!(f, "zstd:{}", level),
+    }
+  }
+}
+
+/// Parses `none`, `lz4`, `zstd` or `zstd:LEVEL`.
+impl FromStr for Compression {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || format!("invalid compression {:?}", s);
+
+    let compression = match s {
+      "none" => Compression::None,
+      "lz4" => Compression::Lz4,
+      "zstd" => Compression::Zstd(0),
+      _ if s.starts_with("zstd:") => {
+        let level = s.trim_start_matches("zstd:").parse().map_err(|_| invalid())?;
+        Compression::Zstd(level)
+      },
+      _ => return Err(invalid()),
+    };
+
+    Ok(compression)
+  }
+}
+
+impl Record {
+  /// The record's key and value, with the value decompressed if need be.
+  pub fn into_pair(self) -> io::Result<KeyValuePair> {
+    let value = decompress(self.flags, self.value)?;
+    Ok(KeyValuePair { key: self.key, value })
+  }
+}
+
+fn decompress(flags: u8, stored: ByteString) -> io::Result<ByteString> {
+  let invalid = |err: String| {
+    io::Error::new(io::ErrorKind::InvalidData, format!("unable to decompress value: {}", err))
+  };
+
+  if flags & LZ4 != 0 {
+    return lz4_flex::decompress_size_prepended(&stored).map_err(|err| invalid(err.to_string()));
+  }
+  if flags & ZSTD != 0 {
+    // A value was never compressed unless that made it smaller, and no
+    // value is longer than `u32::MAX`
+    let capacity = zstd::zstd_safe::get_frame_content_size(&stored)
+      .ok()
+      .flatten()
+      .filter(|size| *size <= u32::MAX as u64)
+      .ok_or_else(|| invalid("unknown content size".to_string()))?;
+    return zstd::bulk::decompress(&stored, capacity as usize).map_err(|err| invalid(err.to_string()));
+  }
+
+  Ok(stored)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Compression;
+  use crate::record::{COMPRESSED, LZ4, ZSTD};
+  use crate::{ActionKV, ByteString, MemoryStorage, OpenOptions, Storage, WriteOptions};
+
+  fn open(mem: &MemoryStorage, compression: Compression) -> ActionKV {
+    let mut store = OpenOptions::new().compression(compression).open_storage(mem.clone()).unwrap();
+    store.load().unwrap();
+    store
+  }
+
+  fn blob() -> ByteString {
+    let item = r#"{"id":1,"name":"actionkv","tags":["log","store"]},"#;
+    format!("[{}]", item.repeat(200)).into_bytes()
+  }
+
+  fn flags(store: &ActionKV, key: &[u8]) -> u8 {
+    let position = store.index.get(key).unwrap().position;
+    store.record_at(position).unwrap().flags & COMPRESSED
+  }
+
+  #[test]
+  fn compressed_values_round_trip() {
+    for (compression, flag) in [(Compression::Lz4, LZ4), (Compression::Zstd(0), ZSTD), (Compression::Zstd(19), ZSTD)] {
+      let mem = MemoryStorage::new();
+      let mut store = open(&mem, compression);
+      store.insert(b"blob", &blob()).unwrap();
+      store.insert(b"short", b"x").unwrap();
+      assert!(mem.len().unwrap() < blob().len() as u64, "{}", compression);
+
+      // Values that would not shrink are stored as they are
+      assert_eq!(flags(&store, b"blob"), flag);
+      assert_eq!(flags(&store, b"short"), 0);
+      let position = store.index.get(&b"blob"[..]).unwrap().position;
+      assert_eq!(store.get_at(position).unwrap().value, blob());
+      drop(store);
+
+      // Nor does reading them depend on the store's own setting
+      let store = open(&mem, Compression::None);
+      assert_eq!(store.get(b"blob").unwrap(), Some(blob()));
+      assert_eq!(store.get(b"short").unwrap(), Some(b"x".to_vec()));
+      assert!(store.check().unwrap().is_clean());
+    }
+  }
+
+  #[test]
+  fn write_options_override_the_store() {
+    let mem = MemoryStorage::new();
+    let mut store = open(&mem, Compression::Zstd(0));
+    store.insert(b"default", &blob()).unwrap();
+    store.insert_with(b"lz4", &blob(), WriteOptions::new().compression(Compression::Lz4)).unwrap();
+    store.insert_with(b"raw", &blob(), WriteOptions::new().compression(Compression::None)).unwrap();
+
+    assert_eq!(flags(&store, b"default"), ZSTD);
+    assert_eq!(flags(&store, b"lz4"), LZ4);
+    assert_eq!(flags(&store, b"raw"), 0);
+    for key in [&b"default"[..], b"lz4", b"raw"] {
+      assert_eq!(store.get(key).unwrap(), Some(blob()));
+    }
+  }
+
+  #[test]
+  fn names_parse_back() {
+    for compression in [Compression::None, Compression::Lz4, Compression::Zstd(0), Compression::Zstd(3)] {
+      assert_eq!(compression.to_string().parse::<Compression>(), Ok(compression));
+    }
+    assert!("zstd:x".parse::<Compression>().is_err());
+    assert!("gzip".parse::<Compression>().is_err());
+  }
+}
@@ -0,0 +1,231 @@
+//! Writes that only go ahead if a key has not changed since it was read.
+//!
+//! Every insert and delete is written with a sequence number, its `seq`,
+//! one more than that of the insert or delete before it. The version of
+//! a key is the `seq` of the record that last inserted it, so it changes
+//! whenever the key is written, and never goes back to an earlier value.
+//! Keys last written before records had a `seq` are at version 0.
+//!
+//! A caller reads a key with `get_with_version()`, works out the new
+//! value, and writes it back with `compare_and_swap()`, which fails with
+//! a `Conflict` if someone else wrote the key in between. Through a
+//! `Handle`, the check and the write happen under one lock.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::record::{self, Record, RecordKind, LEGACY_VERSION};
+use crate::{ActionKV, ActionKvError, ByteStr, ByteString, Result};
+
+/// A conditional write found the key at another version than it
+/// expected. Returned as `ActionKvError::Conflict`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+  pub key: ByteString,
+
+  /// The version the write expected, or `None` if it expected the key
+  /// not to exist.
+  pub expected: Option<u64>,
+
+  /// The version the key is at, or `None` if it does not exist.
+  pub actual: Option<u64>,
+}
+
+impl Conflict {
+  /// Returns the `Conflict` error carried by `err`, if there is one.
+  pub fn from_io_error(err: &io::Error) -> Option<&Conflict> {
+    err.get_ref().and_then(|inner| inner.downcast_ref::<Conflict>())
+  }
+
+  pub(crate) fn into_io_error(self) -> io::Error {
+    io::Error::other(self)
+  }
+}
+
+impl fmt::Display for Conflict {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let describe = |version: Option<u64>| match version {
+      Some(version) => format!("version {}", version),
+      None => "absent".to_string(),
+    };
+    write!(
+      f,
+      "{:?} was expected to be {} but is {}",
+      String::from_utf8_lossy(&self.key),
+      describe(self.expected),
+      describe(self.actual),
+    )
+  }
+}
+
+impl Error for Conflict {}
+
+impl ActionKV {
+  /// The value of `key` and its version, unless it has been deleted or
+  /// has expired.
+  pub fn get_with_version(&self, key: &ByteStr) -> Result<Option<(ByteString, u64)>> {
+    match self.live_record(key)? {
+      Some(record) => {
+        let version = record.version();
+        Ok(Some((record.into_pair()?.value, version)))
+      },
+      None => Ok(None),
+    }
+  }
+
+  /// Inserts `value` for `key` if the key is at `expected_version`, and
+  /// returns its new version. Fails with a `Conflict` if it is at another
+  /// version or does not exist.
+  pub fn compare_and_swap(&mut self, key: &ByteStr, expected_version: u64, value: &ByteStr) -> Result<u64> {
+    self.check_version(key, Some(expected_version))?;
+    self.insert(key, value)?;
+    Ok(self.last_seq()?)
+  }
+
+  /// Inserts `value` for `key` if the key does not exist, and returns its
+  /// version. Fails with a `Conflict` if it does.
+  pub fn insert_if_absent(&mut self, key: &ByteStr, value: &ByteStr) -> Result<u64> {
+    self.check_version(key, None)?;
+    self.insert(key, value)?;
+    Ok(self.last_seq()?)
+  }
+
+  /// Deletes `key` if it is at `expected_version`. Fails with a
+  /// `Conflict` if it is at another version or does not exist.
+  pub fn delete_if_version(&mut self, key: &ByteStr, expected_version: u64) -> Result<()> {
+    self.check_version(key, Some(expected_version))?;
+    self.delete(key)
+  }
+
+  fn check_version(&self, key: &ByteStr, expected: Option<u64>) -> io::Result<()> {
+    // Records in a version 1 file have no `seq`, so every key would look
+    // unchanged
+    if self.version() == LEGACY_VERSION {
+      return Err(ActionKvError::NeedsUpgrade { feature: "conditional writes", found: LEGACY_VERSION }.into());
+    }
+
+    let actual = self.live_record(key)?.map(|record| record.version());
+    match actual == expected {
+      true => Ok(()),
+      false => Err(Conflict { key: key.to_vec(), expected, actual }.into_io_error()),
+    }
+  }
+
+  /// The record that holds the current value of `key`, if it has one.
+  /// Like `get()`, falls back to `find_record()` until `load()` has been
+  /// called, and to the tables with the LSM engine.
+  fn live_record(&self, key: &ByteStr) -> io::Result<Option<Record>> {
+    let now = record::now();
+    let record = match self.index.get(key) {
+      Some(entry) if entry.is_expired(now) => return Ok(None),
+      Some(entry) => self.record_at(entry.position)?,
+      None if self.lsm.is_some() => match self.lsm_record(key)? {
+        Some((_, record)) => record,
+        None => return Ok(None),
+      },
+      None if !self.loaded => match self.find_record(key)? {
+        Some((_, record)) => record,
+        None => return Ok(None),
+      },
+      None => return Ok(None),
+    };
+
+    match record.kind {
+      RecordKind::Value if !record.is_expired(now) => Ok(Some(record)),
+      _ => Ok(None),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::thread;
+  use std::time::Duration;
+
+  use super::*;
+  use crate::{MemoryStorage, OpenOptions};
+
+  fn open(mem: &MemoryStorage) -> ActionKV {
+    let mut store = OpenOptions::new().open_storage(mem.clone()).unwrap();
+    store.load().unwrap();
+    store
+  }
+
+  fn conflict<T: fmt::Debug>(result: Result<T>) -> (Option<u64>, Option<u64>) {
+    match result {
+      Err(ActionKvError::Conflict(conflict)) => (conflict.expected, conflict.actual),
+      other => panic!("expected a conflict, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn stale_version_conflicts() {
+    let mut store = open(&MemoryStorage::new());
+    let first = store.insert_if_absent(b"a", b"1").unwrap();
+    let second = store.compare_and_swap(b"a", first, b"2").unwrap();
+    assert!(second > first);
+
+    assert_eq!(conflict(store.compare_and_swap(b"a", first, b"3")), (Some(first), Some(second)));
+    assert_eq!(conflict(store.delete_if_version(b"a", first)), (Some(first), Some(second)));
+    assert_eq!(store.get_with_version(b"a").unwrap(), Some((b"2".to_vec(), second)));
+
+    store.delete_if_version(b"a", second).unwrap();
+    assert_eq!(store.get(b"a").unwrap(), None);
+  }
+
+  #[test]
+  fn missing_key_conflicts_unless_expected_absent() {
+    let mut store = open(&MemoryStorage::new());
+    assert_eq!(conflict(store.compare_and_swap(b"a", 1, b"1")), (Some(1), None));
+    assert_eq!(conflict(store.delete_if_version(b"a", 1)), (Some(1), None));
+    assert_eq!(store.get(b"a").unwrap(), None);
+
+    let version = store.insert_if_absent(b"a", b"1").unwrap();
+    assert_eq!(conflict(store.insert_if_absent(b"a", b"2")), (None, Some(version)));
+
+    // A deleted key is absent again
+    store.delete(b"a").unwrap();
+    assert_eq!(conflict(store.compare_and_swap(b"a", version, b"3")), (Some(version), None));
+    assert!(store.insert_if_absent(b"a", b"4").unwrap() > version);
+  }
+
+  #[test]
+  fn expired_key_is_absent() {
+    let mut store = open(&MemoryStorage::new());
+    store.insert_with_ttl(b"a", b"1", Duration::from_millis(1)).unwrap();
+    let version = store.last_seq().unwrap();
+    thread::sleep(Duration::from_millis(10));
+
+    assert_eq!(store.get_with_version(b"a").unwrap(), None);
+    assert_eq!(conflict(store.compare_and_swap(b"a", version, b"2")), (Some(version), None));
+    assert_eq!(conflict(store.delete_if_version(b"a", version)), (Some(version), None));
+    let new = store.insert_if_absent(b"a", b"3").unwrap();
+    assert!(new > version);
+    assert_eq!(store.get_with_version(b"a").unwrap(), Some((b"3".to_vec(), new)));
+  }
+
+  #[test]
+  fn versions_survive_compaction_and_reopen() {
+    let mem = MemoryStorage::new();
+    let mut store = open(&mem);
+    store.insert(b"a", b"1").unwrap();
+    store.insert(b"b", b"1").unwrap();
+    store.insert(b"a", b"2").unwrap();
+    let (_, a) = store.get_with_version(b"a").unwrap().unwrap();
+    store.delete(b"b").unwrap();
+    let last = store.last_seq().unwrap();
+
+    store.compact().unwrap();
+    assert_eq!(store.get_with_version(b"a").unwrap(), Some((b"2".to_vec(), a)));
+    drop(store);
+
+    let mut store = open(&mem);
+    assert_eq!(store.get_with_version(b"a").unwrap(), Some((b"2".to_vec(), a)));
+
+    // The delete of `b` was compacted away, but versions still only go up
+    let b = store.insert_if_absent(b"b", b"2").unwrap();
+    assert!(b > last);
+    assert!(store.compare_and_swap(b"a", a, b"3").unwrap() > b);
+  }
+}
@@ -0,0 +1,311 @@
+//! Encryption at rest.
+//!
+//! A store opened with `ActionKV::open_encrypted()` seals the key and
+//! value of every `Value` and `Tombstone` record with XChaCha20-Poly1305
+//! and sets the record's `ENCRYPTED` flag:
+//!
+//! ```text
+//! +------------+-------------------+----------------+--------------+
+//! | header ... | key (ciphertext)  | value (cipher- | tag | nonce  |
+//! |            |                   | text)          |              |
+//! +------------+-------------------+----------------+--------------+
+//!                  key_len bytes     val_len - 40      16     24
+//! ```
+//!
+//! The record header is passed to the cipher as associated data, so it
+//! cannot be altered without the record failing to open either. The
+//! `BATCHED` flag is left out of it, as compaction clears that flag when it
+//! copies a record. The CRC32 is kept, and still covers every stored byte:
+//! a checksum mismatch is damage that `repair()` can deal with, while a
+//! record that passes its checksum but fails to open was written with a
+//! different key or deliberately altered, and is reported as an
+//! `AuthenticationError`.
+//!
+//! `Commit` and `Meta` records hold no user data and are only checksummed.
+//! In an encrypted store, a `Value` or `Tombstone` record without the
+//! `ENCRYPTED` flag is rejected rather than trusted, so an existing
+//! plaintext store cannot be opened with a key. Hint files are sealed with
+//! the same key, since they hold every key in the store.
+//!
+//! Encryption keeps keys and values secret and detects records that were
+//! altered, but it does not detect records that were moved. The associated
+//! data ties a record to its own header, `seq` included, and nonces are
+//! random, but nothing ties a record to its position or to the store:
+//! positions are left out so that compaction can copy records without
+//! sealing them again, and a store has no identity to bind. Someone who
+//! can write to the files can copy a sealed record to the end of the log
+//! to bring back an old value, reorder or drop whole records, or copy
+//! records between stores that share a key, and every record still opens.
+//! A replayed record keeps its old `seq`, so its version goes backwards,
+//! but `load()` does not check for that.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use chacha20poly1305::aead::{AeadCore, AeadInPlace, KeyInit, OsRng};
+use chacha20poly1305::{Tag, XChaCha20Poly1305, XNonce};
+
+use crate::record::{Record, RecordKind, BATCHED, ENCRYPTED, EXPIRES, SEQ};
+use crate::{ByteString, Position};
+
+/// Length of an encryption key, in bytes.
+pub const KEY_LEN: usize = 32;
+
+const TAG_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+/// What sealing adds to the length of a value.
+const OVERHEAD: usize = TAG_LEN + NONCE_LEN;
+
+/// A record that passed its checksum but could not be opened with the
+/// store's key: either the key is wrong or the record was tampered with.
+/// Returned as `ActionKvError::Authentication`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthenticationError {
+  pub segment: u32,
+  pub offset: u64,
+}
+
+impl AuthenticationError {
+  /// Returns the `AuthenticationError` carried by `err`, if there is one.
+  pub fn from_io_error(err: &io::Error) -> Option<&AuthenticationError> {
+    err.get_ref().and_then(|inner| inner.downcast_ref::<AuthenticationError>())
+  }
+
+  pub fn position(&self) -> Position {
+    Position { segment: self.segment, offset: self.offset }
+  }
+
+  pub(crate) fn into_io_error(self) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, self)
+  }
+}
+
+impl fmt::Display for AuthenticationError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    // Segment 0 is a store kept in a single file
+    let at = match self.segment {
+      0 => format!("offset {}", self.offset),
+      segment => format!("offset {} of segment {}", self.offset, segment),
+    };
+    write!(f, "record at {} failed authentication; the key is wrong or the record was altered", at)
+  }
+}
+
+impl Error for AuthenticationError {}
+
+/// The cipher a store was opened with.
+#[derive(Clone)]
+pub(crate) struct Cipher(XChaCha20Poly1305);
+
+impl Cipher {
+  pub fn new(key: &[u8; KEY_LEN]) -> Self {
+    Cipher(XChaCha20Poly1305::new(key.into()))
+  }
+
+  /// Encrypts the key and value of `record` and sets its `ENCRYPTED`
+  /// flag. Records other than values and tombstones are returned as they
+  /// are.
+  pub fn seal(&self, mut record: Record) -> io::Result<Record> {
+    if !matches!(record.kind, RecordKind::Value | RecordKind::Tombstone) {
+      return Ok(record);
+    }
+
+    record.flags |= ENCRYPTED;
+    if record.expires_at.is_some() {
+      record.flags |= EXPIRES;
+    }
+    if record.seq.is_some() {
+      record.flags |= SEQ;
+    }
+    let val_len = record.value.len() + OVERHEAD;
+    let aad = associated_data(&record, val_len);
+
+    let key_len = record.key.len();
+    let mut buf = std::mem::take(&mut record.key);
+    buf.append(&mut record.value);
+
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let tag = self
+      .0
+      .encrypt_in_place_detached(&nonce, &aad, &mut buf)
+      .map_err(|_| io::Error::other("unable to encrypt record"))?;
+
+    record.value = buf.split_off(key_len);
+    record.value.extend_from_slice(&tag);
+    record.value.extend_from_slice(&nonce);
+    record.key = buf;
+    Ok(record)
+  }
+
+  /// Decrypts a record written by `seal()`, which was read from
+  /// `position`, and clears its `ENCRYPTED` flag.
+  pub fn open(&self, position: Position, mut record: Record) -> io::Result<Record> {
+    if !matches!(record.kind, RecordKind::Value | RecordKind::Tombstone) {
+      return Ok(record);
+    }
+
+    let failed = || AuthenticationError { segment: position.segment, offset: position.offset }.into_io_error();
+    if record.flags & ENCRYPTED == 0 || record.value.len() < OVERHEAD {
+      return Err(failed());
+    }
+    let aad = associated_data(&record, record.value.len());
+
+    let nonce = record.value.split_off(record.value.len() - NONCE_LEN);
+    let tag = record.value.split_off(record.value.len() - TAG_LEN);
+
+    let key_len = record.key.len();
+    let mut buf = std::mem::take(&mut record.key);
+    buf.append(&mut record.value);
+
+    self
+      .0
+      .decrypt_in_place_detached(XNonce::from_slice(&nonce), &aad, &mut buf, Tag::from_slice(&tag))
+      .map_err(|_| failed())?;
+
+    record.value = buf.split_off(key_len);
+    record.key = buf;
+    record.flags &= !ENCRYPTED;
+    Ok(record)
+  }
+
+  /// Encrypts `plaintext` as a whole, for files other than the log.
+  pub fn seal_bytes(&self, aad: &[u8], mut plaintext: ByteString) -> io::Result<ByteString> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let tag = self
+      .0
+      .encrypt_in_place_detached(&nonce, aad, &mut plaintext)
+      .map_err(|_| io::Error::other("unable to encrypt"))?;
+
+    plaintext.extend_from_slice(&tag);
+    plaintext.extend_from_slice(&nonce);
+    Ok(plaintext)
+  }
+
+  /// Decrypts bytes written by `seal_bytes()`, or returns `None` if they
+  /// do not open with this key.
+  pub fn open_bytes(&self, aad: &[u8], mut sealed: ByteString) -> Option<ByteString> {
+    if sealed.len() < OVERHEAD {
+      return None;
+    }
+    let nonce = sealed.split_off(sealed.len() - NONCE_LEN);
+    let tag = sealed.split_off(sealed.len() - TAG_LEN);
+
+    self
+      .0
+      .decrypt_in_place_detached(XNonce::from_slice(&nonce), aad, &mut sealed, Tag::from_slice(&tag))
+      .ok()?;
+    Some(sealed)
+  }
+}
+
+// Keeps the key out of `ActionKV`'s debug output
+impl fmt::Debug for Cipher {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("Cipher")
+  }
+}
+
+/// Opens `record`, read from `position`, with the store's cipher. Without
+/// one, encrypted records are refused.
+pub(crate) fn unseal(cipher: Option<&Cipher>, position: Position, record: Record) -> io::Result<Record> {
+  match cipher {
+    Some(cipher) => cipher.open(position, record),
+    None if record.flags & ENCRYPTED != 0 => Err(io::Error::new(
+      io::ErrorKind::InvalidInput,
+      "the store is encrypted; open it with ActionKV::open_encrypted()",
+    )),
+    None => Ok(record),
+  }
+}
+
+/// The record header as written to disk, apart from the checksum and the
+/// `BATCHED` flag.
+fn associated_data(record: &Record, val_len: usize) -> ByteString {
+  let mut aad = ByteString::with_capacity(26);
+  aad.push(record.kind.to_byte());
+  aad.push(record.flags & !BATCHED);
+  aad.extend_from_slice(&(record.key.len() as u32).to_le_bytes());
+  aad.extend_from_slice(&(val_len as u32).to_le_bytes());
+  if let Some(expires_at) = record.expires_at {
+    aad.extend_from_slice(&expires_at.to_le_bytes());
+  }
+  if let Some(seq) = record.seq {
+    aad.extend_from_slice(&seq.to_le_bytes());
+  }
+  aad
+}
+
+#[cfg(test)]
+mod tests {
+  use crc::crc32;
+
+  use super::*;
+  use crate::record::FORMAT_VERSION;
+  use crate::{ActionKV, ActionKvError, MemoryStorage, OpenOptions, Storage};
+
+  const KEY: [u8; KEY_LEN] = [7; KEY_LEN];
+
+  fn open(mem: &MemoryStorage, key: &[u8; KEY_LEN]) -> ActionKV {
+    OpenOptions::new().encryption_key(key).open_storage(mem.clone()).unwrap()
+  }
+
+  /// A store holding `a` and `b`, and the offset of `b`'s record.
+  fn written() -> (MemoryStorage, u64) {
+    let mem = MemoryStorage::new();
+    let mut store = open(&mem, &KEY);
+    store.load().unwrap();
+    store.insert(b"a", b"1").unwrap();
+    let b = mem.len().unwrap();
+    store.insert(b"b", b"2").unwrap();
+    (mem, b)
+  }
+
+  fn assert_fails_authentication(store: &mut ActionKV, offset: u64) {
+    match store.load() {
+      Err(ActionKvError::Authentication(err)) => assert_eq!(err.position(), Position { segment: 0, offset }),
+      other => panic!("expected an authentication error, got {:?}", other),
+    }
+
+    // Nor is it damage for `repair()` to cut out
+    assert!(matches!(store.check(), Err(ActionKvError::Authentication(_))));
+  }
+
+  #[test]
+  fn tampered_record_fails_authentication() {
+    let (mem, b) = written();
+
+    // Alter the sealed value of `b` and give it a checksum that matches,
+    // as someone able to write to the file could
+    let mut bytes = mem.to_vec();
+    let end = bytes.len();
+    bytes[end - OVERHEAD - 1] ^= 1;
+    let checksum = crc32::checksum_ieee(&bytes[b as usize + 4..]);
+    bytes[b as usize..b as usize + 4].copy_from_slice(&checksum.to_le_bytes());
+
+    let mut store = open(&MemoryStorage::from_bytes(bytes), &KEY);
+    assert_fails_authentication(&mut store, b);
+  }
+
+  #[test]
+  fn wrong_key_fails_authentication() {
+    let (mem, _) = written();
+    let mut store = open(&mem, &[8; KEY_LEN]);
+    let first = store.first_position().offset;
+    assert_fails_authentication(&mut store, first);
+    assert!(matches!(store.get(b"a"), Err(ActionKvError::Authentication(_))));
+  }
+
+  #[test]
+  fn plaintext_record_is_rejected() {
+    let (mem, _) = written();
+    let c = mem.len().unwrap();
+    let mut record = Vec::new();
+    ActionKV::write_record(&mut record, FORMAT_VERSION, RecordKind::Value, 0, None, Some(3), b"c", b"3").unwrap();
+    mem.clone().append(&record).unwrap();
+
+    let mut store = open(&mem, &KEY);
+    assert_fails_authentication(&mut store, c);
+  }
+}
@@ -0,0 +1,209 @@
+//! The error type returned by the store.
+//!
+//! Inside the crate, records are read and written with `io::Result`, and
+//! the errors that need more than an `io::ErrorKind` (`Corruption`,
+//! `StoreLocked` and so on) travel inside an `io::Error`. The public API
+//! returns an `ActionKvError` instead, which pulls them back out so that
+//! callers can match on them. The two convert both ways with `?`, and
+//! converting an `ActionKvError` into an `io::Error` and back gives the
+//! same error.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::{AuthenticationError, CodecError, Compacted, Conflict, Corruption, StoreLocked, FORMAT_VERSION};
+
+/// `Result` with `ActionKvError` as its default error.
+pub type Result<T, E = ActionKvError> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum ActionKvError {
+  /// Reading or writing a file or socket failed.
+  Io(io::Error),
+
+  /// A record was cut short, or did not match its checksum.
+  Corruption(Corruption),
+
+  /// A record did not decrypt with the store's key.
+  Authentication(AuthenticationError),
+
+  /// A key longer than the `u32` length field of a record can describe.
+  KeyTooLarge { len: usize },
+
+  /// A value longer than the `u32` length field of a record can describe,
+  /// once compressed and encrypted.
+  ValueTooLarge { len: usize },
+
+  /// The store is already open elsewhere.
+  Locked(StoreLocked),
+
+  /// A file was written in a newer format than this build can read.
+  UnsupportedVersion { found: u32 },
+
+  /// The store is in a format too old for `feature`. `compact()`
+  /// upgrades it.
+  NeedsUpgrade { feature: &'static str, found: u32 },
+
+  /// A conditional write found the key at another version.
+  Conflict(Conflict),
+
+  /// A watch fell behind a compaction.
+  Compacted(Compacted),
+
+  /// A `TypedStore` could not encode or decode a key or value.
+  Codec(CodecError),
+}
+
+impl ActionKvError {
+  /// The `io::ErrorKind` that best describes the error.
+  pub fn kind(&self) -> io::ErrorKind {
+    match self {
+      ActionKvError::Io(err) => err.kind(),
+      ActionKvError::Corruption(_) => io::ErrorKind::InvalidData,
+      ActionKvError::Authentication(_) => io::ErrorKind::InvalidData,
+      ActionKvError::KeyTooLarge { .. } => io::ErrorKind::InvalidInput,
+      ActionKvError::ValueTooLarge { .. } => io::ErrorKind::InvalidInput,
+      ActionKvError::Locked(_) => io::ErrorKind::WouldBlock,
+      ActionKvError::UnsupportedVersion { .. } => io::ErrorKind::InvalidData,
+      ActionKvError::NeedsUpgrade { .. } => io::ErrorKind::InvalidInput,
+      ActionKvError::Conflict(_) => io::ErrorKind::Other,
+      ActionKvError::Compacted(_) => io::ErrorKind::Other,
+      ActionKvError::Codec(_) => io::ErrorKind::InvalidData,
+    }
+  }
+
+  /// Fails with `KeyTooLarge` or `ValueTooLarge` if either will not fit
+  /// in a record.
+  pub(crate) fn check_len(key: &[u8], value: &[u8]) -> io::Result<()> {
+    if key.len() > u32::MAX as usize {
+      return Err(ActionKvError::KeyTooLarge { len: key.len() }.into());
+    }
+    if value.len() > u32::MAX as usize {
+      return Err(ActionKvError::ValueTooLarge { len: value.len() }.into());
+    }
+    Ok(())
+  }
+}
+
+impl fmt::Display for ActionKvError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ActionKvError::Io(err) => err.fmt(f),
+      ActionKvError::Corruption(err) => err.fmt(f),
+      ActionKvError::Authentication(err) => err.fmt(f),
+      ActionKvError::KeyTooLarge { len } => {
+        write!(f, "key of {} bytes is larger than the {} bytes a record can hold", len, u32::MAX)
+      },
+      ActionKvError::ValueTooLarge { len } => {
+        write!(f, "value of {} bytes is larger than the {} bytes a record can hold", len, u32::MAX)
+      },
+      ActionKvError::Locked(err) => err.fmt(f),
+      ActionKvError::UnsupportedVersion { found } => write!(
+        f,
+        "unsupported format version {}; this build reads up to version {}",
+        found, FORMAT_VERSION
+      ),
+      ActionKvError::NeedsUpgrade { feature, found } => write!(
+        f,
+        "format version {} does not support {}; run compact() to upgrade the file",
+        found, feature
+      ),
+      ActionKvError::Conflict(err) => err.fmt(f),
+      ActionKvError::Compacted(err) => err.fmt(f),
+      ActionKvError::Codec(err) => err.fmt(f),
+    }
+  }
+}
+
+impl Error for ActionKvError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      ActionKvError::Io(err) => Some(err),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for ActionKvError {
+  fn from(err: io::Error) -> Self {
+    let kind = err.kind();
+    let inner = match err.get_ref() {
+      Some(_) => err.into_inner().expect("the error has an inner error"),
+      None => return ActionKvError::Io(err),
+    };
+
+    let inner = match inner.downcast::<ActionKvError>() {
+      Ok(err) => return *err,
+      Err(inner) => inner,
+    };
+    let inner = match inner.downcast::<Corruption>() {
+      Ok(err) => return ActionKvError::Corruption(*err),
+      Err(inner) => inner,
+    };
+    let inner = match inner.downcast::<AuthenticationError>() {
+      Ok(err) => return ActionKvError::Authentication(*err),
+      Err(inner) => inner,
+    };
+    let inner = match inner.downcast::<StoreLocked>() {
+      Ok(err) => return ActionKvError::Locked(*err),
+      Err(inner) => inner,
+    };
+    let inner = match inner.downcast::<Conflict>() {
+      Ok(err) => return ActionKvError::Conflict(*err),
+      Err(inner) => inner,
+    };
+    let inner = match inner.downcast::<Compacted>() {
+      Ok(err) => return ActionKvError::Compacted(*err),
+      Err(inner) => inner,
+    };
+    let inner = match inner.downcast::<CodecError>() {
+      Ok(err) => return ActionKvError::Codec(*err),
+      Err(inner) => inner,
+    };
+    ActionKvError::Io(io::Error::new(kind, inner))
+  }
+}
+
+impl From<ActionKvError> for io::Error {
+  fn from(err: ActionKvError) -> Self {
+    match err {
+      ActionKvError::Io(err) => err,
+      ActionKvError::Corruption(err) => err.into_io_error(),
+      ActionKvError::Authentication(err) => err.into_io_error(),
+      ActionKvError::Locked(err) => err.into_io_error(),
+      ActionKvError::Conflict(err) => err.into_io_error(),
+      ActionKvError::Compacted(err) => err.into_io_error(),
+      ActionKvError::Codec(err) => err.into_io_error(),
+      err => io::Error::new(err.kind(), err),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::Codec;
+
+  #[test]
+  fn round_trip() {
+    let codec = CodecError { codec: Codec::Json, action: "decode", message: "expected value".to_string() };
+    let err = ActionKvError::from(io::Error::from(ActionKvError::Codec(codec.clone())));
+    assert!(matches!(err, ActionKvError::Codec(found) if found == codec));
+
+    let conflict = Conflict { key: b"a".to_vec(), expected: Some(1), actual: Some(2) };
+    let err = ActionKvError::from(io::Error::from(ActionKvError::Conflict(conflict.clone())));
+    assert!(matches!(err, ActionKvError::Conflict(found) if found == conflict));
+
+    let err = ActionKvError::from(io::Error::from(ActionKvError::KeyTooLarge { len: 1 }));
+    assert!(matches!(err, ActionKvError::KeyTooLarge { len: 1 }));
+  }
+
+  #[test]
+  fn io_error_is_the_source() {
+    let err = ActionKvError::Io(io::Error::new(io::ErrorKind::NotFound, "no such file"));
+    let source = err.source().expect("an Io error has a source");
+    assert_eq!(source.to_string(), "no such file");
+    assert!(source.downcast_ref::<io::Error>().is_some());
+  }
+}
@@ -0,0 +1,357 @@
+//! Moving pairs in and out of a store in formats other tools can read.
+//!
+//! Three formats are supported:
+//!
+//! - JSON Lines: one `{"key": ..., "value": ...}` object per line. Keys
+//!   and values that are not valid UTF-8 are written base64-encoded under
+//!   `key_base64` or `value_base64` instead.
+//! - CSV: a `key,value` header followed by one row per pair. Fields hold
+//!   the raw bytes, quoted where needed.
+//! - Bincode: a `"AKVDUMP\0"` magic number and a u32 version, followed by
+//!   each `KeyValuePair` encoded with bincode. Compact and exact, but only
+//!   readable by this library.
+//!
+//! Only live pairs are exported, without their history or TTLs. Exports
+//! come in key order when the index is ordered, so the output of two
+//! stores can be compared with `diff`.
+
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::str::FromStr;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{ActionKV, ByteString, Iter, KeyValuePair, Result, Snapshot, WriteBatch};
+
+const DUMP_MAGIC: &[u8; 8] = b"AKVDUMP\0";
+const DUMP_VERSION: u32 = 1;
+
+/// Imports are written in batches of this many pairs.
+const IMPORT_BATCH: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+  JsonLines,
+  Csv,
+  Bincode,
+}
+
+impl fmt::Display for ExportFormat {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ExportFormat::JsonLines => write!(f, "jsonl"),
+      ExportFormat::Csv => write!(f, "csv"),
+      ExportFormat::Bincode => write!(f, "bincode"),
+    }
+  }
+}
+
+/// Parses `jsonl`, `csv` or `bincode`.
+impl FromStr for ExportFormat {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "jsonl" | "json" => Ok(ExportFormat::JsonLines),
+      "csv" => Ok(ExportFormat::Csv),
+      "bincode" => Ok(ExportFormat::Bincode),
+      _ => Err(format!("invalid export format {:?}", s)),
+    }
+  }
+}
+
+/// A pair as it appears on a line of JSON Lines.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct JsonPair {
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  key: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  key_base64: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  value: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  value_base64: Option<String>,
+}
+
+impl JsonPair {
+  fn new(kv: KeyValuePair) -> Self {
+    let mut pair = JsonPair::default();
+    match String::from_utf8(kv.key) {
+      Ok(key) => pair.key = Some(key),
+      Err(err) => pair.key_base64 = Some(BASE64.encode(err.as_bytes())),
+    }
+    match String::from_utf8(kv.value) {
+      Ok(value) => pair.value = Some(value),
+      Err(err) => pair.value_base64 = Some(BASE64.encode(err.as_bytes())),
+    }
+    pair
+  }
+
+  fn into_pair(self) -> Result<KeyValuePair, String> {
+    let key = decode_field("key", self.key, self.key_base64)?;
+    let value = decode_field("value", self.value, self.value_base64)?;
+    Ok(KeyValuePair { key, value })
+  }
+}
+
+fn decode_field(name: &str, text: Option<String>, base64: Option<String>) -> Result<ByteString, String> {
+  match (text, base64) {
+    (Some(text), None) => Ok(text.into_bytes()),
+    (None, Some(base64)) => BASE64
+      .decode(base64)
+      .map_err(|err| format!("invalid {}_base64: {}", name, err)),
+    (None, None) => Err(format!("missing {}", name)),
+    (Some(_), Some(_)) => Err(format!("both {} and {}_base64 given", name, name)),
+  }
+}
+
+impl ActionKV {
+  /// Writes every live pair to `w` in `format`, returning how many were
+  /// written. Pairs are read from the store one at a time, so the store
+  /// never has to fit in memory.
+  pub fn export<W: Write>(&self, w: W, format: ExportFormat) -> Result<u64> {
+    Ok(export_pairs(self.iter(), w, format)?)
+  }
+
+  /// Inserts every pair read from `r` in `format`, returning how many
+  /// were imported. Pairs are written in batches, so a failure part way
+  /// through leaves the store holding whole batches only. Existing keys
+  /// are overwritten; other keys are left alone.
+  pub fn import<R: Read>(&mut self, r: R, format: ExportFormat) -> Result<u64> {
+    let mut importer = Importer { store: self, batch: WriteBatch::new(), count: 0 };
+    let mut r = BufReader::new(r);
+
+    match format {
+      ExportFormat::JsonLines => {
+        for (i, line) in r.lines().enumerate() {
+          let line = line?;
+          if line.trim().is_empty() {
+            continue;
+          }
+          let invalid = |err: String| invalid_data(format!("line {}: {}", i + 1, err));
+          let pair: JsonPair = serde_json::from_str(&line).map_err(|err| invalid(err.to_string()))?;
+          importer.push(pair.into_pair().map_err(invalid)?)?;
+        }
+      },
+      ExportFormat::Csv => {
+        let mut csv = csv::Reader::from_reader(r);
+        for record in csv.byte_records() {
+          let record = record.map_err(csv_error)?;
+          if record.len() != 2 {
+            let line = record.position().map(|position| position.line()).unwrap_or(0);
+            return Err(invalid_data(format!("line {}: expected 2 fields, found {}", line, record.len())).into());
+          }
+          importer.push(KeyValuePair { key: record[0].to_vec(), value: record[1].to_vec() })?;
+        }
+      },
+      ExportFormat::Bincode => {
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != DUMP_MAGIC {
+          return Err(invalid_data("not an actionkv dump".to_string()).into());
+        }
+        let version = r.read_u32::<LittleEndian>()?;
+        if version != DUMP_VERSION {
+          return Err(invalid_data(format!("unsupported dump version {}", version)).into());
+        }
+
+        while !r.fill_buf()?.is_empty() {
+          let kv = bincode::deserialize_from(&mut r).map_err(into_io_error)?;
+          importer.push(kv)?;
+        }
+      },
+    }
+
+    Ok(importer.finish()?)
+  }
+}
+
+impl Snapshot {
+  /// See `ActionKV::export()`. Writes to the store carry on while the
+  /// export runs, without showing up in it.
+  pub fn export<W: Write>(&self, w: W, format: ExportFormat) -> Result<u64> {
+    Ok(export_pairs(self.iter(), w, format)?)
+  }
+}
+
+fn export_pairs<W: Write>(pairs: Iter<'_>, w: W, format: ExportFormat) -> io::Result<u64> {
+  let mut count = 0;
+  let mut w = io::BufWriter::new(w);
+
+  match format {
+    ExportFormat::JsonLines => {
+      for kv in pairs {
+        serde_json::to_writer(&mut w, &JsonPair::new(kv?))?;
+        w.write_all(b"\n")?;
+        count += 1;
+      }
+    },
+    ExportFormat::Csv => {
+      let mut csv = csv::Writer::from_writer(&mut w);
+      csv.write_record(["key", "value"])?;
+      for kv in pairs {
+        let kv = kv?;
+        csv.write_record([&kv.key, &kv.value])?;
+        count += 1;
+      }
+      csv.flush()?;
+    },
+    ExportFormat::Bincode => {
+      w.write_all(DUMP_MAGIC)?;
+      w.write_u32::<LittleEndian>(DUMP_VERSION)?;
+      for kv in pairs {
+        bincode::serialize_into(&mut w, &kv?).map_err(into_io_error)?;
+        count += 1;
+      }
+    },
+  }
+
+  w.flush()?;
+  Ok(count)
+}
+
+/// Collects imported pairs into batches.
+struct Importer<'a> {
+  store: &'a mut ActionKV,
+  batch: WriteBatch,
+  count: u64,
+}
+
+impl Importer<'_> {
+  fn push(&mut self, kv: KeyValuePair) -> io::Result<()> {
+    self.batch.insert(&kv.key, &kv.value);
+    if self.batch.len() >= IMPORT_BATCH {
+      self.flush()?;
+    }
+    Ok(())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.store.write_batch(&self.batch)?;
+    self.count += self.batch.len() as u64;
+    self.batch.clear();
+    Ok(())
+  }
+
+  fn finish(mut self) -> io::Result<u64> {
+    self.flush()?;
+    Ok(self.count)
+  }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn into_io_error(err: bincode::Error) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+fn csv_error(err: csv::Error) -> io::Error {
+  match err.is_io_error() {
+    true => err.into(),
+    false => invalid_data(err.to_string()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{MemoryStorage, OpenOptions};
+
+  /// Pairs that need every path through the formats: plain text, bytes
+  /// that are not UTF-8, an empty value, and CSV fields that have to be
+  /// quoted.
+  fn pairs() -> Vec<(ByteString, ByteString)> {
+    vec![
+      (b"".to_vec(), b"empty key".to_vec()),
+      (b"binary\xff\x00".to_vec(), vec![0, 159, 146, 150, 255]),
+      (b"comma,key".to_vec(), b"line one\nline two\r\n".to_vec()),
+      (b"plain".to_vec(), b"".to_vec()),
+      (b"quoted \"key\"".to_vec(), b"say \"hi\", then leave".to_vec()),
+      ("unicode \u{1F600}".as_bytes().to_vec(), "caf\u{e9}".as_bytes().to_vec()),
+    ]
+  }
+
+  fn store() -> ActionKV {
+    let mut store = OpenOptions::new().ordered(true).open_storage(MemoryStorage::new()).unwrap();
+    store.load().unwrap();
+    store
+  }
+
+  fn contents(store: &ActionKV) -> Vec<(ByteString, ByteString)> {
+    store.iter().map(|kv| kv.map(|kv| (kv.key, kv.value))).collect::<Result<_>>().unwrap()
+  }
+
+  /// Exports a store holding `pairs()` in `format`, checks that importing
+  /// it gives the same pairs back, and returns the export.
+  fn round_trip(format: ExportFormat) -> Vec<u8> {
+    let mut source = store();
+    for (key, value) in pairs() {
+      source.insert(&key, &value).unwrap();
+    }
+    source.insert(b"deleted", b"gone").unwrap();
+    source.delete(b"deleted").unwrap();
+
+    let mut exported = Vec::new();
+    assert_eq!(source.export(&mut exported, format).unwrap(), pairs().len() as u64);
+
+    let mut target = store();
+    assert_eq!(target.import(&exported[..], format).unwrap(), pairs().len() as u64);
+    assert_eq!(contents(&target), pairs());
+    exported
+  }
+
+  #[test]
+  fn json_lines_round_trip() {
+    let exported = String::from_utf8(round_trip(ExportFormat::JsonLines)).unwrap();
+    let lines: Vec<&str> = exported.lines().collect();
+    assert_eq!(lines.len(), pairs().len());
+
+    // Text stays readable, and only bytes that are not UTF-8 are encoded
+    assert!(lines[1].contains("\"key_base64\":") && lines[1].contains("\"value_base64\":"));
+    assert!(lines[5].contains("\"key\":\"unicode \u{1F600}\""));
+  }
+
+  #[test]
+  fn csv_round_trip() {
+    let exported = round_trip(ExportFormat::Csv);
+    assert!(exported.starts_with(b"key,value\n"));
+    assert!(exported.windows(b"\"quoted \"\"key\"\"\"".len()).any(|w| w == b"\"quoted \"\"key\"\"\""));
+  }
+
+  #[test]
+  fn bincode_round_trip() {
+    let exported = round_trip(ExportFormat::Bincode);
+    assert!(exported.starts_with(DUMP_MAGIC));
+  }
+
+  #[test]
+  fn import_overwrites_only_the_keys_it_holds() {
+    let mut exported = Vec::new();
+    let mut source = store();
+    source.insert(b"a", b"new").unwrap();
+    source.export(&mut exported, ExportFormat::JsonLines).unwrap();
+
+    let mut target = store();
+    target.insert(b"a", b"old").unwrap();
+    target.insert(b"b", b"kept").unwrap();
+    target.import(&exported[..], ExportFormat::JsonLines).unwrap();
+    assert_eq!(contents(&target), vec![(b"a".to_vec(), b"new".to_vec()), (b"b".to_vec(), b"kept".to_vec())]);
+  }
+
+  #[test]
+  fn malformed_imports_are_refused() {
+    let mut target = store();
+    let both = br#"{"key":"a","key_base64":"YQ==","value":"1"}"#;
+    assert!(target.import(&both[..], ExportFormat::JsonLines).is_err());
+    assert!(target.import(&b"key,value\na,1,extra\n"[..], ExportFormat::Csv).is_err());
+    assert!(target.import(&b"not a dump"[..], ExportFormat::Bincode).is_err());
+    assert!(contents(&target).is_empty());
+  }
+}
@@ -0,0 +1,258 @@
+//! Syncing in the background, for `SyncPolicy::Interval`.
+//!
+//! A store with that policy starts a thread that wakes once per interval
+//! and syncs the active segment if anything has been written to it since
+//! it last did, so writes are synced within about one interval even if
+//! the store then goes quiet. The thread syncs through its own handle to
+//! the segment, from `Storage::try_clone()`, and the store hands it a new
+//! one whenever the active segment changes.
+//!
+//! A sync that fails is kept, and returned by the store's next write,
+//! `flush()` or `sync()`. The writes it should have covered are synced
+//! again on the first round after that.
+
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::Storage;
+
+#[derive(Debug)]
+pub(crate) struct Flusher {
+  shared: Arc<Shared>,
+  worker: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+  state: Mutex<State>,
+  wake: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct State {
+  /// Whether anything has been written since the last sync.
+  dirty: bool,
+
+  /// The handle to sync from now on, once the active segment changes.
+  next: Option<Box<dyn Storage>>,
+  failed: Option<io::Error>,
+  stopped: bool,
+}
+
+impl Shared {
+  fn state(&self) -> MutexGuard<'_, State> {
+    self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+}
+
+impl Flusher {
+  /// Starts syncing `f` every `interval` while there are writes to sync.
+  pub fn start(f: Box<dyn Storage>, interval: Duration) -> io::Result<Self> {
+    let shared = Arc::new(Shared::default());
+    let worker = {
+      let shared = Arc::clone(&shared);
+      thread::Builder::new()
+        .name("actionkv-flusher".to_string())
+        .spawn(move || run(&shared, f, interval))?
+    };
+    Ok(Flusher { shared, worker: Some(worker) })
+  }
+
+  /// Notes a write to be synced, returning the error from a background
+  /// sync that failed since the last call, if any.
+  pub fn written(&self) -> io::Result<()> {
+    let mut state = self.shared.state();
+    state.dirty = true;
+    match state.failed.take() {
+      Some(err) => Err(err),
+      None => Ok(()),
+    }
+  }
+
+  /// Notes that the store has synced everything itself, returning the
+  /// error from a background sync that failed before it did, if any.
+  pub fn synced(&self) -> io::Result<()> {
+    let mut state = self.shared.state();
+    state.dirty = false;
+    match state.failed.take() {
+      Some(err) => Err(err),
+      None => Ok(()),
+    }
+  }
+
+  /// Whether there are writes that the thread has yet to sync.
+  pub fn is_dirty(&self) -> bool {
+    self.shared.state().dirty
+  }
+
+  /// Syncs `f` from now on, for a new active segment.
+  pub fn retarget(&self, f: Box<dyn Storage>) {
+    self.shared.state().next = Some(f);
+  }
+}
+
+impl Drop for Flusher {
+  fn drop(&mut self) {
+    self.shared.state().stopped = true;
+    self.shared.wake.notify_one();
+    if let Some(worker) = self.worker.take() {
+      let _ = worker.join();
+    }
+  }
+}
+
+fn run(shared: &Shared, mut f: Box<dyn Storage>, interval: Duration) {
+  let mut state = shared.state();
+  loop {
+    state = match shared.wake.wait_timeout(state, interval) {
+      Ok((state, _)) => state,
+      Err(poisoned) => poisoned.into_inner().0,
+    };
+    if state.stopped {
+      return;
+    }
+    if let Some(next) = state.next.take() {
+      f = next;
+    }
+    if !state.dirty || state.failed.is_some() {
+      continue;
+    }
+
+    // Writes carry on while the sync runs, and mark the store dirty again
+    state.dirty = false;
+    drop(state);
+    let result = f.sync();
+    state = shared.state();
+    if let Err(err) = result {
+      state.dirty = true;
+      state.failed = Some(err);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Instant;
+
+  use crate::{ActionKV, ActionKvError, Faults, FaultyStorage, MemoryStorage, OpenOptions, SyncPolicy};
+
+  use super::*;
+
+  fn open(policy: SyncPolicy) -> (ActionKV, Faults) {
+    let storage = FaultyStorage::new(MemoryStorage::new());
+    let faults = storage.faults();
+    let mut store = OpenOptions::new().sync(policy).open_storage(storage).unwrap();
+    store.load().unwrap();
+    (store, faults)
+  }
+
+  /// Writes `n` keys and returns how many syncs they caused.
+  fn syncs_for_writes(store: &mut ActionKV, faults: &Faults, n: u32) -> u64 {
+    let before = faults.syncs();
+    for i in 0..n {
+      store.insert(format!("k{}", i).as_bytes(), b"v").unwrap();
+    }
+    faults.syncs() - before
+  }
+
+  /// Waits up to a second for a background sync.
+  fn wait_for_sync(faults: &Faults, before: u64) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while Instant::now() < deadline {
+      if faults.syncs() > before {
+        return true;
+      }
+      thread::sleep(Duration::from_millis(5));
+    }
+    false
+  }
+
+  #[test]
+  fn always_syncs_every_write() {
+    let (mut store, faults) = open(SyncPolicy::Always);
+    assert_eq!(syncs_for_writes(&mut store, &faults, 4), 4);
+  }
+
+  #[test]
+  fn every_writes_syncs_every_nth_write() {
+    let (mut store, faults) = open(SyncPolicy::EveryWrites(3));
+    assert_eq!(syncs_for_writes(&mut store, &faults, 7), 2);
+
+    // The seventh write is left for flush(), which then has nothing to do
+    let before = faults.syncs();
+    store.flush().unwrap();
+    assert_eq!(faults.syncs(), before + 1);
+    store.flush().unwrap();
+    assert_eq!(faults.syncs(), before + 1);
+  }
+
+  #[test]
+  fn never_leaves_syncing_to_the_caller() {
+    let (mut store, faults) = open(SyncPolicy::Never);
+    let before = faults.syncs();
+    assert_eq!(syncs_for_writes(&mut store, &faults, 5), 0);
+    store.flush().unwrap();
+    assert_eq!(faults.syncs(), before + 1);
+  }
+
+  #[test]
+  fn interval_syncs_a_quiet_store() {
+    let (mut store, faults) = open(SyncPolicy::Interval(Duration::from_millis(20)));
+    let before = faults.syncs();
+    store.insert(b"a", b"1").unwrap();
+
+    // Nothing is written after this, but the write is still synced
+    assert!(wait_for_sync(&faults, before));
+    assert!(!store.flusher.as_ref().unwrap().is_dirty());
+  }
+
+  #[test]
+  fn interval_follows_the_active_segment() {
+    let dir = std::env::temp_dir().join(format!("actionkv-flusher-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut store = OpenOptions::new()
+      .sync(SyncPolicy::Interval(Duration::from_millis(20)))
+      .segment_size(64)
+      .open(&dir)
+      .unwrap();
+    store.load().unwrap();
+    for i in 0..8 {
+      store.insert(format!("k{}", i).as_bytes(), b"some value").unwrap();
+    }
+    assert!(store.segments.len() > 1);
+
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while store.flusher.as_ref().unwrap().is_dirty() && Instant::now() < deadline {
+      thread::sleep(Duration::from_millis(5));
+    }
+    assert!(!store.flusher.as_ref().unwrap().is_dirty());
+    drop(store);
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn failed_background_sync_is_reported() {
+    let (mut store, faults) = open(SyncPolicy::Interval(Duration::from_millis(10)));
+    faults.fail_sync();
+    store.insert(b"a", b"1").unwrap();
+
+    // Give the thread time to try, and fail
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while store.flusher.as_ref().unwrap().shared.state().failed.is_none() && Instant::now() < deadline {
+      thread::sleep(Duration::from_millis(5));
+    }
+    faults.clear();
+    match store.insert(b"b", b"2") {
+      Err(ActionKvError::Io(_)) => {},
+      other => panic!("expected the failed sync, got {:?}", other),
+    }
+
+    // The writes are still dirty, and are synced now that syncing works
+    let before = faults.syncs();
+    store.flush().unwrap();
+    assert!(faults.syncs() > before);
+    assert!(!store.flusher.as_ref().unwrap().is_dirty());
+  }
+}
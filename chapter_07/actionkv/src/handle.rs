@@ -0,0 +1,284 @@
+//! Sharing a store between threads.
+
+use std::ops::RangeBounds;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+
+use crate::{ActionKV, BloomStats, ByteStr, ByteString, KeyValuePair, Position, Result, Snapshot, Stats, Version, WriteBatch};
+
+/// A cloneable handle to a store, for sharing it between threads.
+///
+/// Reads take a shared lock and read with positional I/O rather than
+/// seeking, so any number of them run in parallel. Writes take the lock
+/// exclusively, one at a time, to append their records and update the
+/// index. The sync that the store's `SyncPolicy` calls for comes after
+/// the lock is released, through a second handle to the segment, so
+/// reads never wait on the disk for it. A write still returns only once
+/// it is synced, but a read may see it a little before then.
+///
+/// Sealing a segment, and with the LSM engine a flush or merge, still
+/// happen under the lock. Writes through `write()` sync under the lock,
+/// as the store does on its own.
+#[derive(Debug, Clone)]
+pub struct Handle {
+  store: Arc<RwLock<ActionKV>>,
+}
+
+impl ActionKV {
+  /// Shorthand for `Handle::new()`.
+  pub fn into_handle(self) -> Handle {
+    Handle::new(self)
+  }
+}
+
+impl Handle {
+  /// `store` should already be loaded.
+  pub fn new(store: ActionKV) -> Self {
+    Handle { store: Arc::new(RwLock::new(store)) }
+  }
+
+  /// Locks the store for reading. Other readers may hold it at the same
+  /// time, but writers wait until the guard is dropped.
+  pub fn read(&self) -> RwLockReadGuard<'_, ActionKV> {
+    // A panic part way through a write cannot leave the index pointing
+    // at a record that was never written, so a poisoned lock is safe to
+    // keep using
+    self.store.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+
+  /// Locks the store for writing.
+  pub fn write(&self) -> RwLockWriteGuard<'_, ActionKV> {
+    self.store.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+
+  /// Applies `write` under the lock, then makes any sync that it called
+  /// for once the lock is released.
+  fn write_with<T>(&self, write: impl FnOnce(&mut ActionKV) -> Result<T>) -> Result<T> {
+    let (result, due) = {
+      let mut store = self.write();
+      store.defer_syncs = true;
+      let result = write(&mut store);
+      store.defer_syncs = false;
+      (result, store.take_due_sync(false))
+    };
+    if let Some(mut f) = due? {
+      f.sync()?;
+    }
+    result
+  }
+
+  /// Returns the store if this is its last handle, or the handle back if
+  /// it is not.
+  pub fn into_inner(self) -> Result<ActionKV, Handle> {
+    match Arc::try_unwrap(self.store) {
+      Ok(store) => Ok(store.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner())),
+      Err(store) => Err(Handle { store }),
+    }
+  }
+
+  pub fn get(&self, key: &ByteStr) -> Result<Option<ByteString>> {
+    self.read().get(key)
+  }
+
+  pub fn get_at(&self, position: Position) -> Result<KeyValuePair> {
+    self.read().get_at(position)
+  }
+
+  pub fn find(&self, target: &ByteStr) -> Result<Option<(Position, ByteString)>> {
+    self.read().find(target)
+  }
+
+  /// See `ActionKV::get_as_of()`.
+  pub fn get_as_of(&self, key: &ByteStr, as_of: Position) -> Result<Vec<Version>> {
+    self.read().get_as_of(key, as_of)
+  }
+
+  /// See `ActionKV::snapshot()`. The lock is only held while the snapshot
+  /// is taken, so writes carry on while it is read.
+  pub fn snapshot(&self) -> Result<Snapshot> {
+    self.read().snapshot()
+  }
+
+  /// Live pairs whose keys fall within `range`, in key order. The pairs
+  /// are read while the lock is held, so they all come from the same
+  /// moment.
+  pub fn scan<R: RangeBounds<ByteStr>>(&self, range: R) -> Result<Vec<KeyValuePair>> {
+    self.read().scan(range).collect()
+  }
+
+  /// Live pairs whose keys start with `prefix`, in key order.
+  pub fn prefix(&self, prefix: &ByteStr) -> Result<Vec<KeyValuePair>> {
+    self.read().prefix(prefix).collect()
+  }
+
+  pub fn insert(&self, key: &ByteStr, value: &ByteStr) -> Result<()> {
+    self.write_with(|store| store.insert(key, value))
+  }
+
+  pub fn insert_with_ttl(&self, key: &ByteStr, value: &ByteStr, ttl: Duration) -> Result<()> {
+    self.write_with(|store| store.insert_with_ttl(key, value, ttl))
+  }
+
+  pub fn update(&self, key: &ByteStr, value: &ByteStr) -> Result<()> {
+    self.write_with(|store| store.update(key, value))
+  }
+
+  pub fn delete(&self, key: &ByteStr) -> Result<()> {
+    self.write_with(|store| store.delete(key))
+  }
+
+  pub fn get_with_version(&self, key: &ByteStr) -> Result<Option<(ByteString, u64)>> {
+    self.read().get_with_version(key)
+  }
+
+  /// See `ActionKV::compare_and_swap()`. The version is checked and the
+  /// value written under the same lock, so no other write comes between
+  /// them.
+  pub fn compare_and_swap(&self, key: &ByteStr, expected_version: u64, value: &ByteStr) -> Result<u64> {
+    self.write_with(|store| store.compare_and_swap(key, expected_version, value))
+  }
+
+  pub fn insert_if_absent(&self, key: &ByteStr, value: &ByteStr) -> Result<u64> {
+    self.write_with(|store| store.insert_if_absent(key, value))
+  }
+
+  pub fn delete_if_version(&self, key: &ByteStr, expected_version: u64) -> Result<()> {
+    self.write_with(|store| store.delete_if_version(key, expected_version))
+  }
+
+  pub fn write_batch(&self, batch: &WriteBatch) -> Result<()> {
+    self.write_with(|store| store.write_batch(batch))
+  }
+
+  /// See `ActionKV::sync()`. The lock is only held while a second handle
+  /// to the active segment is taken, not while it is synced.
+  pub fn sync(&self) -> Result<()> {
+    let f = self.write().take_due_sync(true)?;
+    if let Some(mut f) = f {
+      f.sync()?;
+    }
+    Ok(())
+  }
+
+  pub fn stats(&self) -> Result<Stats> {
+    self.read().stats()
+  }
+
+  pub fn bloom_stats(&self) -> BloomStats {
+    self.read().bloom_stats()
+  }
+
+  /// See `ActionKV::compact()`. A segmented store is better served by
+  /// `start_compaction()` through `write()`, which holds the lock only
+  /// long enough to seal the active segment.
+  pub fn compact(&self) -> Result<()> {
+    self.write().compact()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io;
+  use std::sync::mpsc;
+  use std::sync::{Condvar, Mutex};
+  use std::thread;
+
+  use super::*;
+  use crate::{MemoryStorage, OpenOptions, Storage, SyncPolicy};
+
+  /// Whether syncs are held up, and whether one is waiting.
+  #[derive(Debug, Default)]
+  struct Gate {
+    state: Mutex<(bool, bool)>,
+    changed: Condvar,
+  }
+
+  /// Storage whose syncs wait while its gate is closed, as if the disk
+  /// were slow.
+  #[derive(Debug)]
+  struct GatedStorage {
+    inner: Box<dyn Storage>,
+    gate: Arc<Gate>,
+  }
+
+  impl Gate {
+    fn set_closed(&self, closed: bool) {
+      self.state.lock().unwrap().0 = closed;
+      self.changed.notify_all();
+    }
+
+    fn wait_for_sync(&self) {
+      let mut state = self.state.lock().unwrap();
+      while !state.1 {
+        state = self.changed.wait(state).unwrap();
+      }
+    }
+  }
+
+  impl Storage for GatedStorage {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+      self.inner.read_at(buf, offset)
+    }
+
+    fn append(&mut self, buf: &[u8]) -> io::Result<u64> {
+      self.inner.append(buf)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+      let mut state = self.gate.state.lock().unwrap();
+      state.1 = true;
+      self.gate.changed.notify_all();
+      while state.0 {
+        state = self.gate.changed.wait(state).unwrap();
+      }
+      state.1 = false;
+      drop(state);
+      self.inner.sync()
+    }
+
+    fn len(&self) -> io::Result<u64> {
+      self.inner.len()
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+      self.inner.truncate(len)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Storage>> {
+      Ok(Box::new(GatedStorage { inner: self.inner.try_clone()?, gate: Arc::clone(&self.gate) }))
+    }
+  }
+
+  #[test]
+  fn reads_go_on_while_a_write_syncs() {
+    let gate = Arc::new(Gate::default());
+    let storage = GatedStorage { inner: Box::new(MemoryStorage::new()), gate: Arc::clone(&gate) };
+    let mut store = OpenOptions::new().sync(SyncPolicy::Always).open_storage(storage).unwrap();
+    store.load().unwrap();
+    let handle = store.into_handle();
+    handle.insert(b"a", b"1").unwrap();
+
+    gate.set_closed(true);
+    let writer = {
+      let handle = handle.clone();
+      thread::spawn(move || handle.insert(b"b", b"2"))
+    };
+    gate.wait_for_sync();
+
+    // The write is stuck in its sync, but reads are not held up by it
+    let (sent, received) = mpsc::channel();
+    let reader = {
+      let handle = handle.clone();
+      thread::spawn(move || sent.send((handle.get(b"a"), handle.prefix(b""))).unwrap())
+    };
+    let (value, pairs) = received.recv_timeout(Duration::from_secs(5)).expect("a read waited for the sync");
+    assert_eq!(value.unwrap(), Some(b"1".to_vec()));
+    assert!(!pairs.unwrap().is_empty());
+    assert!(!writer.is_finished());
+
+    gate.set_closed(false);
+    writer.join().unwrap().unwrap();
+    reader.join().unwrap();
+    assert_eq!(handle.get(b"b").unwrap(), Some(b"2".to_vec()));
+  }
+}
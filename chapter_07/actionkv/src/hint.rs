@@ -0,0 +1,229 @@
+//! Hint files, which let `load()` skip most of the log.
+//!
+//! Like Bitcask's hint files, a hint is a snapshot of `index` stored next
+//! to the log (`capitals.hint` for `capitals`, or `hint` inside a
+//! segmented store's directory). It is written by `compact()` and
+//! `close()`, and records how long each segment was at the time. On
+//! `load()`, a hint that still matches the segments is read back and only
+//! the records appended since then are scanned.
+//!
+//! ```text
+//! +------------------+---------+-------+------------+-------------+----------+----------+----------+-------+
+//! | "AKVHINT\0"      | version | count | segments   | tail_digest | last_seq | body_len | checksum | body  |
+//! +------------------+---------+-------+------------+-------------+----------+----------+----------+-------+
+//!       8 bytes         u32       u32    count x       u32           u64        u64        u32
+//!                                        (u32, u64)
+//! ```
+//!
+//! `segments` holds the id and length of every segment, oldest first.
+//! `body` is the bincode encoding of `index` as a sequence of `(key,
+//! entry)` pairs, and `checksum` is its CRC32. `tail_digest` is a CRC32 of
+//! the last bytes of the newest segment, so a hint left behind by a file
+//! that has since been replaced is noticed and ignored. `last_seq` is the
+//! `seq` of the last insert or delete in the segments it covers. In an
+//! encrypted store, `body` is sealed with the store's key and `checksum`
+//! covers the sealed bytes.
+//!
+//! A hint is only ever a cache: if it is missing or does not match,
+//! `load()` falls back to scanning the whole log.
+
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+
+use crate::segment;
+use crate::{ActionKV, ByteString, Entry, Position, Result, SyncPolicy};
+
+const HINT_MAGIC: &[u8; 8] = b"AKVHINT\0";
+const HINT_VERSION: u32 = 4;
+
+pub(crate) struct Hint {
+  pub entries: Vec<(ByteString, Entry)>,
+
+  /// Where the log ended when the hint was written.
+  pub end: Position,
+  pub last_seq: u64,
+
+  segments: Vec<(u32, u64)>,
+  tail_digest: u32,
+}
+
+impl ActionKV {
+  /// Waits for any background compaction, syncs outstanding writes,
+  /// writes a hint file for the next `load()`, saves any bloom filters
+  /// that have changed, and closes the store.
+  /// Writes are left to the OS if the sync policy is `SyncPolicy::Never`.
+  ///
+  /// The hint is only written when `index` covers the whole log, that
+  /// is, after `load()`, `compact()` or `repair()`. A read-only store is
+  /// just closed.
+  pub fn close(mut self) -> Result<()> {
+    if self.read_only {
+      return Ok(());
+    }
+    self.finish_compaction(true)?;
+    if self.sync != SyncPolicy::Never && self.unsynced_writes > 0 {
+      self.sync()?;
+    }
+    if self.loaded {
+      self.write_hint()?;
+    }
+    Ok(self.write_blooms()?)
+  }
+
+  pub(crate) fn write_hint(&mut self) -> io::Result<()> {
+    // The LSM engine rebuilds its memtable from the write-ahead log
+    if !self.has_path() || self.lsm.is_some() {
+      return Ok(());
+    }
+
+    let mut segments = Vec::with_capacity(self.segments.len());
+    for segment in &self.segments {
+      segments.push((segment.id, segment.f.len()?));
+    }
+    let (_, end) = segments[segments.len() - 1];
+    let tail_digest = self.active().tail_digest(end)?;
+    let last_seq = self.last_seq()?;
+
+    let entries: Vec<_> = self.index.iter().collect();
+    let mut body = bincode::serialize(&entries)
+      .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    if let Some(cipher) = &self.cipher {
+      body = cipher.seal_bytes(HINT_MAGIC, body)?;
+    }
+
+    let path = self.store_path("hint");
+    let tmp_path = self.store_path("hint.tmp");
+    {
+      let mut f = BufWriter::new(File::create(&tmp_path)?);
+      f.write_all(HINT_MAGIC)?;
+      f.write_u32::<LittleEndian>(HINT_VERSION)?;
+      f.write_u32::<LittleEndian>(segments.len() as u32)?;
+      for (id, len) in segments {
+        f.write_u32::<LittleEndian>(id)?;
+        f.write_u64::<LittleEndian>(len)?;
+      }
+      f.write_u32::<LittleEndian>(tail_digest)?;
+      f.write_u64::<LittleEndian>(last_seq)?;
+      f.write_u64::<LittleEndian>(body.len() as u64)?;
+      f.write_u32::<LittleEndian>(crc32::checksum_ieee(&body))?;
+      f.write_all(&body)?;
+      f.flush()?;
+
+      // Otherwise a crash soon after the rename can leave the hint empty
+      f.get_ref().sync_all()?;
+    }
+    fs::rename(&tmp_path, &path)
+  }
+
+  pub(crate) fn remove_hint(&self) -> io::Result<()> {
+    if !self.has_path() {
+      return Ok(());
+    }
+    let path = self.store_path("hint");
+    match fs::remove_file(path) {
+      Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+      _ => Ok(()),
+    }
+  }
+
+  /// Reads the hint file. Returns `None` if there is no hint or it does
+  /// not match the segments.
+  pub(crate) fn read_hint(&mut self) -> io::Result<Option<Hint>> {
+    if !self.has_path() {
+      return Ok(None);
+    }
+    let path = self.store_path("hint");
+    let f = match File::open(&path) {
+      Ok(f) => f,
+      Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(err) => return Err(err),
+    };
+
+    let hint = match self.process_hint(&mut BufReader::new(f)) {
+      Ok(Some(hint)) => hint,
+      Ok(None) => return Ok(None),
+      Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+      Err(err) => return Err(err),
+    };
+
+    // Every segment up to the newest one in the hint must still be there
+    // and unchanged. The newest may have grown since.
+    let current: Vec<(u32, u64)> = self
+      .segments
+      .iter()
+      .filter(|segment| segment.id <= hint.end.segment)
+      .map(|segment| (segment.id, segment.len))
+      .collect();
+    if current.len() != hint.segments.len() {
+      return Ok(None);
+    }
+    for (&(id, len), &(hint_id, hint_len)) in current.iter().zip(&hint.segments) {
+      let unchanged = match id == hint.end.segment {
+        true => len >= hint_len,
+        false => len == hint_len,
+      };
+      if id != hint_id || !unchanged {
+        return Ok(None);
+      }
+    }
+
+    let newest = segment::lookup(&self.segments, hint.end.segment)?;
+    if hint.end.offset < newest.data_start() {
+      return Ok(None);
+    }
+    if newest.tail_digest(hint.end.offset)? != hint.tail_digest {
+      return Ok(None);
+    }
+
+    Ok(Some(hint))
+  }
+
+  fn process_hint<R: Read>(&self, f: &mut R) -> io::Result<Option<Hint>> {
+    let mut magic = [0u8; 8];
+    f.read_exact(&mut magic)?;
+    if &magic != HINT_MAGIC || f.read_u32::<LittleEndian>()? != HINT_VERSION {
+      return Ok(None);
+    }
+
+    let count = f.read_u32::<LittleEndian>()?;
+    let mut segments = Vec::new();
+    for _ in 0..count {
+      let id = f.read_u32::<LittleEndian>()?;
+      let len = f.read_u64::<LittleEndian>()?;
+      segments.push((id, len));
+    }
+    let end = match segments.last() {
+      Some(&(segment, offset)) => Position { segment, offset },
+      None => return Ok(None),
+    };
+
+    let tail_digest = f.read_u32::<LittleEndian>()?;
+    let last_seq = f.read_u64::<LittleEndian>()?;
+    let body_len = f.read_u64::<LittleEndian>()?;
+    let saved_checksum = f.read_u32::<LittleEndian>()?;
+
+    let mut body = ByteString::new();
+    f.take(body_len).read_to_end(&mut body)?;
+    if body.len() as u64 != body_len || crc32::checksum_ieee(&body) != saved_checksum {
+      return Ok(None);
+    }
+    if let Some(cipher) = &self.cipher {
+      body = match cipher.open_bytes(HINT_MAGIC, body) {
+        Some(body) => body,
+        None => return Ok(None),
+      };
+    }
+
+    let entries = match bincode::deserialize(&body) {
+      Ok(entries) => entries,
+      Err(_) => return Ok(None),
+    };
+
+    Ok(Some(Hint { entries, end, last_seq, segments, tail_digest }))
+  }
+}
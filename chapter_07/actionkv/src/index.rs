@@ -0,0 +1,194 @@
+//! The in-memory map from each live key to the position of its latest
+//! record.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ops::{Bound, RangeBounds};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::record;
+use crate::{ByteStr, ByteString, Position};
+
+/// What the index knows about a live key, much like an entry in Bitcask's
+/// keydir.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+  pub position: Position,
+
+  /// When the key expires, in milliseconds since the Unix epoch.
+  pub expires_at: Option<u64>,
+}
+
+impl Entry {
+  pub fn is_expired(&self, now: u64) -> bool {
+    record::is_expired(self.expires_at, now)
+  }
+}
+
+impl From<Position> for Entry {
+  fn from(position: Position) -> Self {
+    Entry { position, expires_at: None }
+  }
+}
+
+/// `Hashed` is the cheaper of the two to maintain. `Ordered` keeps keys
+/// sorted, so range and prefix scans do not need to sort the whole
+/// keyspace first. Choose with `OpenOptions::ordered()`.
+#[derive(Debug, Clone)]
+pub enum Index {
+  Hashed(HashMap<ByteString, Entry>),
+  Ordered(BTreeMap<ByteString, Entry>),
+}
+
+pub(crate) type Entries<'a> = Box<dyn Iterator<Item = (&'a ByteString, &'a Entry)> + 'a>;
+
+impl Default for Index {
+  fn default() -> Self {
+    Index::Hashed(HashMap::new())
+  }
+}
+
+impl Index {
+  pub fn new(ordered: bool) -> Self {
+    match ordered {
+      true => Index::Ordered(BTreeMap::new()),
+      false => Index::Hashed(HashMap::new()),
+    }
+  }
+
+  /// An empty index of the same kind as this one.
+  pub fn empty_like(&self) -> Self {
+    Index::new(self.is_ordered())
+  }
+
+  pub fn is_ordered(&self) -> bool {
+    matches!(self, Index::Ordered(_))
+  }
+
+  pub fn get(&self, key: &ByteStr) -> Option<&Entry> {
+    match self {
+      Index::Hashed(map) => map.get(key),
+      Index::Ordered(map) => map.get(key),
+    }
+  }
+
+  pub fn contains_key(&self, key: &ByteStr) -> bool {
+    self.get(key).is_some()
+  }
+
+  pub fn insert(&mut self, key: ByteString, entry: Entry) -> Option<Entry> {
+    match self {
+      Index::Hashed(map) => map.insert(key, entry),
+      Index::Ordered(map) => map.insert(key, entry),
+    }
+  }
+
+  pub fn remove(&mut self, key: &ByteStr) -> Option<Entry> {
+    match self {
+      Index::Hashed(map) => map.remove(key),
+      Index::Ordered(map) => map.remove(key),
+    }
+  }
+
+  /// Keeps only the entries for which `keep` returns `true`.
+  pub fn retain<F>(&mut self, mut keep: F)
+  where
+    F: FnMut(&ByteString, &Entry) -> bool,
+  {
+    match self {
+      Index::Hashed(map) => map.retain(|key, entry| keep(key, entry)),
+      Index::Ordered(map) => map.retain(|key, entry| keep(key, entry)),
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    match self {
+      Index::Hashed(map) => map.len(),
+      Index::Ordered(map) => map.len(),
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  pub fn clear(&mut self) {
+    match self {
+      Index::Hashed(map) => map.clear(),
+      Index::Ordered(map) => map.clear(),
+    }
+  }
+
+  pub fn keys(&self) -> impl Iterator<Item = &ByteString> {
+    self.iter().map(|(key, _)| key)
+  }
+
+  /// Every entry, in key order for `Ordered` and in no particular order
+  /// for `Hashed`.
+  pub fn iter(&self) -> Entries<'_> {
+    match self {
+      Index::Hashed(map) => Box::new(map.iter()),
+      Index::Ordered(map) => Box::new(map.iter()),
+    }
+  }
+
+  /// Entries whose keys fall within `range`, in key order.
+  pub fn range<'a, R>(&'a self, range: R) -> Entries<'a>
+  where
+    R: RangeBounds<ByteStr> + 'a,
+  {
+    if is_empty_range(&range) {
+      return Box::new(std::iter::empty());
+    }
+
+    match self {
+      Index::Ordered(map) => Box::new(map.range::<ByteStr, _>(range)),
+      Index::Hashed(map) => {
+        let mut entries: Vec<_> = map
+          .iter()
+          .filter(|(key, _)| range.contains(key.as_slice()))
+          .collect();
+        entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        Box::new(entries.into_iter())
+      },
+    }
+  }
+
+  /// Entries whose keys start with `prefix`, in key order.
+  pub fn prefix<'a>(&'a self, prefix: &'a ByteStr) -> Entries<'a> {
+    let range = (Bound::Included(prefix), Bound::Unbounded);
+    Box::new(
+      self
+        .range(range)
+        .take_while(move |(key, _)| key.starts_with(prefix)),
+    )
+  }
+}
+
+/// `BTreeMap::range()` panics on ranges like `b..a`, which can come
+/// straight from user input, so they are caught here instead.
+pub(crate) fn is_empty_range<R: RangeBounds<ByteStr>>(range: &R) -> bool {
+  match (range.start_bound(), range.end_bound()) {
+    (Bound::Included(start), Bound::Included(end)) => start > end,
+    (Bound::Included(start), Bound::Excluded(end))
+    | (Bound::Excluded(start), Bound::Included(end))
+    | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+    _ => false,
+  }
+}
+
+impl FromIterator<(ByteString, Entry)> for Index {
+  /// Collects into a `Hashed` index.
+  fn from_iter<I: IntoIterator<Item = (ByteString, Entry)>>(iter: I) -> Self {
+    Index::Hashed(iter.into_iter().collect())
+  }
+}
+
+impl Extend<(ByteString, Entry)> for Index {
+  fn extend<I: IntoIterator<Item = (ByteString, Entry)>>(&mut self, iter: I) {
+    match self {
+      Index::Hashed(map) => map.extend(iter),
+      Index::Ordered(map) => map.extend(iter),
+    }
+  }
+}
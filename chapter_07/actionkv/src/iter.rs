@@ -0,0 +1,174 @@
+//! Iterating over the live pairs in a store.
+
+use std::ops::RangeBounds;
+
+use crate::encryption::{self, Cipher};
+use crate::lsm::Merge;
+use crate::record::{self, RecordKind};
+use crate::segment::{self, Segment};
+use crate::{ActionKV, ByteStr, ByteString, Entry, KeyValuePair, Result};
+
+/// Pairs from `ActionKV::iter()`, `scan()` or `prefix()`. Keys come from
+/// the index up front; each value is only read from disk when the
+/// iterator reaches it. Keys that had expired when the iterator was
+/// created are skipped.
+///
+/// In a store that uses the LSM engine, the memtable and the tables are
+/// instead read side by side as the iterator goes, and merged in key
+/// order.
+pub struct Iter<'a> {
+  source: Source<'a>,
+  cipher: Option<&'a Cipher>,
+  now: u64,
+}
+
+enum Source<'a> {
+  Index {
+    segments: &'a [Segment],
+    entries: Box<dyn Iterator<Item = (&'a ByteString, &'a Entry)> + 'a>,
+  },
+  Merged(Merge<'a>),
+}
+
+impl<'a> Iter<'a> {
+  pub(crate) fn new(
+    segments: &'a [Segment],
+    cipher: Option<&'a Cipher>,
+    entries: Box<dyn Iterator<Item = (&'a ByteString, &'a Entry)> + 'a>,
+    now: u64,
+  ) -> Self {
+    Iter { source: Source::Index { segments, entries }, cipher, now }
+  }
+
+  pub(crate) fn merged(merge: Merge<'a>, cipher: Option<&'a Cipher>, now: u64) -> Self {
+    Iter { source: Source::Merged(merge), cipher, now }
+  }
+}
+
+impl Iterator for Iter<'_> {
+  type Item = Result<KeyValuePair>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let (position, record) = match &mut self.source {
+      Source::Index { segments, entries } => {
+        let position = loop {
+          let (_, entry) = entries.next()?;
+          if !entry.is_expired(self.now) {
+            break entry.position;
+          }
+        };
+        let record = segment::lookup(segments, position.segment)
+          .and_then(|segment| segment.read_record(position.offset));
+        (position, record)
+      },
+      Source::Merged(merge) => loop {
+        match merge.next()? {
+          Ok(found) if found.record.kind == RecordKind::Tombstone || found.record.is_expired(self.now) => {},
+          Ok(found) => break (found.position, Ok(found.record)),
+          Err(err) => return Some(Err(err.into())),
+        }
+      },
+    };
+
+    let pair = record
+      .and_then(|record| encryption::unseal(self.cipher, position, record))
+      .and_then(|record| record.into_pair());
+    Some(pair.map_err(Into::into))
+  }
+}
+
+impl ActionKV {
+  /// Every live pair. Pairs come in key order if the index is ordered,
+  /// as it always is with the LSM engine, and in no particular order
+  /// otherwise.
+  pub fn iter(&self) -> Iter<'_> {
+    if self.lsm.is_some() {
+      return self.lsm_scan(..);
+    }
+    Iter::new(&self.segments, self.cipher.as_ref(), self.index.iter(), record::now())
+  }
+
+  /// Live pairs whose keys fall within `range`, in key order. With a
+  /// hashed index the matching keys are sorted first; see
+  /// `OpenOptions::ordered()`.
+  pub fn scan<'a, R>(&'a self, range: R) -> Iter<'a>
+  where
+    R: RangeBounds<ByteStr> + 'a,
+  {
+    if self.lsm.is_some() {
+      return self.lsm_scan(range);
+    }
+    Iter::new(&self.segments, self.cipher.as_ref(), self.index.range(range), record::now())
+  }
+
+  /// Live pairs whose keys start with `prefix`, in key order.
+  pub fn prefix<'a>(&'a self, prefix: &'a ByteStr) -> Iter<'a> {
+    if self.lsm.is_some() {
+      return self.lsm_prefix(prefix);
+    }
+    Iter::new(&self.segments, self.cipher.as_ref(), self.index.prefix(prefix), record::now())
+  }
+
+  /// Whether `index` keeps its keys sorted.
+  pub fn is_ordered(&self) -> bool {
+    self.index.is_ordered()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::ops::Bound::{self, Excluded, Included, Unbounded};
+
+  use super::Iter;
+  use crate::{ActionKV, ByteStr, ByteString, MemoryStorage, OpenOptions};
+
+  fn store(ordered: bool) -> ActionKV {
+    let mut store = OpenOptions::new().ordered(ordered).open_storage(MemoryStorage::new()).unwrap();
+    store.load().unwrap();
+    for key in [&b"a"[..], b"ab", b"abc", b"b", b"b\xff", b"c"] {
+      store.insert(key, b"v").unwrap();
+    }
+    store.delete(b"abc").unwrap();
+    store
+  }
+
+  fn keys(pairs: Iter) -> Vec<ByteString> {
+    pairs.map(|pair| pair.unwrap().key).collect()
+  }
+
+  fn range<'a>(start: Bound<&'a ByteStr>, end: Bound<&'a ByteStr>) -> (Bound<&'a ByteStr>, Bound<&'a ByteStr>) {
+    (start, end)
+  }
+
+  #[test]
+  fn scan_honours_its_bounds() {
+    for ordered in [false, true] {
+      let store = store(ordered);
+      assert_eq!(keys(store.scan(range(Included(b"ab"), Excluded(b"b")))), [b"ab".to_vec()]);
+      assert_eq!(keys(store.scan(range(Included(b"ab"), Included(b"b")))), [b"ab".to_vec(), b"b".to_vec()]);
+      assert_eq!(
+        keys(store.scan(range(Excluded(b"a"), Excluded(b"c")))),
+        [b"ab".to_vec(), b"b".to_vec(), b"b\xff".to_vec()],
+      );
+      assert_eq!(keys(store.scan(range(Included(b"b\xff"), Unbounded))), [b"b\xff".to_vec(), b"c".to_vec()]);
+      assert_eq!(keys(store.scan(range(Unbounded, Excluded(b"ab")))), [b"a".to_vec()]);
+
+      // Ranges that are empty or run backwards match nothing
+      assert!(keys(store.scan(range(Included(b"b"), Excluded(b"b")))).is_empty());
+      assert!(keys(store.scan(range(Included(b"c"), Included(b"a")))).is_empty());
+    }
+  }
+
+  #[test]
+  fn prefix_matches_only_its_keys() {
+    for ordered in [false, true] {
+      let store = store(ordered);
+      assert_eq!(keys(store.prefix(b"a")), [b"a".to_vec(), b"ab".to_vec()]);
+      assert_eq!(keys(store.prefix(b"b")), [b"b".to_vec(), b"b\xff".to_vec()]);
+      assert_eq!(keys(store.prefix(b"b\xff")), [b"b\xff".to_vec()]);
+      assert!(keys(store.prefix(b"abc")).is_empty());
+      assert!(keys(store.prefix(b"d")).is_empty());
+      assert_eq!(keys(store.prefix(b"")).len(), 5);
+    }
+  }
+}
@@ -1,14 +1,68 @@
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
+use std::fs::File;
 use std::io;
 use std::io::prelude::*;
-use std::io::{BufReader, BufWriter, SeekFrom};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use crc::crc32;
+use crc::crc32::{self, Hasher32};
 use serde_derive::{Deserialize, Serialize};
 
+mod batch;
+mod bloom;
+mod compaction;
+mod compression;
+mod conditional;
+mod encryption;
+mod error;
+mod export;
+mod flusher;
+mod handle;
+mod hint;
+mod index;
+mod iter;
+mod lock;
+mod lsm;
+pub mod net;
+mod options;
+mod record;
+mod recovery;
+pub mod replication;
+mod segment;
+mod snapshot;
+mod stats;
+mod storage;
+mod typed;
+mod watch;
+
+pub use batch::WriteBatch;
+pub use bloom::BloomStats;
+pub use compression::Compression;
+pub use conditional::Conflict;
+pub use encryption::{AuthenticationError, KEY_LEN};
+pub use error::{ActionKvError, Result};
+pub use export::ExportFormat;
+pub use handle::Handle;
+pub use index::{Entry, Index};
+pub use iter::Iter;
+pub use lock::StoreLocked;
+pub use options::{Engine, OpenOptions, SyncPolicy, WriteOptions, DEFAULT_MEMTABLE_SIZE, DEFAULT_SEGMENT_SIZE};
+pub use record::{RecordKind, FORMAT_VERSION, LEGACY_VERSION};
+pub use recovery::{Corruption, RecoveryReport};
+pub use segment::Position;
+pub use snapshot::{Snapshot, Version};
+pub use stats::Stats;
+pub use storage::{FaultyStorage, Faults, FileStorage, MemoryStorage, Storage};
+pub use typed::{Codec, CodecError, TypedIter, TypedStore};
+pub use watch::{Change, Compacted, Watch, DEFAULT_POLL_INTERVAL};
+use bloom::BloomCounters;
+use compaction::Compaction;
+use encryption::Cipher;
+use flusher::Flusher;
+use lsm::Lsm;
+use record::{Record, COMPRESSED, ENCRYPTED, EXPIRES, KNOWN_FLAGS, SEQ};
+use segment::Segment;
+
 type ByteString = Vec<u8>;
 type ByteStr = [u8];
 
@@ -20,140 +74,965 @@ pub struct KeyValuePair {
 
 #[derive(Debug)]
 pub struct ActionKV {
-  f: File,
-  pub index: HashMap<ByteString, u64>,
+  path: PathBuf,
+  segments: Vec<Segment>,
+  segment_size: Option<u64>,
+  compact_after: usize,
+  compaction: Option<Compaction>,
+  compression: Compression,
+  cipher: Option<Cipher>,
+  bloom: Option<f64>,
+  bloom_counters: BloomCounters,
+  read_only: bool,
+
+  /// The tables of a store that uses the LSM engine, whose memtable is
+  /// `index` and whose write-ahead log is the only segment. See the
+  /// `lsm` module.
+  lsm: Option<Lsm>,
+
+  /// The file holding the store's lock, which is released when it is
+  /// dropped. See the `lock` module.
+  _lock: Option<File>,
+  loaded: bool,
+
+  /// The `seq` of the last insert or delete, once it is known. See
+  /// `last_seq()`.
+  seq: Option<u64>,
+  sync: SyncPolicy,
+  unsynced_writes: u32,
+
+  /// Syncs in the background with `SyncPolicy::Interval`. See the
+  /// `flusher` module.
+  flusher: Option<Flusher>,
+
+  /// Set by `Handle` while it writes, so that a sync the policy calls
+  /// for is left to `take_due_sync()` rather than made under its lock.
+  defer_syncs: bool,
+  sync_due: bool,
+  pub index: Index,
 }
 
 impl ActionKV {
-  pub fn open(path: &Path) -> io::Result<Self> {
-    let f = OpenOptions::new()
-      .read(true)
-      .write(true)
-      .create(true)
-      .open(path)?;
-    let index = HashMap::new();
-    Ok(ActionKV { f, index })
+  pub fn open(path: &Path) -> Result<Self> {
+    OpenOptions::new().open(path)
+  }
+
+  /// Opens an existing store for reading only. See
+  /// `OpenOptions::read_only()`.
+  pub fn open_read_only(path: &Path) -> Result<Self> {
+    OpenOptions::new().read_only(true).open(path)
+  }
+
+  fn open_with(path: &Path, options: &OpenOptions) -> io::Result<Self> {
+    let read_only = options.is_read_only();
+    if read_only {
+      // Fails with `NotFound` before a lock file is left behind
+      path.metadata()?;
+    }
+
+    let engine = match options.engine {
+      Some(engine) => engine,
+      None if lsm::is_lsm(path) => Engine::Lsm,
+      None => Engine::Log,
+    };
+    if engine == Engine::Lsm && options.segment_size.is_some() {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "a store using the LSM engine is not segmented"));
+    }
+    if engine == Engine::Log && lsm::is_lsm(path) {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} holds a store that uses the LSM engine", path.display()),
+      ));
+    }
+
+    let segment_size = match options.segment_size {
+      Some(size) => Some(size),
+      None if engine == Engine::Log && path.is_dir() => Some(DEFAULT_SEGMENT_SIZE),
+      None => None,
+    };
+
+    // The lock is taken before the segments are opened, since opening
+    // them for writing may delete stale ones
+    let lock_path = match (segment_size, engine) {
+      (Some(_), _) | (None, Engine::Lsm) => {
+        if !read_only {
+          std::fs::create_dir_all(path)?;
+        }
+        path.join("lock")
+      },
+      (None, Engine::Log) => ActionKV::sibling_path(path, "lock"),
+    };
+    let lock = match options.follow {
+      true => None,
+      false => Some(lock::acquire(&lock_path, read_only)?),
+    };
+
+    if engine == Engine::Lsm {
+      let (wal, lsm) = lsm::open(path, options)?;
+      let mut store = ActionKV::with_segments(path.to_path_buf(), vec![wal], None, Some(lsm), options)?;
+      store._lock = lock;
+
+      // A damaged store still opens, so that it can be checked and repaired
+      match store.load() {
+        Ok(()) | Err(ActionKvError::Corruption(_)) => {},
+        Err(err) => return Err(err.into()),
+      }
+      return Ok(store);
+    }
+
+    let segments = match (segment_size, read_only) {
+      (Some(_), _) => segment::open_dir(path, read_only)?,
+      (None, true) => vec![Segment::open_read_only(0, path)?],
+      (None, false) => vec![Segment::create(0, path)?],
+    };
+    let mut store = ActionKV::with_segments(path.to_path_buf(), segments, segment_size, None, options)?;
+    store._lock = lock;
+    Ok(store)
+  }
+
+  fn open_storage_with(storage: Box<dyn Storage>, options: &OpenOptions) -> io::Result<Self> {
+    if options.segment_size.is_some() {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "a store opened on a Storage cannot be segmented",
+      ));
+    }
+    if options.is_read_only() {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "a store opened on a Storage cannot be read-only",
+      ));
+    }
+    if options.engine == Some(Engine::Lsm) {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "a store opened on a Storage cannot use the LSM engine",
+      ));
+    }
+    let segments = vec![Segment::with_storage(0, storage)?];
+    ActionKV::with_segments(PathBuf::new(), segments, None, None, options)
+  }
+
+  fn with_segments(
+    path: PathBuf,
+    segments: Vec<Segment>,
+    segment_size: Option<u64>,
+    lsm: Option<Lsm>,
+    options: &OpenOptions,
+  ) -> io::Result<Self> {
+    if let Some(rate) = options.bloom_filter {
+      if !(rate > 0.0 && rate < 1.0) {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidInput,
+          format!("bloom filter false positive rate {} is not between 0 and 1", rate),
+        ));
+      }
+    }
+
+    // The memtable is flushed in key order, and tables keep their own
+    // bloom filters rather than the log having one
+    let index = Index::new(options.ordered || lsm.is_some());
+    let bloom = match lsm {
+      Some(_) => None,
+      None => options.bloom_filter,
+    };
+    let mut store = ActionKV {
+      path,
+      segments,
+      segment_size,
+      compact_after: options.compact_after,
+      compaction: None,
+      compression: options.compression,
+      cipher: options.cipher.clone(),
+      bloom,
+      bloom_counters: BloomCounters::default(),
+      read_only: options.is_read_only(),
+      lsm,
+      _lock: None,
+      loaded: false,
+      seq: None,
+      sync: options.sync,
+      unsynced_writes: 0,
+      flusher: None,
+      defer_syncs: false,
+      sync_due: false,
+      index,
+    };
+    store.load_blooms()?;
+    if let (SyncPolicy::Interval(interval), false) = (options.sync, options.is_read_only()) {
+      store.flusher = Some(Flusher::start(store.active().f.try_clone()?, interval)?);
+    }
+    Ok(store)
+  }
+
+  /// Opens an empty store kept in memory, on a `MemoryStorage`.
+  pub fn in_memory() -> Result<Self> {
+    OpenOptions::new().open_storage(MemoryStorage::new())
+  }
+
+  /// Opens a store whose records are encrypted with `key`. See
+  /// `OpenOptions::encryption_key()`.
+  pub fn open_encrypted(path: &Path, key: &[u8; KEY_LEN]) -> Result<Self> {
+    OpenOptions::new().encryption_key(key).open(path)
+  }
+
+  /// Shorthand for `OpenOptions::new()`.
+  pub fn options() -> OpenOptions {
+    OpenOptions::new()
+  }
+
+  /// Whether the store was opened with an encryption key.
+  pub fn is_encrypted(&self) -> bool {
+    self.cipher.is_some()
+  }
+
+  /// Whether the store was opened with `open_read_only()` or
+  /// `OpenOptions::follow()`.
+  pub fn is_read_only(&self) -> bool {
+    self.read_only
+  }
+
+  /// Fails with `PermissionDenied` if the store was opened read-only.
+  pub(crate) fn check_writable(&self) -> io::Result<()> {
+    match self.read_only {
+      true => Err(io::Error::new(io::ErrorKind::PermissionDenied, "the store was opened read-only")),
+      false => Ok(()),
+    }
+  }
+
+  /// Whether the store is kept as a directory of segments rather than in
+  /// a single file.
+  pub fn is_segmented(&self) -> bool {
+    self.segment_size.is_some()
+  }
+
+  /// Whether the store has a path, rather than being opened on a
+  /// `Storage`.
+  pub(crate) fn has_path(&self) -> bool {
+    !self.path.as_os_str().is_empty()
+  }
+
+  /// The ids of the store's segments, oldest first. A store kept in a
+  /// single file has one segment, with id 0, as does one that uses the
+  /// LSM engine: its write-ahead log.
+  pub fn segment_ids(&self) -> Vec<u32> {
+    self.segments.iter().map(|segment| segment.id).collect()
+  }
+
+  /// The segment that new records are appended to.
+  fn active(&mut self) -> &mut Segment {
+    self.segments.last_mut().expect("a store always has a segment")
+  }
+
+  /// The path of a file that belongs to the store, such as its hint:
+  /// inside the directory of a segmented store, or next to the file of
+  /// one that is not.
+  pub(crate) fn store_path(&self, name: &str) -> PathBuf {
+    match self.is_segmented() || self.lsm.is_some() {
+      true => self.path.join(name),
+      false => ActionKV::sibling_path(&self.path, name),
+    }
+  }
+
+  /// The format version of the active segment. Legacy files are upgraded
+  /// to `FORMAT_VERSION` by `compact()`.
+  pub fn version(&self) -> u32 {
+    self.segments.last().expect("a store always has a segment").version
   }
 
-  fn process_record<R: Read>(f: &mut R) -> io::Result<KeyValuePair> {
+  /// Reads the record that starts at `position`. A record that is cut
+  /// short by the end of the file is reported as `UnexpectedEof`, and one
+  /// that fails its checksum as a `Corruption`.
+  pub(crate) fn process_record<R: Read>(f: &mut R, version: u32, position: Position) -> io::Result<Record> {
+    if version == LEGACY_VERSION {
+      return ActionKV::process_legacy_record(f, position);
+    }
+
     let saved_checksum = f.read_u32::<LittleEndian>()?;
+    let kind = f.read_u8()?;
+    let flags = f.read_u8()?;
     let key_len = f.read_u32::<LittleEndian>()?;
     let val_len = f.read_u32::<LittleEndian>()?;
-    let data_len = key_len + val_len;
+    let data_len = key_len as u64 + val_len as u64;
 
-    let mut data = ByteString::with_capacity(data_len as usize);
+    let mut header_len = 14;
+    let expires_at = match flags & EXPIRES {
+      0 => None,
+      _ => {
+        header_len += 8;
+        Some(f.read_u64::<LittleEndian>()?)
+      },
+    };
+    let seq = match flags & SEQ {
+      0 => None,
+      _ => {
+        header_len += 8;
+        Some(f.read_u64::<LittleEndian>()?)
+      },
+    };
+
+    // The lengths are not trusted until the checksum has been verified,
+    // so the buffer grows as data arrives instead of being allocated up
+    // front.
+    let mut data = ByteString::new();
 
     {
       f.by_ref()
-        .take(data_len as u64)
+        .take(data_len)
         .read_to_end(&mut data)?;
     }
-    debug_assert_eq!(data.len(), data_len as usize);
+    if data.len() as u64 != data_len {
+      return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    let mut digest = crc32::Digest::new(crc32::IEEE);
+    digest.write(&[kind, flags]);
+    digest.write(&key_len.to_le_bytes());
+    digest.write(&val_len.to_le_bytes());
+    if let Some(expires_at) = expires_at {
+      digest.write(&expires_at.to_le_bytes());
+    }
+    if let Some(seq) = seq {
+      digest.write(&seq.to_le_bytes());
+    }
+    digest.write(&data);
+    let checksum = digest.sum32();
+    if checksum != saved_checksum {
+      return Err(Corruption::Checksum {
+        segment: position.segment,
+        offset: position.offset,
+        len: header_len + data_len,
+        expected: saved_checksum,
+        actual: checksum,
+      }.into_io_error());
+    }
+
+    if flags & !KNOWN_FLAGS != 0 {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unknown record flags {:08b}", flags),
+      ));
+    }
+    let kind = RecordKind::from_byte(kind)?;
+
+    let value = data.split_off(key_len as usize);
+    let key = data;
+
+    Ok(Record { kind, flags, key, value, expires_at, seq })
+  }
+
+  fn process_legacy_record<R: Read>(f: &mut R, position: Position) -> io::Result<Record> {
+    let saved_checksum = f.read_u32::<LittleEndian>()?;
+    let key_len = f.read_u32::<LittleEndian>()?;
+    let val_len = f.read_u32::<LittleEndian>()?;
+    let data_len = key_len as u64 + val_len as u64;
+
+    // The lengths are not trusted until the checksum has been verified,
+    // so the buffer grows as data arrives instead of being allocated up
+    // front.
+    let mut data = ByteString::new();
+
+    {
+      f.by_ref()
+        .take(data_len)
+        .read_to_end(&mut data)?;
+    }
+    if data.len() as u64 != data_len {
+      return Err(io::ErrorKind::UnexpectedEof.into());
+    }
 
     let checksum = crc32::checksum_ieee(&data);
     if checksum != saved_checksum {
-      panic!(
-        "data corruption encountered ({:08x} != {:08x})",
-        checksum, saved_checksum
-      );
+      return Err(Corruption::Checksum {
+        segment: position.segment,
+        offset: position.offset,
+        len: 12 + data_len,
+        expected: saved_checksum,
+        actual: checksum,
+      }.into_io_error());
     }
 
     let value = data.split_off(key_len as usize);
     let key = data;
 
-    Ok(KeyValuePair { key, value })
+    // Version 1 files recorded deletions as empty values
+    let kind = match value.is_empty() {
+      true => RecordKind::Tombstone,
+      false => RecordKind::Value,
+    };
+
+    Ok(Record { kind, flags: 0, key, value, expires_at: None, seq: None })
   }
 
-  pub fn seek_to_end(&mut self) -> io::Result<u64> {
-    self.f.seek(SeekFrom::End(0))
+  /// The position of the first record in the store.
+  pub fn first_position(&self) -> Position {
+    let first = &self.segments[0];
+    first.position(first.data_start())
   }
 
-  pub fn load(&mut self) -> io::Result<()> {
-    let mut f = BufReader::new(&mut self.f);
+  /// Calls `visit` with the position of each record in the store, in the
+  /// order they were written. Stops at the first damaged record.
+  ///
+  /// Batched records are held back until their commit record is read,
+  /// and dropped if it never arrives. Commit and meta records themselves
+  /// are not passed to `visit`.
+  pub(crate) fn scan_log<F>(&self, visit: F) -> io::Result<()>
+  where
+    F: FnMut(Position, Record),
+  {
+    let start = self.first_position();
+    self.scan_from(start, false, visit)?;
+    Ok(())
+  }
 
-    loop {
-      let current_poition = f.seek(SeekFrom::Current(0))?;
+  /// Like `scan_log`, but steps over damaged records and returns a
+  /// description of each one instead of failing.
+  fn scan_recovering<F>(&self, visit: F) -> io::Result<Vec<Corruption>>
+  where
+    F: FnMut(Position, Record),
+  {
+    let start = self.first_position();
+    self.scan_from(start, true, visit)
+  }
 
-      let maybe_kv = ActionKV::process_record(&mut f);
-      let kv = match maybe_kv {
-        Ok(kv) => kv,
-        Err(err) => {
-          match err.kind() {
-            io::ErrorKind::UnexpectedEof => {
-              break;
-            },
-            _ => return Err(err),
-          }
-        }
+  fn scan_from<F>(&self, start: Position, recover: bool, mut visit: F) -> io::Result<Vec<Corruption>>
+  where
+    F: FnMut(Position, Record),
+  {
+    let mut problems = Vec::new();
+    let mut failed = None;
+
+    for segment in self.segments.iter().filter(|segment| segment.id >= start.segment) {
+      let offset = match segment.id == start.segment {
+        true => start.offset,
+        false => segment.data_start(),
       };
+      let id = segment.id;
+      let found = segment.scan(offset, recover, |offset, record| {
+        if failed.is_some() {
+          return;
+        }
+        let position = Position { segment: id, offset };
+        match encryption::unseal(self.cipher.as_ref(), position, record) {
+          Ok(record) => visit(position, record),
+          Err(err) => failed = Some(err),
+        }
+      })?;
+      problems.extend(found);
+
+      // Unlike damage, a record that fails to open is not stepped over
+      if let Some(err) = failed {
+        return Err(err);
+      }
+    }
+
+    Ok(problems)
+  }
+
+  /// The position that the next record will be written at.
+  pub fn seek_to_end(&mut self) -> Result<Position> {
+    let segment = self.active();
+    let offset = segment.end()?;
+    Ok(segment.position(offset))
+  }
 
-      self.index.insert(kv.key, current_poition);
+  /// Builds `index` from the store. If a hint file written by `close()`
+  /// or `compact()` still matches the log, the index is read from it and
+  /// only the records appended since are scanned.
+  ///
+  /// A store that uses the LSM engine loads its memtable when it is
+  /// opened, so this only returns the damage that stopped it, if any.
+  pub fn load(&mut self) -> Result<()> {
+    if self.lsm.is_some() {
+      self.check_lsm_intact()?;
+      if !self.loaded {
+        self.replay_wal()?;
+      }
+      return Ok(());
     }
 
+    let now = record::now();
+    let (mut index, start, mut seq) = match self.read_hint()? {
+      Some(hint) => {
+        let mut index = self.index.empty_like();
+        index.extend(hint.entries);
+        index.retain(|_, entry| !entry.is_expired(now));
+        (index, hint.end, hint.last_seq)
+      },
+      None => (std::mem::take(&mut self.index), self.first_position(), 0),
+    };
+
+    let result = self.scan_from(start, false, |position, record| {
+      seq = seq.max(record.version());
+      ActionKV::index_record(&mut index, position, record, now);
+    });
+
+    self.index = index;
+    self.loaded = false;
+    let torn = result?;
+    self.cut_torn_batches(torn)?;
+    self.seq = Some(seq.max(self.meta_seq()?));
+    self.loaded = true;
     Ok(())
   }
 
-  pub fn get(&mut self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+  /// The `seq` of the last insert or delete written. Until `load()` has
+  /// been called, the first call reads the log to find it.
+  pub(crate) fn last_seq(&mut self) -> io::Result<u64> {
+    if let Some(seq) = self.seq {
+      return Ok(seq);
+    }
+
+    let (start, mut seq) = match self.read_hint()? {
+      Some(hint) => (hint.end, hint.last_seq),
+      None => (self.first_position(), 0),
+    };
+    let torn = self.scan_from(start, false, |_, record| seq = seq.max(record.version()))?;
+    self.cut_torn_batches(torn)?;
+
+    let seq = seq.max(self.meta_seq()?);
+    self.seq = Some(seq);
+    Ok(seq)
+  }
+
+  /// The highest `seq` held by the segments' `last-seq` records.
+  fn meta_seq(&self) -> io::Result<u64> {
+    let mut max = 0;
+    for segment in &self.segments {
+      max = max.max(segment.last_seq()?);
+    }
+    Ok(max)
+  }
+
+  /// Points `index` at the record at `position`, or removes its key if
+  /// the record is a tombstone or had expired by `now`.
+  pub(crate) fn index_record(index: &mut Index, position: Position, record: Record, now: u64) {
+    match record.kind {
+      RecordKind::Value if !record.is_expired(now) => {
+        let entry = Entry { position, expires_at: record.expires_at };
+        index.insert(record.key, entry);
+      },
+      RecordKind::Value | RecordKind::Tombstone => {
+        index.remove(&record.key);
+      },
+      RecordKind::Commit | RecordKind::Meta => {},
+    }
+  }
+
+  /// The value of `key`, unless it has been deleted or has expired.
+  /// Until `load()` has been called, a key that is not in `index` is
+  /// looked for in the log with `find()`. With the LSM engine, a key that
+  /// is not in the memtable is looked for in the tables.
+  pub fn get(&self, key: &ByteStr) -> Result<Option<ByteString>> {
     let position = match self.index.get(key) {
+      None if self.lsm.is_some() => {
+        return match self.lsm_record(key)? {
+          Some((_, record)) if record.kind == RecordKind::Value && !record.is_expired(record::now()) => {
+            Ok(Some(record.into_pair()?.value))
+          },
+          _ => Ok(None),
+        };
+      },
+      None if !self.loaded => return Ok(self.find(key)?.map(|(_, value)| value)),
       None => return Ok(None),
-      Some(position) => *position,
+      Some(entry) if entry.is_expired(record::now()) => return Ok(None),
+      Some(entry) => entry.position,
     };
-    
+
     let kv = self.get_at(position)?;
 
     Ok(Some(kv.value))
   }
 
-  pub fn get_at(&mut self, position: u64) -> io::Result<KeyValuePair> {
-    let mut f = BufReader::new(&mut self.f);
-    f.seek(SeekFrom::Start(position))?;
-    let kv = ActionKV::process_record(&mut f)?;
+  /// How long until `key` expires, or `None` if it does not exist or was
+  /// inserted without a TTL. With the LSM engine, a key whose table
+  /// cannot be read is also reported as `None`.
+  pub fn ttl(&self, key: &ByteStr) -> Option<Duration> {
+    let expires_at = match self.index.get(key) {
+      Some(entry) => entry.expires_at?,
+      None => match self.lsm_record(key) {
+        Ok(Some((_, record))) if record.kind == RecordKind::Value => record.expires_at?,
+        _ => return None,
+      },
+    };
+    let now = record::now();
+    match expires_at > now {
+      true => Some(Duration::from_millis(expires_at - now)),
+      false => None,
+    }
+  }
 
-    Ok(kv)
+  /// The pair stored at `position`, whether or not it has since been
+  /// overwritten, deleted or expired.
+  pub fn get_at(&self, position: Position) -> Result<KeyValuePair> {
+    Ok(self.record_at(position)?.into_pair()?)
   }
 
-  pub fn find(&mut self, target: &ByteStr) -> io::Result<Option<(u64, ByteString)>> {
-    let mut f = BufReader::new(&mut self.f);
+  fn record_at(&self, position: Position) -> io::Result<Record> {
+    let record = self.lookup_segment(position.segment)?.read_record(position.offset)?;
+    encryption::unseal(self.cipher.as_ref(), position, record)
+  }
 
-    let mut found: Option<(u64, ByteString)> = None;
+  /// Looks for the latest value of `target` in the log, without using
+  /// `index`. Segments are read newest first, skipping any that their
+  /// bloom filter rules out.
+  pub fn find(&self, target: &ByteStr) -> Result<Option<(Position, ByteString)>> {
+    self.check_log_engine("find()")?;
+    let now = record::now();
+    match self.find_record(target)? {
+      Some((position, record)) if record.kind == RecordKind::Value && !record.is_expired(now) => {
+        Ok(Some((position, record.into_pair()?.value)))
+      },
+      _ => Ok(None),
+    }
+  }
 
-    loop {
-      let position = f.seek(SeekFrom::Current(0))?;
+  /// The last record written for `key`, an insert or a delete.
+  fn find_record(&self, key: &ByteStr) -> io::Result<Option<(Position, Record)>> {
+    for segment in self.segments.iter().rev() {
+      if !self.bloom_counters.check(segment, key) {
+        continue;
+      }
 
-      let maybe_kv = ActionKV::process_record(&mut f);
-      let kv = match maybe_kv {
-        Ok(kv) => kv,
-        Err(err) => {
-          match err.kind() {
-            io::ErrorKind::UnexpectedEof => {
-              break;
-            },
-            _ => return Err(err),
-          }
+      let mut found = None;
+      let mut failed = None;
+      segment.scan(segment.data_start(), false, |offset, record| {
+        if failed.is_some() {
+          return;
         }
-      };
+        let position = segment.position(offset);
+        match encryption::unseal(self.cipher.as_ref(), position, record) {
+          // Important to keep looping until the end of the segment,
+          // in case the key has been overwritten
+          Ok(record) if record.key == key => found = Some((position, record)),
+          Ok(_) => {},
+          Err(err) => failed = Some(err),
+        }
+      })?;
 
-      if kv.key == target {
-        found = Some((position, kv.value));
+      if let Some(err) = failed {
+        return Err(err);
+      }
+      match found {
+        Some(found) => return Ok(Some(found)),
+        None => self.bloom_counters.missed(segment),
       }
+    }
+
+    Ok(None)
+  }
+
+  pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) -> Result<()> {
+    let position = self.append(RecordKind::Value, key, value, None, self.compression)?;
+
+    self.index.insert(key.to_vec(), Entry::from(position));
+    self.memtable_insert(key);
+    Ok(self.flush_if_full()?)
+  }
+
+  /// Like `insert`, but `key` expires once `ttl` has passed. From then on
+  /// `get` and iteration treat it as deleted, and `load()` and
+  /// compaction drop its record.
+  pub fn insert_with_ttl(&mut self, key: &ByteStr, value: &ByteStr, ttl: Duration) -> Result<()> {
+    self.insert_with(key, value, WriteOptions::new().ttl(ttl))
+  }
+
+  /// Like `insert`, with a TTL or compression of its own. See
+  /// `WriteOptions`.
+  pub fn insert_with(&mut self, key: &ByteStr, value: &ByteStr, options: &WriteOptions) -> Result<()> {
+    let expires_at = options
+      .ttl
+      .map(|ttl| record::now().saturating_add(ttl.as_millis() as u64));
+    let compression = options.compression.unwrap_or(self.compression);
+    let position = self.append(RecordKind::Value, key, value, expires_at, compression)?;
 
-      // Important to keep looping until the end of the file,
-      // in case the key has been overwritten
+    self.index.insert(key.to_vec(), Entry { position, expires_at });
+    self.memtable_insert(key);
+    Ok(self.flush_if_full()?)
+  }
+
+  /// Appends a value without adding it to `index`. Not supported with the
+  /// LSM engine, where `index` is the memtable.
+  pub fn insert_but_ignore_index(&mut self, key: &ByteStr, value: &ByteStr) -> Result<Position> {
+    self.check_log_engine("insert_but_ignore_index()")?;
+    Ok(self.append(RecordKind::Value, key, value, None, self.compression)?)
+  }
+
+  /// The compression that new values get unless a write asks otherwise.
+  pub fn compression(&self) -> Compression {
+    self.compression
+  }
+
+  /// Appends a record to the active segment. A compaction that finished
+  /// in the background is installed first; if it failed, its error is
+  /// returned and the record is not written.
+  fn append(
+    &mut self,
+    kind: RecordKind,
+    key: &ByteStr,
+    value: &ByteStr,
+    expires_at: Option<u64>,
+    compression: Compression,
+  ) -> io::Result<Position> {
+    self.check_writable()?;
+    self.check_lsm_intact()?;
+    self.finish_compaction(false)?;
+
+    let version = self.version();
+    let (flags, value) = ActionKV::compress(version, compression, value)?;
+    // Version 1 files have nowhere to keep a sequence number
+    let seq = match version {
+      LEGACY_VERSION => None,
+      _ => Some(self.last_seq()? + 1),
+    };
+    let record = self.seal(Record { kind, flags, key: key.to_vec(), value, expires_at, seq })?;
+    let mut buf = ByteString::new();
+    ActionKV::write_record(&mut buf, version, kind, record.flags, expires_at, seq, &record.key, &record.value)?;
+    self.roll_if_needed(buf.len() as u64)?;
+
+    let segment = self.active();
+    let offset = segment.append(&buf)?;
+    let position = segment.position(offset);
+    if seq.is_some() {
+      self.seq = seq;
     }
+    self.bloom_insert(key)?;
+    self.sync_after_write()?;
 
-    Ok(found)
+    Ok(position)
   }
 
-  pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
-    let position = self.insert_but_ignore_index(key, value)?;
+  /// Seals the active segment and starts a new one if `incoming` more
+  /// bytes would take it past the segment size. An empty segment takes a
+  /// write of any size, so that records are never split.
+  fn roll_if_needed(&mut self, incoming: u64) -> io::Result<()> {
+    let max = match self.segment_size {
+      Some(max) => max,
+      None => return Ok(()),
+    };
+
+    let active = self.active();
+    if active.is_empty() || active.len + incoming <= max {
+      return Ok(());
+    }
+    self.roll()
+  }
+
+  fn roll(&mut self) -> io::Result<()> {
+    // Nothing is written to a sealed segment again, so this is the last
+    // chance for the sync policy to cover it
+    if self.sync != SyncPolicy::Never {
+      self.sync()?;
+    }
 
-    self.index.insert(key.to_vec(), position);
+    self.write_blooms()?;
+
+    let id = self.active().id + 1;
+    let path = segment::segment_path(&self.path, id);
+    let segment = Segment::create(id, &path)?;
+    ActionKV::sync_parent_dir(&path)?;
+    self.segments.push(segment);
+    self.retarget_flusher()?;
+    self.load_blooms()?;
+
+    if self.compact_after > 0 && self.segments.len() > self.compact_after {
+      self.start_compaction()?;
+    }
     Ok(())
   }
 
-  pub fn insert_but_ignore_index(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<u64> {
-    let mut f = BufWriter::new(&mut self.f);
+  /// Counts a write against the sync policy, syncing if one is due.
+  /// With `SyncPolicy::Interval`, the write is left to the flusher.
+  fn sync_after_write(&mut self) -> io::Result<()> {
+    self.unsynced_writes += 1;
+
+    let due = match self.sync {
+      SyncPolicy::Always => true,
+      SyncPolicy::Interval(_) => false,
+      SyncPolicy::EveryWrites(n) => self.unsynced_writes >= n,
+      SyncPolicy::Never => false,
+    };
+    if let Some(flusher) = &self.flusher {
+      flusher.written()?;
+    }
+
+    match (due, self.defer_syncs) {
+      (true, true) => {
+        self.sync_due = true;
+        Ok(())
+      },
+      (true, false) => Ok(self.sync()?),
+      (false, _) => Ok(()),
+    }
+  }
+
+  /// A second handle to the active segment to sync through, if a sync
+  /// was left due while `defer_syncs` was set, or `always`. The writes so
+  /// far count as synced, and a background sync that failed is returned.
+  pub(crate) fn take_due_sync(&mut self, always: bool) -> io::Result<Option<Box<dyn Storage>>> {
+    let due = std::mem::take(&mut self.sync_due);
+    if self.read_only || !(always || due) {
+      return Ok(None);
+    }
+    self.unsynced_writes = 0;
+    if let Some(flusher) = &self.flusher {
+      flusher.synced()?;
+    }
+    Ok(Some(self.active().f.try_clone()?))
+  }
 
+  /// Forces out the writes that the sync policy has not synced yet, if
+  /// there are any, and reports a background sync that failed since the
+  /// last write. Unlike `sync()`, this does nothing when every write has
+  /// been synced already.
+  pub fn flush(&mut self) -> Result<()> {
+    let pending = match &self.flusher {
+      Some(flusher) => flusher.is_dirty(),
+      None => self.unsynced_writes > 0,
+    };
+    if pending {
+      return self.sync();
+    }
+    if let Some(flusher) = &self.flusher {
+      flusher.synced()?;
+    }
+    Ok(())
+  }
+
+  /// Forces every record written so far out to the disk, whatever the
+  /// sync policy. Does nothing if the store is read-only.
+  pub fn sync(&mut self) -> Result<()> {
+    if self.read_only {
+      return Ok(());
+    }
+    let segment = self.active();
+    segment.f.sync()?;
+    self.unsynced_writes = 0;
+    if let Some(flusher) = &self.flusher {
+      flusher.synced()?;
+    }
+    Ok(())
+  }
+
+  /// Points the flusher, if there is one, at the active segment after it
+  /// has been replaced.
+  pub(crate) fn retarget_flusher(&mut self) -> io::Result<()> {
+    if let Some(flusher) = &self.flusher {
+      flusher.retarget(self.segments.last().expect("a store always has a segment").f.try_clone()?);
+    }
+    Ok(())
+  }
+
+  pub fn sync_policy(&self) -> SyncPolicy {
+    self.sync
+  }
+
+  /// Compresses `value` for a record in the given format version,
+  /// returning the flags that mark how.
+  pub(crate) fn compress(version: u32, compression: Compression, value: &ByteStr) -> io::Result<(u8, ByteString)> {
+    if version == LEGACY_VERSION && compression != Compression::None {
+      return Err(ActionKvError::NeedsUpgrade { feature: "compression", found: LEGACY_VERSION }.into());
+    }
+    compression.compress(value)
+  }
+
+  /// Encrypts `record` if the store has a key.
+  pub(crate) fn seal(&self, record: Record) -> io::Result<Record> {
+    match &self.cipher {
+      Some(cipher) => cipher.seal(record),
+      None => Ok(record),
+    }
+  }
+
+  /// Writes a record in the given format version. The `EXPIRES` and
+  /// `SEQ` flags are added to `flags` when there is an `expires_at` or a
+  /// `seq`. `key` and `value` are written as given, so a compressed or
+  /// encrypted record must come with its flags.
+  #[allow(clippy::too_many_arguments)]
+  pub(crate) fn write_record<W: Write + ?Sized>(
+    f: &mut W,
+    version: u32,
+    kind: RecordKind,
+    mut flags: u8,
+    expires_at: Option<u64>,
+    seq: Option<u64>,
+    key: &ByteStr,
+    value: &ByteStr,
+  ) -> io::Result<u64> {
+    ActionKvError::check_len(key, value)?;
+
+    if version == LEGACY_VERSION {
+      if flags & ENCRYPTED != 0 {
+        return Err(ActionKvError::NeedsUpgrade { feature: "encryption", found: LEGACY_VERSION }.into());
+      }
+      if flags & COMPRESSED != 0 {
+        return Err(ActionKvError::NeedsUpgrade { feature: "compression", found: LEGACY_VERSION }.into());
+      }
+      if expires_at.is_some() {
+        return Err(ActionKvError::NeedsUpgrade { feature: "expiry", found: LEGACY_VERSION }.into());
+      }
+      if seq.is_some() {
+        return Err(ActionKvError::NeedsUpgrade { feature: "sequence numbers", found: LEGACY_VERSION }.into());
+      }
+
+      // Version 1 has no record kinds; a deletion is an empty value
+      let value: &ByteStr = match kind {
+        RecordKind::Tombstone => b"",
+        _ => value,
+      };
+      return ActionKV::write_legacy_record(f, key, value);
+    }
+
+    if expires_at.is_some() {
+      flags |= EXPIRES;
+    }
+    if seq.is_some() {
+      flags |= SEQ;
+    }
+
+    let key_len = key.len() as u32;
+    let val_len = value.len() as u32;
+    let mut tmp = ByteString::with_capacity(26 + key.len() + value.len());
+
+    tmp.push(kind.to_byte());
+    tmp.push(flags);
+    tmp.extend_from_slice(&key_len.to_le_bytes());
+    tmp.extend_from_slice(&val_len.to_le_bytes());
+    if let Some(expires_at) = expires_at {
+      tmp.extend_from_slice(&expires_at.to_le_bytes());
+    }
+    if let Some(seq) = seq {
+      tmp.extend_from_slice(&seq.to_le_bytes());
+    }
+    tmp.extend_from_slice(key);
+    tmp.extend_from_slice(value);
+
+    let checksum = crc32::checksum_ieee(&tmp);
+
+    f.write_u32::<LittleEndian>(checksum)?;
+    f.write_all(&tmp)?;
+
+    Ok(4 + tmp.len() as u64)
+  }
+
+  /// Writes `record` out again in `FORMAT_VERSION`, as compaction and
+  /// repair do, keeping its `seq`. It is written on its own, without the
+  /// `BATCHED` flag. A compressed or encrypted record is copied as it is,
+  /// without decompressing or decrypting it.
+  pub(crate) fn copy_record<W: Write + ?Sized>(f: &mut W, record: &Record) -> io::Result<u64> {
+    ActionKV::write_record(
+      f,
+      FORMAT_VERSION,
+      record.kind,
+      record.flags & (COMPRESSED | ENCRYPTED),
+      record.expires_at,
+      record.seq,
+      &record.key,
+      &record.value,
+    )
+  }
+
+  fn write_legacy_record<W: Write + ?Sized>(f: &mut W, key: &ByteStr, value: &ByteStr) -> io::Result<u64> {
     let key_len = key.len();
     let val_len = value.len();
     let mut tmp = ByteString::with_capacity(key_len + val_len);
@@ -168,24 +1047,49 @@ impl ActionKV {
 
     let checksum = crc32::checksum_ieee(&tmp);
 
-    let next_byte = SeekFrom::End(0);
-    let current_poition = f.seek(SeekFrom::Current(0))?;
-    f.seek(next_byte)?;
     f.write_u32::<LittleEndian>(checksum)?;
     f.write_u32::<LittleEndian>(key_len as u32)?;
     f.write_u32::<LittleEndian>(val_len as u32)?;
     f.write_all(&tmp)?;
 
-    Ok(current_poition)
+    Ok(12 + tmp.len() as u64)
+  }
+
+  /// `path` with `extension` appended to its file name, such as
+  /// `capitals.compact` for `capitals`.
+  pub(crate) fn sibling_path(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(extension);
+    path.with_file_name(name)
+  }
+
+  #[cfg(unix)]
+  pub(crate) fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+      Some(parent) if !parent.as_os_str().is_empty() => parent,
+      _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()
+  }
+
+  #[cfg(not(unix))]
+  pub(crate) fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
   }
 
   #[inline]
-  pub fn update(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+  pub fn update(&mut self, key: &ByteStr, value: &ByteStr) -> Result<()> {
     self.insert(key, value)
   }
 
-  #[inline]
-  pub fn delete(&mut self, key: &ByteStr) -> io::Result<()> {
-    self.insert(key, b"")
+  /// Appends a tombstone for `key`. Later calls to `get` return `None`
+  /// until the key is inserted again.
+  pub fn delete(&mut self, key: &ByteStr) -> Result<()> {
+    let position = self.append(RecordKind::Tombstone, key, b"", None, Compression::None)?;
+
+    self.index.remove(key);
+    self.memtable_delete(key, position);
+    Ok(self.flush_if_full()?)
   }
-}
\ No newline at end of file
+}
@@ -0,0 +1,176 @@
+//! Advisory locks that keep two stores from writing the same files.
+//!
+//! Opening a store with a path locks a `lock` file, next to the store's
+//! file (`capitals.lock` for `capitals`) or inside the directory of a
+//! segmented store. The data files themselves cannot carry the lock, as
+//! compaction replaces them. A store opened for writing holds the lock
+//! exclusively; one opened with `ActionKV::open_read_only()` shares it
+//! with other readers. Either way, opening fails with `StoreLocked`
+//! rather than waiting if the lock is already held in a way that
+//! conflicts. A store opened with `OpenOptions::follow()`, to watch a
+//! writer, takes no lock at all.
+//!
+//! The lock is `flock` on Unix and `LockFileEx` on Windows. It belongs to
+//! the open lock file, so two stores in the same process conflict just as
+//! two processes do, and it is released when the store is dropped or its
+//! process dies. It is advisory: other programs can still write to the
+//! files.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, TryLockError};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The store is already open elsewhere, for writing or, when opening it
+/// for writing, for reading. Returned as `ActionKvError::Locked`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoreLocked {
+  /// The lock file.
+  pub path: PathBuf,
+
+  /// Whether this open wanted to share the lock, as a read-only open
+  /// does.
+  pub shared: bool,
+}
+
+impl StoreLocked {
+  /// Returns the `StoreLocked` error carried by `err`, if there is one.
+  pub fn from_io_error(err: &io::Error) -> Option<&StoreLocked> {
+    err.get_ref().and_then(|inner| inner.downcast_ref::<StoreLocked>())
+  }
+
+  pub(crate) fn into_io_error(self) -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, self)
+  }
+}
+
+impl fmt::Display for StoreLocked {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let holder = match self.shared {
+      true => "a writer",
+      false => "another store",
+    };
+    write!(f, "{} is locked by {}", self.path.display(), holder)
+  }
+}
+
+impl Error for StoreLocked {}
+
+/// Locks the file at `path`, creating it if need be, and returns it. The
+/// lock lasts until the file is closed.
+pub(crate) fn acquire(path: &Path, shared: bool) -> io::Result<File> {
+  let f = match shared {
+    // A reader may not be allowed to create files next to the store
+    true => match File::open(path) {
+      Err(err) if err.kind() == io::ErrorKind::NotFound => create(path)?,
+      opened => opened?,
+    },
+    false => create(path)?,
+  };
+
+  let locked = match shared {
+    true => f.try_lock_shared(),
+    false => f.try_lock(),
+  };
+  match locked {
+    Ok(()) => Ok(f),
+    Err(TryLockError::WouldBlock) => Err(StoreLocked { path: path.to_path_buf(), shared }.into_io_error()),
+    Err(TryLockError::Error(err)) => Err(err),
+  }
+}
+
+fn create(path: &Path) -> io::Result<File> {
+  fs::OpenOptions::new()
+    .read(true)
+    .write(true)
+    .create(true)
+    .truncate(false)
+    .open(path)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+  use std::time::Duration;
+
+  use crate::{ActionKV, ActionKvError, OpenOptions};
+
+  fn store_path(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("actionkv-lock-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.join("store")
+  }
+
+  fn assert_locked(opened: crate::Result<ActionKV>, shared: bool) {
+    match opened {
+      Err(ActionKvError::Locked(err)) => assert_eq!(err.shared, shared),
+      other => panic!("expected StoreLocked, got {:?}", other.map(|_| ())),
+    }
+  }
+
+  #[test]
+  fn second_writer_is_locked_out() {
+    let path = store_path("writers");
+    let store = ActionKV::open(&path).unwrap();
+    assert_locked(ActionKV::open(&path), false);
+    assert_locked(ActionKV::open_read_only(&path), true);
+
+    drop(store);
+    ActionKV::open(&path).unwrap();
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+  }
+
+  #[test]
+  fn readers_share_the_lock() {
+    let path = store_path("readers");
+    let mut store = ActionKV::open(&path).unwrap();
+    store.load().unwrap();
+    store.insert(b"a", b"1").unwrap();
+    store.close().unwrap();
+
+    let mut first = ActionKV::open_read_only(&path).unwrap();
+    let mut second = ActionKV::open_read_only(&path).unwrap();
+    first.load().unwrap();
+    second.load().unwrap();
+    assert_eq!(first.get(b"a").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(second.get(b"a").unwrap(), Some(b"1".to_vec()));
+    assert_locked(ActionKV::open(&path), false);
+
+    drop((first, second));
+    ActionKV::open(&path).unwrap();
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+  }
+
+  #[test]
+  fn follower_does_not_block_a_writer() {
+    let path = store_path("follow");
+    let mut writer = ActionKV::open(&path).unwrap();
+    writer.load().unwrap();
+    writer.insert(b"a", b"1").unwrap();
+
+    let mut follower = OpenOptions::new().follow(true).open(&path).unwrap();
+    follower.load().unwrap();
+    let end = follower.seek_to_end().unwrap();
+    let mut watch = follower.watch(b"", end).unwrap();
+    watch.poll_interval(Duration::from_millis(1));
+
+    // Writers come and go while the watch is open
+    writer.insert(b"b", b"2").unwrap();
+    writer.close().unwrap();
+    let mut writer = ActionKV::open(&path).unwrap();
+    writer.load().unwrap();
+    writer.delete(b"a").unwrap();
+
+    let first = watch.next().unwrap().unwrap();
+    assert_eq!((first.key, first.value), (b"b".to_vec(), Some(b"2".to_vec())));
+    let second = watch.next().unwrap().unwrap();
+    assert_eq!((second.key, second.value), (b"a".to_vec(), None));
+
+    // A follower cannot write
+    assert!(follower.insert(b"c", b"3").is_err());
+    drop((writer, follower));
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+  }
+}
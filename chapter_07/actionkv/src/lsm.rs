@@ -0,0 +1,1529 @@
+//! The LSM engine: a log-structured merge tree kept in a directory.
+//!
+//! With `OpenOptions::engine(Engine::Lsm)`, writes are still appended to
+//! a log, but that log (`wal` in the store's directory) only backs the
+//! memtable: `index`, plus the keys deleted since the last flush. Once
+//! the log reaches `OpenOptions::memtable_size()`, the memtable is
+//! written out in key order as an immutable table (`0000000001.sst`,
+//! ...) and the log is emptied.
+//!
+//! A table holds records in the same framing as the log, copied as
+//! stored, so encrypted records stay sealed. After them comes a sparse
+//! index, with the key and offset of the first record in each block of
+//! about `BLOCK_SIZE` bytes and the table's bloom filter if filters are
+//! enabled, then a fixed trailer:
+//!
+//! ```text
+//! +--------+-----------------+-----------------+--------------+------------+
+//! | header | records, sorted | "sst-index"     | index offset | "AKVTABLE" |
+//! |        | by key          | Meta record     |     u64      |  8 bytes   |
+//! +--------+-----------------+-----------------+--------------+------------+
+//! ```
+//!
+//! The index is bincode, sealed with the store's key in an encrypted
+//! store, since it holds keys.
+//!
+//! Tables are arranged in levels. Flushes add tables to level 0, where
+//! they may overlap. Once level 0 has `L0_TABLES` tables, they are merged
+//! with the tables they overlap in level 1. From level 1 down, the tables
+//! in a level never overlap, and a level that grows past its limit
+//! (`LEVEL_BASE` for level 1, and `LEVEL_FANOUT` times more for each
+//! level below) has one of its tables merged into the next. Deletes and
+//! expired values are only dropped once they reach the lowest level that
+//! holds anything. Merging runs on the writing thread, straight after
+//! the flush that calls for it.
+//!
+//! The `manifest` file lists the tables in each level. It is replaced
+//! atomically after every flush and merge, and tables it does not list
+//! are deleted when the store is opened, so an interrupted flush or merge
+//! is simply redone from the log or the old tables.
+//!
+//! A record cut short or damaged at the end of the log, by a write that
+//! never finished, is truncated away as the store is opened. Damage
+//! anywhere else, in the log or a table, leaves the store open but
+//! refusing reads and writes with the `Corruption` until `repair()`
+//! removes it. `repair()` rewrites the log without its damaged records,
+//! and rebuilds a damaged table from the records it still holds.
+//!
+//! A lookup tries the memtable, then level 0 from newest to oldest, then
+//! the one table in each lower level whose keys could cover it. Scans
+//! merge all of them in key order. `find()`, `get_as_of()`,
+//! `snapshot()`, `watch()` and `stats()` work on the log alone, and fail
+//! with `Unsupported`.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::bloom::BloomFilter;
+use crate::encryption::{self, Cipher};
+use crate::index::{self, Entries};
+use crate::iter::Iter;
+use crate::record::{self, Record, RecordKind, ENCRYPTED, FORMAT_VERSION};
+use crate::segment::{self, ReadAt, Segment};
+use crate::{
+  ActionKV, AuthenticationError, ByteStr, ByteString, Corruption, Engine, Entry, OpenOptions, Position,
+  RecoveryReport, DEFAULT_MEMTABLE_SIZE,
+};
+
+const MANIFEST: &str = "manifest";
+const MANIFEST_MAGIC: &[u8; 8] = b"AKVMANIF";
+const MANIFEST_VERSION: u32 = 1;
+
+const WAL: &str = "wal";
+
+/// The write-ahead log's segment id. Tables are numbered from 1.
+pub(crate) const WAL_ID: u32 = 0;
+
+const TABLE_EXTENSION: &str = "sst";
+const TABLE_MAGIC: &[u8; 8] = b"AKVTABLE";
+
+/// Key of the `Meta` record that holds a table's index.
+const TABLE_INDEX: &[u8] = b"sst-index";
+
+const TRAILER_LEN: u64 = 16;
+
+/// How far apart the keys in a table's sparse index are, in bytes.
+const BLOCK_SIZE: u64 = 4096;
+
+/// The size at which a merge starts a new table.
+const TABLE_SIZE: u64 = 2 * 1024 * 1024;
+
+/// How many tables level 0 takes before they are merged into level 1.
+const L0_TABLES: usize = 4;
+
+/// How large level 1 may grow, and how much larger each level below it.
+const LEVEL_BASE: u64 = 10 * 1024 * 1024;
+const LEVEL_FANOUT: u64 = 10;
+
+/// The tables of an LSM store, and the part of the memtable that `index`
+/// cannot hold.
+#[derive(Debug)]
+pub(crate) struct Lsm {
+  memtable_size: u64,
+  bloom: Option<f64>,
+  next_id: u32,
+
+  /// The `seq` held by the manifest, which is at least that of every
+  /// record in the tables.
+  flushed_seq: u64,
+
+  /// Level 0 oldest first, and every other level in key order.
+  levels: Vec<Vec<Table>>,
+
+  /// For each level, the last key of the table merged out of it last, so
+  /// that merges take turns through its key range.
+  cursors: Vec<ByteString>,
+
+  /// Keys deleted since the last flush, and where their tombstones are
+  /// in the log. A value that has expired counts as a delete, since it
+  /// still hides older values in the tables.
+  deleted: BTreeMap<ByteString, Position>,
+
+  /// What kept the store from loading, for `repair()` to remove.
+  damage: Vec<Corruption>,
+
+  /// The tables that could not be opened, by level, in the order of the
+  /// manifest.
+  broken: Vec<(usize, u32)>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+  next_id: u32,
+  last_seq: u64,
+
+  /// The ids of the tables in each level, in the order kept by `Lsm`.
+  levels: Vec<Vec<u32>>,
+}
+
+/// A sorted, immutable table.
+#[derive(Debug)]
+pub(crate) struct Table {
+  segment: Segment,
+
+  /// The first key of each block and the offset it starts at.
+  blocks: Vec<(ByteString, u64)>,
+  last: ByteString,
+
+  /// Where the records stop and the index starts.
+  end: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TableIndex {
+  blocks: Vec<(ByteString, u64)>,
+  last: ByteString,
+  bloom: Option<BloomFilter>,
+}
+
+/// A record found in the memtable or a table, along with its key, which
+/// may be sealed in `record`.
+pub(crate) struct Found {
+  pub key: ByteString,
+  pub position: Position,
+  pub record: Record,
+}
+
+type Source<'a> = Box<dyn Iterator<Item = io::Result<Found>> + 'a>;
+
+/// Whether `path` holds an LSM store.
+pub(crate) fn is_lsm(path: &Path) -> bool {
+  path.join(MANIFEST).is_file()
+}
+
+/// Opens the write-ahead log and tables of the LSM store in `dir`,
+/// creating the store if need be. With `read_only`, nothing is created
+/// or deleted.
+pub(crate) fn open(dir: &Path, options: &OpenOptions) -> io::Result<(Segment, Lsm)> {
+  let memtable_size = options.memtable_size.unwrap_or(DEFAULT_MEMTABLE_SIZE);
+  if memtable_size == 0 {
+    return Err(io::Error::new(io::ErrorKind::InvalidInput, "the memtable size must be more than 0"));
+  }
+
+  let wal_path = dir.join(WAL);
+  let (wal, manifest) = match options.is_read_only() {
+    true => {
+      let manifest = read_manifest(dir)?.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("{} holds no LSM store", dir.display()))
+      })?;
+      (Segment::open_read_only(WAL_ID, &wal_path)?, manifest)
+    },
+    false => {
+      // The log comes first, so that a store with a manifest always has one
+      let wal = Segment::create(WAL_ID, &wal_path)?;
+      let manifest = match read_manifest(dir)? {
+        Some(manifest) => manifest,
+        None => {
+          let manifest = Manifest { next_id: 1, last_seq: 0, levels: vec![Vec::new()] };
+          write_manifest(dir, &manifest)?;
+          manifest
+        },
+      };
+      remove_stale(dir, &manifest)?;
+      (wal, manifest)
+    },
+  };
+
+  let cipher = options.cipher.as_ref();
+  let mut levels = Vec::with_capacity(manifest.levels.len().max(1));
+  let mut damage = Vec::new();
+  let mut broken = Vec::new();
+  for (n, ids) in manifest.levels.iter().enumerate() {
+    let mut level = Vec::with_capacity(ids.len());
+    for &id in ids {
+      let path = table_path(dir, id);
+      match Table::open(&path, id, cipher) {
+        Ok(table) => level.push(table),
+        Err(err) if is_damaged(&err) => {
+          damage.push(Corruption::Table { segment: id, len: fs::metadata(&path)?.len() });
+          broken.push((n, id));
+        },
+        Err(err) => return Err(err),
+      }
+    }
+    levels.push(level);
+  }
+  if levels.is_empty() {
+    levels.push(Vec::new());
+  }
+
+  let lsm = Lsm {
+    memtable_size,
+    bloom: options.bloom_filter,
+    next_id: manifest.next_id,
+    flushed_seq: manifest.last_seq,
+    cursors: vec![ByteString::new(); levels.len()],
+    levels,
+    deleted: BTreeMap::new(),
+    damage,
+    broken,
+  };
+  Ok((wal, lsm))
+}
+
+/// The intact records of the table at `path`, with their keys, up to
+/// `end` or, for a table whose index could not be read, to the end of
+/// the file. A table too damaged to open as a segment has none.
+fn salvage(path: &Path, id: u32, end: Option<u64>, cipher: Option<&Cipher>) -> io::Result<Vec<(ByteString, Record)>> {
+  let segment = match Segment::open_read_only(id, path) {
+    Ok(segment) => segment,
+    Err(err) if is_damaged(&err) => return Ok(Vec::new()),
+    Err(err) => return Err(err),
+  };
+  let end = end.unwrap_or(segment.len);
+  let mut records = Vec::new();
+  segment.scan_until(segment.data_start(), end, true, |offset, record| records.push((offset, record)))?;
+
+  records
+    .into_iter()
+    .map(|(offset, record)| {
+      let key = match record.flags & ENCRYPTED {
+        0 => record.key.clone(),
+        _ => {
+          let sealed = Record { key: record.key.clone(), value: record.value.clone(), ..record };
+          encryption::unseal(cipher, segment.position(offset), sealed)?.key
+        },
+      };
+      Ok((key, record))
+    })
+    .collect()
+}
+
+/// Whether `err`, from opening a table, means that the table is damaged
+/// rather than that it could not be read at all. A table that does not
+/// open with the store's key is not damaged.
+fn is_damaged(err: &io::Error) -> bool {
+  let kind = err.kind();
+  AuthenticationError::from_io_error(err).is_none()
+    && (kind == io::ErrorKind::InvalidData || kind == io::ErrorKind::UnexpectedEof)
+}
+
+/// Deletes the tables that the manifest does not list, which were left
+/// by a flush or merge that did not finish, along with any temporary
+/// manifest.
+fn remove_stale(dir: &Path, manifest: &Manifest) -> io::Result<()> {
+  let mut removed = false;
+  for entry in fs::read_dir(dir)? {
+    let path = entry?.path();
+    let stale = match table_id(&path) {
+      Some(id) => !manifest.levels.iter().any(|level| level.contains(&id)),
+      None => path.file_name().is_some_and(|name| name == "manifest.tmp"),
+    };
+    if stale {
+      fs::remove_file(&path)?;
+      removed = true;
+    }
+  }
+
+  match removed {
+    true => ActionKV::sync_parent_dir(&dir.join(MANIFEST)),
+    false => Ok(()),
+  }
+}
+
+fn table_path(dir: &Path, id: u32) -> PathBuf {
+  dir.join(format!("{:010}.{}", id, TABLE_EXTENSION))
+}
+
+fn table_id(path: &Path) -> Option<u32> {
+  if path.extension()? != TABLE_EXTENSION {
+    return None;
+  }
+  path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Replaces the manifest in `dir`: the new one is written next to it,
+/// synced, then renamed over it.
+fn write_manifest(dir: &Path, manifest: &Manifest) -> io::Result<()> {
+  let body = bincode::serialize(manifest)
+    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+  let path = dir.join(MANIFEST);
+  let tmp_path = dir.join("manifest.tmp");
+  {
+    let mut f = BufWriter::new(File::create(&tmp_path)?);
+    f.write_all(MANIFEST_MAGIC)?;
+    f.write_u32::<LittleEndian>(MANIFEST_VERSION)?;
+    f.write_u64::<LittleEndian>(body.len() as u64)?;
+    f.write_u32::<LittleEndian>(crc32::checksum_ieee(&body))?;
+    f.write_all(&body)?;
+    f.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+  }
+  fs::rename(&tmp_path, &path)?;
+  ActionKV::sync_parent_dir(&path)
+}
+
+/// Reads the manifest in `dir`, or returns `None` if there is none.
+fn read_manifest(dir: &Path) -> io::Result<Option<Manifest>> {
+  let path = dir.join(MANIFEST);
+  let mut f = match File::open(&path) {
+    Ok(f) => BufReader::new(f),
+    Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+    Err(err) => return Err(err),
+  };
+  let damaged = || io::Error::new(io::ErrorKind::InvalidData, format!("{} is damaged", path.display()));
+
+  let mut magic = [0u8; 8];
+  f.read_exact(&mut magic).map_err(|_| damaged())?;
+  if magic != *MANIFEST_MAGIC {
+    return Err(damaged());
+  }
+  let version = f.read_u32::<LittleEndian>()?;
+  if version > MANIFEST_VERSION {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      format!("unsupported manifest version {}", version),
+    ));
+  }
+  let body_len = f.read_u64::<LittleEndian>()?;
+  let checksum = f.read_u32::<LittleEndian>()?;
+
+  let mut body = ByteString::new();
+  f.take(body_len).read_to_end(&mut body)?;
+  if body.len() as u64 != body_len || crc32::checksum_ieee(&body) != checksum {
+    return Err(damaged());
+  }
+
+  let manifest = bincode::deserialize(&body)
+    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+  Ok(Some(manifest))
+}
+
+/// The first key after every key that starts with `prefix`.
+fn prefix_end(prefix: &ByteStr) -> Bound<ByteString> {
+  let mut end = prefix.to_vec();
+  while let Some(last) = end.pop() {
+    if last < u8::MAX {
+      end.push(last + 1);
+      return Bound::Excluded(end);
+    }
+  }
+  Bound::Unbounded
+}
+
+fn level_limit(level: usize) -> u64 {
+  LEVEL_BASE.saturating_mul(LEVEL_FANOUT.saturating_pow(level as u32 - 1))
+}
+
+fn level_size(tables: &[Table]) -> u64 {
+  tables.iter().map(|table| table.segment.len).sum()
+}
+
+impl Lsm {
+  fn manifest(&self) -> Manifest {
+    Manifest {
+      next_id: self.next_id,
+      last_seq: self.flushed_seq,
+      levels: self
+        .levels
+        .iter()
+        .map(|level| level.iter().map(|table| table.segment.id).collect())
+        .collect(),
+    }
+  }
+
+  /// The table with id `id`.
+  fn table(&self, id: u32) -> io::Result<&Table> {
+    self
+      .levels
+      .iter()
+      .flatten()
+      .find(|table| table.segment.id == id)
+      .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("segment {} does not exist", id)))
+  }
+
+  /// The tables that could hold `key`, newest first.
+  fn candidates<'a>(&'a self, key: &'a ByteStr) -> impl Iterator<Item = &'a Table> {
+    let level0 = self.levels[0].iter().rev().filter(move |table| table.covers(key));
+    let lower = self.levels[1..].iter().filter_map(move |level| {
+      let i = level.partition_point(|table| table.last.as_slice() < key);
+      level.get(i).filter(|table| table.covers(key))
+    });
+    level0.chain(lower)
+  }
+
+  /// Sources that read `tables` within `start` and `end`, in order of
+  /// precedence. Each item of `tables` is a level and some of its
+  /// tables, in the order they are kept in.
+  fn sources<'a>(
+    &'a self,
+    tables: Vec<(usize, Vec<&'a Table>)>,
+    cipher: Option<&'a Cipher>,
+    start: &Bound<ByteString>,
+    end: &Bound<ByteString>,
+  ) -> Vec<Source<'a>> {
+    let mut sources: Vec<Source<'a>> = Vec::new();
+    for (level, tables) in tables {
+      let tables: Vec<&Table> = tables.into_iter().filter(|table| table.overlaps(start, end)).collect();
+      if level == 0 {
+        // Newer tables in level 0 take precedence over older ones
+        for table in tables.into_iter().rev() {
+          sources.push(Box::new(table.iter(cipher, start.clone(), end.clone())));
+        }
+        continue;
+      }
+      // Tables in the other levels do not overlap, so each level is read
+      // as one run
+      let (start, end) = (start.clone(), end.clone());
+      sources.push(Box::new(tables.into_iter().flat_map(move |table| table.iter(cipher, start.clone(), end.clone()))));
+    }
+    sources
+  }
+}
+
+impl Table {
+  /// Opens the table at `path` and reads its index.
+  fn open(path: &Path, id: u32, cipher: Option<&Cipher>) -> io::Result<Self> {
+    let mut segment = Segment::open_read_only(id, path)?;
+    let damaged = || io::Error::new(io::ErrorKind::InvalidData, format!("table {} is damaged", path.display()));
+
+    let len = segment.len;
+    if len < segment.data_start() + TRAILER_LEN {
+      return Err(damaged());
+    }
+    let mut trailer = ReadAt::new(segment.f.as_ref(), len - TRAILER_LEN);
+    let end = trailer.read_u64::<LittleEndian>()?;
+    let mut magic = [0u8; 8];
+    trailer.read_exact(&mut magic)?;
+    if magic != *TABLE_MAGIC || end < segment.data_start() || end >= len - TRAILER_LEN {
+      return Err(damaged());
+    }
+
+    let record = segment.read_record(end)?;
+    if record.kind != RecordKind::Meta || record.key != TABLE_INDEX {
+      return Err(damaged());
+    }
+    let body = match (record.flags & ENCRYPTED != 0, cipher) {
+      (false, _) => record.value,
+      (true, Some(cipher)) => cipher
+        .open_bytes(TABLE_MAGIC, record.value)
+        .ok_or_else(|| AuthenticationError { segment: id, offset: end }.into_io_error())?,
+      (true, None) => {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidInput,
+          "the store is encrypted; open it with ActionKV::open_encrypted()",
+        ))
+      },
+    };
+    let index: TableIndex = bincode::deserialize(&body)
+      .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    if index.blocks.is_empty() {
+      return Err(damaged());
+    }
+
+    segment.bloom = index.bloom;
+    Ok(Table { segment, blocks: index.blocks, last: index.last, end })
+  }
+
+  fn first(&self) -> &ByteStr {
+    &self.blocks[0].0
+  }
+
+  /// Whether `key` falls between the table's first and last keys.
+  fn covers(&self, key: &ByteStr) -> bool {
+    self.first() <= key && key <= self.last.as_slice()
+  }
+
+  /// Whether any of the table's keys could fall within `start` and `end`.
+  fn overlaps(&self, start: &Bound<ByteString>, end: &Bound<ByteString>) -> bool {
+    let after_start = match start {
+      Bound::Included(key) => self.last >= *key,
+      Bound::Excluded(key) => self.last > *key,
+      Bound::Unbounded => true,
+    };
+    let before_end = match end {
+      Bound::Included(key) => self.first() <= key.as_slice(),
+      Bound::Excluded(key) => self.first() < key.as_slice(),
+      Bound::Unbounded => true,
+    };
+    after_start && before_end
+  }
+
+  /// The offset of the block that `key` would be in.
+  fn seek(&self, key: &ByteStr) -> u64 {
+    match self.blocks.partition_point(|(first, _)| first.as_slice() <= key) {
+      0 => self.blocks[0].1,
+      i => self.blocks[i - 1].1,
+    }
+  }
+
+  fn iter<'a>(&'a self, cipher: Option<&'a Cipher>, start: Bound<ByteString>, end: Bound<ByteString>) -> TableIter<'a> {
+    let offset = match &start {
+      Bound::Included(key) | Bound::Excluded(key) => self.seek(key),
+      Bound::Unbounded => self.segment.data_start(),
+    };
+
+    TableIter {
+      table: self,
+      cipher,
+      f: BufReader::new(ReadAt::new(self.segment.f.as_ref(), offset)),
+      offset,
+      start,
+      end,
+      done: false,
+    }
+  }
+
+  /// The record for `key`, as stored, if the table has one.
+  fn get(&self, cipher: Option<&Cipher>, key: &ByteStr) -> io::Result<Option<Found>> {
+    let bound = Bound::Included(key.to_vec());
+    self.iter(cipher, bound.clone(), bound).next().transpose()
+  }
+}
+
+/// The records of a table within a range of keys, in key order.
+struct TableIter<'a> {
+  table: &'a Table,
+  cipher: Option<&'a Cipher>,
+  f: BufReader<ReadAt<'a>>,
+  offset: u64,
+  start: Bound<ByteString>,
+  end: Bound<ByteString>,
+  done: bool,
+}
+
+impl TableIter<'_> {
+  fn read(&mut self) -> io::Result<Found> {
+    let position = self.table.segment.position(self.offset);
+    let record = ActionKV::process_record(&mut self.f, self.table.segment.version, position)?;
+    self.offset = self.f.stream_position()?;
+
+    let key = match record.flags & ENCRYPTED {
+      0 => record.key.clone(),
+      _ => encryption::unseal(self.cipher, position, Record { key: record.key.clone(), value: record.value.clone(), ..record })?.key,
+    };
+    Ok(Found { key, position, record })
+  }
+}
+
+impl Iterator for TableIter<'_> {
+  type Item = io::Result<Found>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    while !self.done && self.offset < self.table.end {
+      let found = match self.read() {
+        Ok(found) => found,
+        Err(err) => {
+          self.done = true;
+          return Some(Err(err));
+        },
+      };
+
+      let before_start = match &self.start {
+        Bound::Included(key) => found.key < *key,
+        Bound::Excluded(key) => found.key <= *key,
+        Bound::Unbounded => false,
+      };
+      if before_start {
+        continue;
+      }
+      self.done = match &self.end {
+        Bound::Included(key) => found.key > *key,
+        Bound::Excluded(key) => found.key >= *key,
+        Bound::Unbounded => false,
+      };
+      if !self.done {
+        return Some(Ok(found));
+      }
+    }
+    None
+  }
+}
+
+/// Merges sources that are each in key order into one, in key order.
+/// Where sources share a key, the first of them wins and the others'
+/// records are skipped.
+pub(crate) struct Merge<'a> {
+  sources: Vec<std::iter::Fuse<Source<'a>>>,
+  heads: Vec<Option<Found>>,
+}
+
+impl<'a> Merge<'a> {
+  fn new(sources: Vec<Source<'a>>) -> Self {
+    let heads = sources.iter().map(|_| None).collect();
+    Merge { sources: sources.into_iter().map(Iterator::fuse).collect(), heads }
+  }
+}
+
+impl Iterator for Merge<'_> {
+  type Item = io::Result<Found>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    for (head, source) in self.heads.iter_mut().zip(&mut self.sources) {
+      if head.is_none() {
+        match source.next() {
+          Some(Ok(found)) => *head = Some(found),
+          Some(Err(err)) => return Some(Err(err)),
+          None => {},
+        }
+      }
+    }
+
+    // `min_by_key()` returns the first of equal keys
+    let first = self
+      .heads
+      .iter()
+      .enumerate()
+      .filter_map(|(i, head)| head.as_ref().map(|found| (i, &found.key)))
+      .min_by_key(|(_, key)| *key)
+      .map(|(i, _)| i)?;
+    let found = self.heads[first].take().unwrap();
+    for head in &mut self.heads {
+      if head.as_ref().is_some_and(|other| other.key == found.key) {
+        *head = None;
+      }
+    }
+    Some(Ok(found))
+  }
+}
+
+/// Writes a table, record by record, in key order.
+struct TableWriter {
+  id: u32,
+  path: PathBuf,
+  f: BufWriter<File>,
+  offset: u64,
+  blocks: Vec<(ByteString, u64)>,
+  last: ByteString,
+
+  /// The false positive rate of the table's bloom filter and the keys to
+  /// go in it, if filters are enabled.
+  bloom: Option<(f64, Vec<ByteString>)>,
+}
+
+impl TableWriter {
+  fn create(dir: &Path, id: u32, bloom: Option<f64>) -> io::Result<Self> {
+    let path = table_path(dir, id);
+    let mut f = BufWriter::new(File::create(&path)?);
+    let offset = segment::write_file_header(&mut f)?;
+
+    Ok(TableWriter {
+      id,
+      path,
+      f,
+      offset,
+      blocks: Vec::new(),
+      last: ByteString::new(),
+      bloom: bloom.map(|rate| (rate, Vec::new())),
+    })
+  }
+
+  /// Adds `record`, as stored, for `key`, which must come after every
+  /// key added so far.
+  fn add(&mut self, key: &ByteStr, record: &Record) -> io::Result<()> {
+    if self.blocks.last().is_none_or(|(_, start)| self.offset - start >= BLOCK_SIZE) {
+      self.blocks.push((key.to_vec(), self.offset));
+    }
+    self.offset += ActionKV::copy_record(&mut self.f, record)?;
+    self.last = key.to_vec();
+    if let Some((_, keys)) = &mut self.bloom {
+      keys.push(key.to_vec());
+    }
+    Ok(())
+  }
+
+  fn is_empty(&self) -> bool {
+    self.blocks.is_empty()
+  }
+
+  /// Writes the index and trailer, syncs the file and opens it as a
+  /// table.
+  fn finish(mut self, cipher: Option<&Cipher>) -> io::Result<Table> {
+    let bloom = self.bloom.map(|(rate, keys)| {
+      let mut filter = BloomFilter::new(keys.len() as u64, rate);
+      for key in &keys {
+        filter.insert(key);
+      }
+      filter
+    });
+    let index = TableIndex { blocks: self.blocks, last: self.last, bloom };
+    let mut body = bincode::serialize(&index)
+      .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let mut flags = 0;
+    if let Some(cipher) = cipher {
+      body = cipher.seal_bytes(TABLE_MAGIC, body)?;
+      flags = ENCRYPTED;
+    }
+
+    let end = self.offset;
+    ActionKV::write_record(&mut self.f, FORMAT_VERSION, RecordKind::Meta, flags, None, None, TABLE_INDEX, &body)?;
+    self.f.write_u64::<LittleEndian>(end)?;
+    self.f.write_all(TABLE_MAGIC)?;
+    self.f.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+
+    Table::open(&self.path, self.id, cipher)
+  }
+}
+
+impl ActionKV {
+  /// The engine the store was opened with.
+  pub fn engine(&self) -> Engine {
+    match self.lsm {
+      Some(_) => Engine::Lsm,
+      None => Engine::Log,
+    }
+  }
+
+  /// Fails with `Unsupported` if the store uses the LSM engine, whose
+  /// tables `operation` cannot read.
+  pub(crate) fn check_log_engine(&self, operation: &str) -> io::Result<()> {
+    match self.lsm {
+      Some(_) => Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{} is not supported by the LSM engine", operation),
+      )),
+      None => Ok(()),
+    }
+  }
+
+  /// Fails with the damage that kept an LSM store from loading, until
+  /// `repair()` removes it.
+  pub(crate) fn check_lsm_intact(&self) -> io::Result<()> {
+    match self.lsm.as_ref().and_then(|lsm| lsm.damage.first()) {
+      Some(problem) => Err(problem.clone().into_io_error()),
+      None => Ok(()),
+    }
+  }
+
+  /// Rebuilds the memtable from the write-ahead log as the store is
+  /// opened. A record cut short or damaged at the end of the log, by a
+  /// write that never finished, is truncated away unless the store is
+  /// read-only; the damaged one is copied to the quarantine file first,
+  /// as `repair()` does. Damage anywhere else is kept for `repair()`.
+  pub(crate) fn replay_wal(&mut self) -> io::Result<()> {
+    let now = record::now();
+    let mut index = self.index.empty_like();
+    let mut deleted = BTreeMap::new();
+    let mut seq = self.lsm.as_ref().map_or(0, |lsm| lsm.flushed_seq);
+
+    let problems = self.scan_from(self.first_position(), true, |position, record| {
+      seq = seq.max(record.version());
+      match record.kind {
+        RecordKind::Value if !record.is_expired(now) => {
+          deleted.remove(&record.key);
+          index.insert(record.key, Entry { position, expires_at: record.expires_at });
+        },
+        RecordKind::Value | RecordKind::Tombstone => {
+          index.remove(&record.key);
+          deleted.insert(record.key, position);
+        },
+        RecordKind::Commit | RecordKind::Meta => {},
+      }
+    })?;
+    let end = self.segments[0].f.len()?;
+    let (tail, damage): (Vec<Corruption>, Vec<Corruption>) =
+      problems.into_iter().partition(|problem| problem.offset() + problem.size() == end);
+    if let Some(problem) = damage.first() {
+      let problem = problem.clone();
+      if let Some(lsm) = &mut self.lsm {
+        lsm.damage = damage;
+      }
+      return Err(problem.into_io_error());
+    }
+    if let (Some(problem), false) = (tail.first(), self.read_only) {
+      if let Corruption::Checksum { .. } = problem {
+        let quarantine = self.store_path("quarantine");
+        self.quarantine(&tail, &quarantine)?;
+      }
+      let offset = problem.offset();
+      let wal = self.active();
+      wal.f.truncate(offset)?;
+      wal.len = offset;
+    }
+
+    self.index = index;
+    if let Some(lsm) = &mut self.lsm {
+      lsm.deleted = deleted;
+    }
+    self.seq = Some(seq);
+    self.loaded = true;
+    Ok(())
+  }
+
+  /// Notes that `key` was inserted into the memtable.
+  pub(crate) fn memtable_insert(&mut self, key: &ByteStr) {
+    if let Some(lsm) = &mut self.lsm {
+      lsm.deleted.remove(key);
+    }
+  }
+
+  /// Notes that `key` was deleted by the tombstone at `position`.
+  pub(crate) fn memtable_delete(&mut self, key: &ByteStr, position: Position) {
+    if let Some(lsm) = &mut self.lsm {
+      lsm.deleted.insert(key.to_vec(), position);
+    }
+  }
+
+  /// Flushes the memtable and merges tables down the levels if the
+  /// write-ahead log has reached the memtable size.
+  pub(crate) fn flush_if_full(&mut self) -> io::Result<()> {
+    let full = match &self.lsm {
+      Some(lsm) => {
+        let wal = &self.segments[0];
+        wal.len - wal.data_start() >= lsm.memtable_size
+      },
+      None => false,
+    };
+    if full {
+      self.flush_memtable()?;
+      self.merge_levels()?;
+    }
+    Ok(())
+  }
+
+  /// Writes the memtable out as a table in level 0, then empties it and
+  /// the write-ahead log.
+  fn flush_memtable(&mut self) -> io::Result<()> {
+    let ActionKV { path, segments, index, lsm, cipher, seq, .. } = self;
+    let lsm = match lsm {
+      Some(lsm) => lsm,
+      None => return Ok(()),
+    };
+    let wal = &mut segments[0];
+
+    let mut keys: Vec<(&ByteString, Position)> = index
+      .iter()
+      .map(|(key, entry)| (key, entry.position))
+      .chain(lsm.deleted.iter().map(|(key, position)| (key, *position)))
+      .collect();
+    if !keys.is_empty() {
+      keys.sort_unstable_by(|a, b| a.0.cmp(b.0));
+
+      let mut writer = TableWriter::create(path, lsm.next_id, lsm.bloom)?;
+      for (key, position) in keys {
+        writer.add(key, &wal.read_record(position.offset)?)?;
+      }
+      lsm.levels[0].push(writer.finish(cipher.as_ref())?);
+      lsm.next_id += 1;
+    }
+
+    lsm.flushed_seq = seq.unwrap_or(lsm.flushed_seq);
+    write_manifest(path, &lsm.manifest())?;
+
+    // The records are safe in the table once the manifest lists it
+    let start = wal.data_start();
+    wal.f.truncate(start)?;
+    wal.len = start;
+    index.clear();
+    lsm.deleted.clear();
+    self.unsynced_writes = 0;
+    Ok(())
+  }
+
+  /// Merges tables down the levels until level 0 has fewer than
+  /// `L0_TABLES` tables and every other level is within its limit.
+  fn merge_levels(&mut self) -> io::Result<()> {
+    loop {
+      let lsm = match &self.lsm {
+        Some(lsm) => lsm,
+        None => return Ok(()),
+      };
+
+      let (level, picked) = match lsm.levels[0].len() >= L0_TABLES {
+        true => (0, (0..lsm.levels[0].len()).collect::<Vec<_>>()),
+        false => {
+          let level = (1..lsm.levels.len()).find(|&n| level_size(&lsm.levels[n]) > level_limit(n));
+          match level {
+            Some(level) => {
+              let tables = &lsm.levels[level];
+              let cursor = &lsm.cursors[level];
+              let i = tables.iter().position(|table| table.first() > cursor.as_slice()).unwrap_or(0);
+              (level, vec![i])
+            },
+            None => return Ok(()),
+          }
+        },
+      };
+
+      // The tables in the next level that overlap the picked ones go
+      // into the merge too, so that the level stays free of overlaps
+      let next = level + 1;
+      let tables = &lsm.levels[level];
+      let first = picked.iter().map(|&i| tables[i].first()).min().unwrap_or_default().to_vec();
+      let last = picked.iter().map(|&i| &tables[i].last).max().cloned().unwrap_or_default();
+      let (start, end) = (Bound::Included(first), Bound::Included(last.clone()));
+      let overlapping = match lsm.levels.get(next) {
+        Some(lower) => (0..lower.len()).filter(|&i| lower[i].overlaps(&start, &end)).collect(),
+        None => Vec::new(),
+      };
+
+      self.merge_tables(vec![(level, picked), (next, overlapping)], next)?;
+      if let Some(lsm) = &mut self.lsm {
+        lsm.cursors[level] = last;
+      }
+    }
+  }
+
+  /// Merges the tables at `picked`, given as a level and indexes into
+  /// it, highest precedence first, into new tables in level `out`. Every
+  /// table already in `out` that overlaps the others must be among them.
+  fn merge_tables(&mut self, picked: Vec<(usize, Vec<usize>)>, out: usize) -> io::Result<()> {
+    let ActionKV { path, lsm, cipher, .. } = self;
+    let lsm = match lsm {
+      Some(lsm) => lsm,
+      None => return Ok(()),
+    };
+    let cipher = cipher.as_ref();
+    while lsm.levels.len() <= out {
+      lsm.levels.push(Vec::new());
+      lsm.cursors.push(ByteString::new());
+    }
+
+    // Below the lowest level with anything in it, there is nothing left
+    // for a delete to hide
+    let bottom = lsm.levels[out + 1..].iter().all(Vec::is_empty);
+    let now = record::now();
+    let mut next_id = lsm.next_id;
+    let mut written = Vec::new();
+    {
+      let tables = picked
+        .iter()
+        .map(|(level, indexes)| (*level, indexes.iter().map(|&i| &lsm.levels[*level][i]).collect()))
+        .collect();
+      let merge = Merge::new(lsm.sources(tables, cipher, &Bound::Unbounded, &Bound::Unbounded));
+
+      let mut writer: Option<TableWriter> = None;
+      for found in merge {
+        let found = found?;
+        let dropped = found.record.kind == RecordKind::Tombstone || found.record.is_expired(now);
+        if bottom && dropped {
+          continue;
+        }
+
+        if writer.as_ref().is_some_and(|writer| writer.offset >= TABLE_SIZE) {
+          written.push(writer.take().unwrap().finish(cipher)?);
+        }
+        let current = match &mut writer {
+          Some(writer) => writer,
+          None => {
+            next_id += 1;
+            writer.insert(TableWriter::create(path, next_id - 1, lsm.bloom)?)
+          },
+        };
+        current.add(&found.key, &found.record)?;
+      }
+      if let Some(writer) = writer.filter(|writer| !writer.is_empty()) {
+        written.push(writer.finish(cipher)?);
+      }
+    }
+
+    let mut removed = Vec::new();
+    for (level, mut indexes) in picked {
+      indexes.sort_unstable();
+      for i in indexes.into_iter().rev() {
+        removed.push(lsm.levels[level].remove(i).segment.path);
+      }
+    }
+    let level = &mut lsm.levels[out];
+    level.extend(written);
+    level.sort_by(|a, b| a.first().cmp(b.first()));
+    lsm.next_id = next_id;
+    write_manifest(path, &lsm.manifest())?;
+
+    for path in removed {
+      fs::remove_file(path)?;
+    }
+    Ok(())
+  }
+
+  /// Flushes the memtable and merges every table into the lowest level,
+  /// dropping deletes and expired values along the way.
+  pub(crate) fn compact_lsm(&mut self) -> io::Result<()> {
+    self.flush_memtable()?;
+
+    let lsm = match &self.lsm {
+      Some(lsm) => lsm,
+      None => return Ok(()),
+    };
+    let picked: Vec<(usize, Vec<usize>)> = lsm
+      .levels
+      .iter()
+      .enumerate()
+      .filter(|(_, tables)| !tables.is_empty())
+      .map(|(level, tables)| (level, (0..tables.len()).collect()))
+      .collect();
+    match picked.last() {
+      Some(&(lowest, _)) => self.merge_tables(picked, lowest.max(1)),
+      None => Ok(()),
+    }
+  }
+
+  /// The latest record for `key` outside `index`, opened: a delete from
+  /// the memtable, or the record from the newest table that has the key.
+  pub(crate) fn lsm_record(&self, key: &ByteStr) -> io::Result<Option<(Position, Record)>> {
+    let lsm = match &self.lsm {
+      Some(lsm) => lsm,
+      None => return Ok(None),
+    };
+    self.check_lsm_intact()?;
+    if let Some(&position) = lsm.deleted.get(key) {
+      return Ok(Some((position, self.record_at(position)?)));
+    }
+
+    let cipher = self.cipher.as_ref();
+    for table in lsm.candidates(key) {
+      if !self.bloom_counters.check(&table.segment, key) {
+        continue;
+      }
+      match table.get(cipher, key)? {
+        Some(found) => {
+          let record = encryption::unseal(cipher, found.position, found.record)?;
+          return Ok(Some((found.position, record)));
+        },
+        None => self.bloom_counters.missed(&table.segment),
+      }
+    }
+    Ok(None)
+  }
+
+  /// The segment with id `id`: the write-ahead log or a table in an LSM
+  /// store, or one of the log's segments otherwise.
+  pub(crate) fn lookup_segment(&self, id: u32) -> io::Result<&Segment> {
+    match &self.lsm {
+      Some(lsm) if id != WAL_ID => Ok(&lsm.table(id)?.segment),
+      _ => segment::lookup(&self.segments, id),
+    }
+  }
+
+  /// The path of the table with id `id`.
+  pub(crate) fn table_path(&self, id: u32) -> PathBuf {
+    table_path(&self.path, id)
+  }
+
+  /// Reads the records of every table for `check()`, counting the intact
+  /// ones in `report`, and adds the damage found, along with the tables
+  /// that could not be opened.
+  pub(crate) fn check_tables(&self, report: &mut RecoveryReport) -> io::Result<()> {
+    let lsm = match &self.lsm {
+      Some(lsm) => lsm,
+      None => return Ok(()),
+    };
+    for table in lsm.levels.iter().flatten() {
+      let segment = &table.segment;
+      let problems = segment.scan_until(segment.data_start(), table.end, true, |_, _| report.records += 1)?;
+      report.problems.extend(problems);
+    }
+    report.problems.extend(lsm.damage.iter().filter(|problem| matches!(problem, Corruption::Table { .. })).cloned());
+    Ok(())
+  }
+
+  /// Rebuilds each table with damage among `problems` from its intact
+  /// records, for `repair()`. The new table takes the old one's id and
+  /// place in its level, and one with nothing left is dropped.
+  pub(crate) fn repair_tables(&mut self, problems: &[Corruption]) -> io::Result<()> {
+    let ActionKV { path, lsm, cipher, .. } = self;
+    let lsm = match lsm {
+      Some(lsm) => lsm,
+      None => return Ok(()),
+    };
+    lsm.damage.clear();
+
+    let mut ids: Vec<u32> = problems.iter().map(Corruption::segment).filter(|&id| id != WAL_ID).collect();
+    ids.sort_unstable();
+    ids.dedup();
+    if ids.is_empty() {
+      return Ok(());
+    }
+    let cipher = cipher.as_ref();
+    for id in ids {
+      // The table is closed before its file is replaced
+      let (level, end) = match lsm.broken.iter().position(|&(_, broken)| broken == id) {
+        Some(i) => (lsm.broken.remove(i).0, None),
+        None => {
+          let (level, i) = lsm
+            .levels
+            .iter()
+            .enumerate()
+            .find_map(|(n, tables)| Some((n, tables.iter().position(|table| table.segment.id == id)?)))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("segment {} does not exist", id)))?;
+          (level, Some(lsm.levels[level].remove(i).end))
+        },
+      };
+
+      let old_path = table_path(path, id);
+      let mut writer = TableWriter::create(path, lsm.next_id, lsm.bloom)?;
+      for (key, record) in salvage(&old_path, id, end, cipher)? {
+        writer.add(&key, &record)?;
+      }
+      let new_path = writer.path.clone();
+      lsm.next_id += 1;
+      if writer.is_empty() {
+        drop(writer);
+        fs::remove_file(&new_path)?;
+        continue;
+      }
+      drop(writer.finish(cipher)?);
+      fs::rename(&new_path, &old_path)?;
+      let table = Table::open(&old_path, id, cipher)?;
+
+      // Level 0 is kept in the order its tables were flushed, which is
+      // the order of their ids
+      let tables = &mut lsm.levels[level];
+      match level {
+        0 => {
+          let i = tables.partition_point(|table| table.segment.id < id);
+          tables.insert(i, table);
+        },
+        _ => {
+          tables.push(table);
+          tables.sort_by(|a, b| a.first().cmp(b.first()));
+        },
+      }
+    }
+    write_manifest(path, &lsm.manifest())?;
+
+    // Tables left with nothing to keep are only deleted once the manifest
+    // no longer lists them
+    remove_stale(path, &lsm.manifest())
+  }
+
+  /// `scan()` for an LSM store.
+  pub(crate) fn lsm_scan<'a, R>(&'a self, range: R) -> Iter<'a>
+  where
+    R: RangeBounds<ByteStr> + 'a,
+  {
+    if index::is_empty_range(&range) {
+      return Iter::merged(Merge::new(Vec::new()), self.cipher.as_ref(), record::now());
+    }
+    let start = range.start_bound().map(<[u8]>::to_vec);
+    let end = range.end_bound().map(<[u8]>::to_vec);
+    self.lsm_iter(self.index.range(range), start, end)
+  }
+
+  /// `prefix()` for an LSM store.
+  pub(crate) fn lsm_prefix<'a>(&'a self, prefix: &'a ByteStr) -> Iter<'a> {
+    let start = Bound::Included(prefix.to_vec());
+    self.lsm_iter(self.index.prefix(prefix), start, prefix_end(prefix))
+  }
+
+  /// Merges `memtable`, the entries of `index` within `start` and `end`,
+  /// with the deletes in the memtable and every table.
+  fn lsm_iter<'a>(&'a self, memtable: Entries<'a>, start: Bound<ByteString>, end: Bound<ByteString>) -> Iter<'a> {
+    let cipher = self.cipher.as_ref();
+    let now = record::now();
+    let lsm = match &self.lsm {
+      Some(lsm) => lsm,
+      None => return Iter::merged(Merge::new(Vec::new()), cipher, now),
+    };
+    if let Err(err) = self.check_lsm_intact() {
+      return Iter::merged(Merge::new(vec![Box::new(std::iter::once(Err(err)))]), cipher, now);
+    }
+
+    let wal = &self.segments[0];
+    let read = move |key: &ByteString, position: Position| -> io::Result<Found> {
+      let record = wal.read_record(position.offset)?;
+      Ok(Found { key: key.clone(), position, record })
+    };
+    // Keys are either live or deleted, never both, so the two parts of
+    // the memtable can go in either order
+    let mut sources: Vec<Source<'a>> = vec![
+      Box::new(memtable.map(move |(key, entry)| read(key, entry.position))),
+      Box::new(lsm.deleted.range((start.clone(), end.clone())).map(move |(key, position)| read(key, *position))),
+    ];
+    let tables = lsm
+      .levels
+      .iter()
+      .enumerate()
+      .map(|(level, tables)| (level, tables.iter().collect()))
+      .collect();
+    sources.extend(lsm.sources(tables, cipher, &start, &end));
+
+    Iter::merged(Merge::new(sources), cipher, now)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{ActionKvError, Result};
+
+  fn store_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("actionkv-lsm-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+  }
+
+  /// Opens the LSM store in `dir`, flushing the memtable after every
+  /// write if `memtable_size` is 1.
+  fn open(dir: &Path, memtable_size: u64) -> ActionKV {
+    let mut store = OpenOptions::new().engine(Engine::Lsm).memtable_size(memtable_size).open(dir).unwrap();
+    store.load().unwrap();
+    store
+  }
+
+  fn lsm(store: &ActionKV) -> &Lsm {
+    store.lsm.as_ref().unwrap()
+  }
+
+  fn level_ids(store: &ActionKV) -> Vec<Vec<u32>> {
+    lsm(store).manifest().levels
+  }
+
+  /// The records of the tables in `level`, in key order, as key and kind.
+  fn level_records(store: &ActionKV, level: usize) -> Vec<(ByteString, RecordKind)> {
+    lsm(store).levels[level]
+      .iter()
+      .flat_map(|table| table.iter(None, Bound::Unbounded, Bound::Unbounded))
+      .map(|found| found.map(|found| (found.key, found.record.kind)))
+      .collect::<io::Result<_>>()
+      .unwrap()
+  }
+
+  fn pairs(store: &ActionKV) -> Vec<(ByteString, ByteString)> {
+    store.iter().map(|pair| pair.map(|pair| (pair.key, pair.value))).collect::<Result<_>>().unwrap()
+  }
+
+  fn flip_bit(path: &Path, at: u64) {
+    let mut bytes = fs::read(path).unwrap();
+    bytes[at as usize] ^= 1;
+    fs::write(path, bytes).unwrap();
+  }
+
+  #[test]
+  fn flushed_memtable_survives_reopen() {
+    let dir = store_dir("flush");
+    let mut store = open(&dir, 64);
+    for i in 0..10 {
+      store.insert(format!("key{}", i).as_bytes(), b"value").unwrap();
+    }
+    store.delete(b"key3").unwrap();
+    assert!(!level_ids(&store)[0].is_empty());
+
+    // The log only holds what was written since the last flush
+    let wal = &store.segments[0];
+    assert!(wal.len - wal.data_start() < 64);
+    drop(store);
+
+    let store = open(&dir, 64);
+    for i in 0..10 {
+      let expected = match i {
+        3 => None,
+        _ => Some(b"value".to_vec()),
+      };
+      assert_eq!(store.get(format!("key{}", i).as_bytes()).unwrap(), expected);
+    }
+    assert_eq!(pairs(&store).len(), 9);
+    drop(store);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn merges_run_down_the_levels() {
+    let dir = store_dir("levels");
+    let mut store = open(&dir, 1);
+    for key in [b"a", b"b", b"c"] {
+      store.insert(key, b"1").unwrap();
+    }
+    assert_eq!(level_ids(&store)[0].len(), 3);
+
+    // The fourth table in level 0 sends them all into level 1
+    store.insert(b"d", b"1").unwrap();
+    assert!(level_ids(&store)[0].is_empty());
+    assert_eq!(level_ids(&store)[1].len(), 1);
+
+    // Then on into level 2, and a new run down to level 1 on top of it
+    store.merge_tables(vec![(1, vec![0]), (2, Vec::new())], 2).unwrap();
+    for key in [b"b", b"e", b"f", b"g"] {
+      store.insert(key, b"2").unwrap();
+    }
+    assert_eq!(level_ids(&store).iter().map(Vec::len).collect::<Vec<_>>(), vec![0, 1, 1]);
+
+    let expected: Vec<(ByteString, ByteString)> = [("a", "1"), ("b", "2"), ("c", "1"), ("d", "1"), ("e", "2"), ("f", "2"), ("g", "2")]
+      .iter()
+      .map(|(key, value)| (key.as_bytes().to_vec(), value.as_bytes().to_vec()))
+      .collect();
+    assert_eq!(pairs(&store), expected);
+    drop(store);
+
+    let store = open(&dir, 1);
+    assert_eq!(level_ids(&store).iter().map(Vec::len).collect::<Vec<_>>(), vec![0, 1, 1]);
+    assert_eq!(pairs(&store), expected);
+    drop(store);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn tombstones_are_dropped_only_at_the_bottom() {
+    let dir = store_dir("tombstones");
+    let mut store = open(&dir, 1);
+    for key in [b"a", b"b", b"c", b"d"] {
+      store.insert(key, b"1").unwrap();
+    }
+    store.merge_tables(vec![(1, vec![0]), (2, Vec::new())], 2).unwrap();
+
+    // Level 2 still holds `a`, so its delete has to stay in level 1
+    store.delete(b"a").unwrap();
+    for key in [b"e", b"f", b"g"] {
+      store.insert(key, b"1").unwrap();
+    }
+    assert!(level_records(&store, 1).contains(&(b"a".to_vec(), RecordKind::Tombstone)));
+    assert_eq!(store.get(b"a").unwrap(), None);
+
+    // Once everything is merged into the bottom, the delete goes with
+    // the value it hid
+    store.compact().unwrap();
+    let bottom = level_records(&store, 2);
+    assert!(bottom.iter().all(|(key, _)| key != b"a"));
+    assert!(bottom.iter().all(|(_, kind)| *kind == RecordKind::Value));
+    assert_eq!(store.get(b"a").unwrap(), None);
+    assert_eq!(pairs(&store).len(), 6);
+    drop(store);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn table_left_out_of_the_manifest_is_redone_from_the_log() {
+    let dir = store_dir("crash");
+    let mut store = open(&dir, DEFAULT_MEMTABLE_SIZE);
+    store.insert(b"a", b"1").unwrap();
+    store.insert(b"b", b"2").unwrap();
+
+    // Write the table for a flush, but stop before the manifest lists it
+    let id = lsm(&store).next_id;
+    let mut writer = TableWriter::create(&dir, id, None).unwrap();
+    for key in [b"a", b"b"] {
+      let position = store.index.get(key).unwrap().position;
+      writer.add(key, &store.segments[0].read_record(position.offset).unwrap()).unwrap();
+    }
+    writer.finish(None).unwrap();
+    drop(store);
+    assert!(table_path(&dir, id).exists());
+
+    let mut store = open(&dir, DEFAULT_MEMTABLE_SIZE);
+    assert!(!table_path(&dir, id).exists());
+    assert_eq!(level_ids(&store), vec![Vec::<u32>::new()]);
+    assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+
+    // The flush can then be done again, with the same id
+    store.flush_memtable().unwrap();
+    assert_eq!(level_ids(&store), vec![vec![id]]);
+    assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+    drop(store);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn scan_takes_the_newest_record() {
+    let dir = store_dir("newest");
+    let mut store = open(&dir, DEFAULT_MEMTABLE_SIZE);
+    for key in [b"k1", b"k2", b"k3", b"k4"] {
+      store.insert(key, b"level 1").unwrap();
+    }
+    store.flush_memtable().unwrap();
+    store.merge_tables(vec![(0, vec![0]), (1, Vec::new())], 1).unwrap();
+
+    store.insert(b"k2", b"level 0").unwrap();
+    store.insert(b"k3", b"level 0").unwrap();
+    store.insert(b"k4", b"level 0").unwrap();
+    store.flush_memtable().unwrap();
+
+    store.insert(b"k3", b"memtable").unwrap();
+    store.delete(b"k4").unwrap();
+
+    let expected: Vec<(ByteString, ByteString)> = [("k1", "level 1"), ("k2", "level 0"), ("k3", "memtable")]
+      .iter()
+      .map(|(key, value)| (key.as_bytes().to_vec(), value.as_bytes().to_vec()))
+      .collect();
+    assert_eq!(pairs(&store), expected);
+    assert_eq!(store.get(b"k4").unwrap(), None);
+    drop(store);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn damaged_wal_tail_is_cut_off() {
+    let dir = store_dir("wal-tail");
+    let mut store = open(&dir, DEFAULT_MEMTABLE_SIZE);
+    store.insert(b"a", b"1").unwrap();
+    let b = store.segments[0].len;
+    store.insert(b"b", b"2").unwrap();
+    drop(store);
+
+    flip_bit(&dir.join(WAL), b + 20);
+    let store = open(&dir, DEFAULT_MEMTABLE_SIZE);
+    assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(store.get(b"b").unwrap(), None);
+    assert_eq!(store.segments[0].len, b);
+    assert!(dir.join("quarantine").exists());
+    drop(store);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn damaged_wal_is_repaired() {
+    let dir = store_dir("wal-repair");
+    let mut store = open(&dir, DEFAULT_MEMTABLE_SIZE);
+    store.insert(b"a", b"1").unwrap();
+    let b = store.segments[0].len;
+    store.insert(b"b", b"2").unwrap();
+    store.insert(b"c", b"3").unwrap();
+    drop(store);
+
+    // The store opens, but refuses to be used until it is repaired
+    flip_bit(&dir.join(WAL), b + 20);
+    let mut store = OpenOptions::new().engine(Engine::Lsm).open(&dir).unwrap();
+    assert!(matches!(store.load(), Err(ActionKvError::Corruption(Corruption::Checksum { .. }))));
+    assert!(matches!(store.get(b"a"), Err(ActionKvError::Corruption(_))));
+    assert!(matches!(store.insert(b"d", b"4"), Err(ActionKvError::Corruption(_))));
+    assert!(store.iter().next().unwrap().is_err());
+
+    let report = store.check().unwrap();
+    assert_eq!(report.records, 2);
+    assert_eq!(report.problems.len(), 1);
+
+    let report = store.repair().unwrap();
+    assert_eq!(report.problems.len(), 1);
+    assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(store.get(b"b").unwrap(), None);
+    assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+    store.insert(b"d", b"4").unwrap();
+    assert!(store.check().unwrap().is_clean());
+    drop(store);
+
+    let store = open(&dir, DEFAULT_MEMTABLE_SIZE);
+    assert_eq!(pairs(&store).len(), 3);
+    drop(store);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn damaged_tables_are_rebuilt() {
+    let dir = store_dir("table-repair");
+    let mut store = open(&dir, 1);
+    store.insert(b"a", b"1").unwrap();
+    store.insert(b"b", b"2").unwrap();
+    let mut batch = crate::WriteBatch::new();
+    batch.insert(b"c", b"3").insert(b"d", b"4");
+    store.write_batch(&batch).unwrap();
+    let ids = level_ids(&store)[0].clone();
+    assert_eq!(ids.len(), 3);
+    let d = {
+      let table = &lsm(&store).levels[0][2];
+      table.iter(None, Bound::Included(b"d".to_vec()), Bound::Unbounded).next().unwrap().unwrap().position.offset
+    };
+    drop(store);
+
+    // The trailer of one table, and a record in another
+    let b_path = table_path(&dir, ids[1]);
+    let len = fs::metadata(&b_path).unwrap().len();
+    flip_bit(&b_path, len - 1);
+    flip_bit(&table_path(&dir, ids[2]), d + 20);
+
+    let mut store = OpenOptions::new().engine(Engine::Lsm).open(&dir).unwrap();
+    assert!(matches!(store.load(), Err(ActionKvError::Corruption(Corruption::Table { .. }))));
+    assert!(store.get(b"a").is_err());
+
+    let report = store.check().unwrap();
+    let mut found: Vec<(u32, bool)> = report
+      .problems
+      .iter()
+      .map(|problem| (problem.segment(), matches!(problem, Corruption::Table { .. })))
+      .collect();
+    found.sort_unstable();
+    assert_eq!(found, vec![(ids[1], true), (ids[2], false)]);
+
+    store.repair().unwrap();
+    assert_eq!(level_ids(&store)[0], ids);
+    assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+    assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+    assert_eq!(store.get(b"d").unwrap(), None);
+    assert!(store.check().unwrap().is_clean());
+    drop(store);
+
+    let store = open(&dir, 1);
+    assert_eq!(pairs(&store).len(), 3);
+    drop(store);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
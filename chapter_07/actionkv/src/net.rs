@@ -0,0 +1,448 @@
+//! Serving a store over TCP.
+//!
+//! Only one process at a time can open a store for writing (see the
+//! `lock` module), so a single `Server` can own the `ActionKV` instead and let other processes reach
+//! it through a `Client`. The server holds the store through a `Handle`,
+//! so requests from different connections read in parallel.
+//!
+//! Each message is a frame made of a `u32` little-endian length followed
+//! by that many bytes of a bincode-encoded `Request` or `Response`. A
+//! client sends one request and waits for its response before sending the
+//! next, and may keep the connection open for as many requests as it
+//! likes. The exception is `Request::Replicate`, which hands the rest of
+//! the connection over to replication (see the `replication` module).
+
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::Bound;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::replication;
+use crate::watch::Change;
+use crate::{ActionKV, ActionKvError, ByteStr, ByteString, Conflict, Handle, KeyValuePair, Position, Result};
+
+/// Frames longer than this are rejected.
+pub const MAX_FRAME_LEN: u32 = 256 * 1024 * 1024;
+
+/// How long `Server::serve()` waits after failing to accept a connection.
+const ACCEPT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Request {
+  Get { key: ByteString },
+  Insert { key: ByteString, value: ByteString },
+  Update { key: ByteString, value: ByteString },
+  Delete { key: ByteString },
+
+  /// The conditional writes of the `conditional` module. A write that
+  /// finds the key at another version is answered with `Conflict`.
+  GetWithVersion { key: ByteString },
+  CompareAndSwap { key: ByteString, expected_version: u64, value: ByteString },
+  InsertIfAbsent { key: ByteString, value: ByteString },
+  DeleteIfVersion { key: ByteString, expected_version: u64 },
+
+  /// Live pairs from `start` up to but not including `end`, in key order.
+  /// Without an `end`, the scan runs to the last key.
+  Scan { start: ByteString, end: Option<ByteString> },
+
+  /// Streams changes from `from`, a position in the server's log and the
+  /// digest sent with it, or from the start of the log. Answered with
+  /// `Changes` and `Reset` until the connection closes.
+  Replicate { from: Option<(Position, u32)> },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+  Done,
+  Value(Option<ByteString>),
+  Pairs(Vec<KeyValuePair>),
+  Versioned(Option<(ByteString, u64)>),
+
+  /// The version of the key that was written.
+  Version(u64),
+  Conflict { expected: Option<u64>, actual: Option<u64> },
+  Error(String),
+
+  /// The next changes in the log, and where to replicate from after
+  /// them.
+  Changes { changes: Vec<Change>, position: Position, digest: u32 },
+
+  /// The log is being replayed from the start, so everything replicated
+  /// so far should be dropped.
+  Reset,
+}
+
+pub fn write_frame<W: Write, T: Serialize>(f: &mut W, message: &T) -> io::Result<()> {
+  let payload = bincode::serialize(message)
+    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+  if payload.len() > MAX_FRAME_LEN as usize {
+    return Err(io::Error::new(io::ErrorKind::InvalidInput, "message too large"));
+  }
+
+  f.write_u32::<LittleEndian>(payload.len() as u32)?;
+  f.write_all(&payload)?;
+  f.flush()
+}
+
+/// Reads one frame. Returns `None` if the peer closed the connection
+/// between frames.
+pub fn read_frame<R: Read, T: DeserializeOwned>(f: &mut R) -> io::Result<Option<T>> {
+  let len = match f.read_u32::<LittleEndian>() {
+    Ok(len) => len,
+    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+    Err(err) => return Err(err),
+  };
+  if len > MAX_FRAME_LEN {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      format!("frame of {} bytes is too large", len),
+    ));
+  }
+
+  // The buffer only grows as bytes arrive, so a peer cannot make us
+  // allocate a large frame just by announcing one
+  let mut payload = Vec::new();
+  f.take(len as u64).read_to_end(&mut payload)?;
+  if payload.len() < len as usize {
+    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "frame cut short"));
+  }
+
+  bincode::deserialize(&payload)
+    .map(Some)
+    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Owns a store and answers requests from any number of connections,
+/// one thread per connection. Reads are served in parallel; writes are
+/// applied one at a time.
+#[derive(Debug, Clone)]
+pub struct Server {
+  store: Handle,
+  read_only: bool,
+}
+
+impl Server {
+  /// `store` should already be loaded.
+  pub fn new(store: ActionKV) -> Self {
+    Server::with_handle(Handle::new(store))
+  }
+
+  /// Serves a store that the rest of the process also has a handle to.
+  pub fn with_handle(store: Handle) -> Self {
+    Server { store, read_only: false }
+  }
+
+  /// Refuses inserts, updates and deletes, as a follower must.
+  pub fn read_only(mut self) -> Self {
+    self.read_only = true;
+    self
+  }
+
+  /// Accepts connections for as long as the process runs. A connection
+  /// that cannot be accepted, or that fails part way through, is passed
+  /// to `on_error` with the address of its peer, if that is known, and
+  /// the server carries on.
+  pub fn serve<F>(&self, listener: TcpListener, on_error: F)
+  where
+    F: Fn(Option<SocketAddr>, ActionKvError) + Send + Sync + 'static,
+  {
+    let on_error = Arc::new(on_error);
+    for stream in listener.incoming() {
+      let stream = match stream {
+        Ok(stream) => stream,
+        Err(err) => {
+          on_error(None, err.into());
+          // Errors such as running out of file descriptors tend to
+          // repeat, so give them a moment to clear
+          thread::sleep(ACCEPT_RETRY_INTERVAL);
+          continue;
+        },
+      };
+      let server = self.clone();
+      let on_error = Arc::clone(&on_error);
+      thread::spawn(move || {
+        let peer = stream.peer_addr().ok();
+        if let Err(err) = server.handle(stream) {
+          on_error(peer, err);
+        }
+      });
+    }
+  }
+
+  /// Answers requests on `stream` until the client hangs up.
+  pub fn handle(&self, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    while let Some(request) = read_frame(&mut reader)? {
+      if let Request::Replicate { from } = request {
+        return Ok(replication::serve(&self.store, from, &mut writer)?);
+      }
+      let response = self.execute(request);
+      write_frame(&mut writer, &response)?;
+    }
+    Ok(())
+  }
+
+  pub fn execute(&self, request: Request) -> Response {
+    let store = &self.store;
+
+    let writes = matches!(
+      request,
+      Request::Insert { .. }
+        | Request::Update { .. }
+        | Request::Delete { .. }
+        | Request::CompareAndSwap { .. }
+        | Request::InsertIfAbsent { .. }
+        | Request::DeleteIfVersion { .. }
+    );
+    if writes && self.read_only {
+      return Response::Error("the store is read-only".to_string());
+    }
+
+    let result = match request {
+      Request::Get { key } => store.get(&key).map(Response::Value),
+      Request::Insert { key, value } => store.insert(&key, &value).map(|_| Response::Done),
+      Request::Update { key, value } => store.update(&key, &value).map(|_| Response::Done),
+      Request::Delete { key } => store.delete(&key).map(|_| Response::Done),
+      Request::GetWithVersion { key } => store.get_with_version(&key).map(Response::Versioned),
+      Request::CompareAndSwap { key, expected_version, value } => {
+        store.compare_and_swap(&key, expected_version, &value).map(Response::Version)
+      },
+      Request::InsertIfAbsent { key, value } => store.insert_if_absent(&key, &value).map(Response::Version),
+      Request::DeleteIfVersion { key, expected_version } => {
+        store.delete_if_version(&key, expected_version).map(|_| Response::Done)
+      },
+      Request::Scan { start, end } => {
+        let end = match &end {
+          Some(end) => Bound::Excluded(end.as_slice()),
+          None => Bound::Unbounded,
+        };
+        store
+          .scan((Bound::Included(start.as_slice()), end))
+          .map(Response::Pairs)
+      },
+      Request::Replicate { .. } => {
+        return Response::Error("replication needs a connection of its own".to_string())
+      },
+    };
+
+    result.unwrap_or_else(|err| match err {
+      ActionKvError::Conflict(conflict) => Response::Conflict { expected: conflict.expected, actual: conflict.actual },
+      err => Response::Error(err.to_string()),
+    })
+  }
+}
+
+/// A connection to a `Server`.
+#[derive(Debug)]
+pub struct Client {
+  reader: BufReader<TcpStream>,
+  writer: BufWriter<TcpStream>,
+}
+
+impl Client {
+  pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_nodelay(true)?;
+    let reader = BufReader::new(stream.try_clone()?);
+    let writer = BufWriter::new(stream);
+    Ok(Client { reader, writer })
+  }
+
+  /// Sends `request` and waits for the answer. Errors reported by the
+  /// server come back as an `ActionKvError::Io` of kind
+  /// `io::ErrorKind::Other`.
+  pub fn send(&mut self, request: &Request) -> Result<Response> {
+    write_frame(&mut self.writer, request)?;
+
+    match read_frame(&mut self.reader)? {
+      Some(Response::Error(message)) => Err(io::Error::other(message).into()),
+      Some(response) => Ok(response),
+      None => Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "server closed the connection",
+      ).into()),
+    }
+  }
+
+  pub fn get(&mut self, key: &ByteStr) -> Result<Option<ByteString>> {
+    match self.send(&Request::Get { key: key.to_vec() })? {
+      Response::Value(value) => Ok(value),
+      response => Err(unexpected(response)),
+    }
+  }
+
+  pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) -> Result<()> {
+    let request = Request::Insert { key: key.to_vec(), value: value.to_vec() };
+    self.expect_done(&request)
+  }
+
+  pub fn update(&mut self, key: &ByteStr, value: &ByteStr) -> Result<()> {
+    let request = Request::Update { key: key.to_vec(), value: value.to_vec() };
+    self.expect_done(&request)
+  }
+
+  pub fn delete(&mut self, key: &ByteStr) -> Result<()> {
+    self.expect_done(&Request::Delete { key: key.to_vec() })
+  }
+
+  pub fn get_with_version(&mut self, key: &ByteStr) -> Result<Option<(ByteString, u64)>> {
+    match self.send(&Request::GetWithVersion { key: key.to_vec() })? {
+      Response::Versioned(versioned) => Ok(versioned),
+      response => Err(unexpected(response)),
+    }
+  }
+
+  /// See `ActionKV::compare_and_swap()`. A conflict comes back as an
+  /// error carrying a `Conflict`.
+  pub fn compare_and_swap(&mut self, key: &ByteStr, expected_version: u64, value: &ByteStr) -> Result<u64> {
+    let request = Request::CompareAndSwap { key: key.to_vec(), expected_version, value: value.to_vec() };
+    self.expect_version(key, &request)
+  }
+
+  pub fn insert_if_absent(&mut self, key: &ByteStr, value: &ByteStr) -> Result<u64> {
+    let request = Request::InsertIfAbsent { key: key.to_vec(), value: value.to_vec() };
+    self.expect_version(key, &request)
+  }
+
+  pub fn delete_if_version(&mut self, key: &ByteStr, expected_version: u64) -> Result<()> {
+    let request = Request::DeleteIfVersion { key: key.to_vec(), expected_version };
+    match self.send(&request)? {
+      Response::Done => Ok(()),
+      Response::Conflict { expected, actual } => Err(ActionKvError::Conflict(Conflict { key: key.to_vec(), expected, actual })),
+      response => Err(unexpected(response)),
+    }
+  }
+
+  pub fn scan(&mut self, start: &ByteStr, end: Option<&ByteStr>) -> Result<Vec<KeyValuePair>> {
+    let request = Request::Scan { start: start.to_vec(), end: end.map(|end| end.to_vec()) };
+    match self.send(&request)? {
+      Response::Pairs(pairs) => Ok(pairs),
+      response => Err(unexpected(response)),
+    }
+  }
+
+  fn expect_version(&mut self, key: &ByteStr, request: &Request) -> Result<u64> {
+    match self.send(request)? {
+      Response::Version(version) => Ok(version),
+      Response::Conflict { expected, actual } => Err(ActionKvError::Conflict(Conflict { key: key.to_vec(), expected, actual })),
+      response => Err(unexpected(response)),
+    }
+  }
+
+  fn expect_done(&mut self, request: &Request) -> Result<()> {
+    match self.send(request)? {
+      Response::Done => Ok(()),
+      response => Err(unexpected(response)),
+    }
+  }
+}
+
+fn unexpected(response: Response) -> ActionKvError {
+  io::Error::new(
+    io::ErrorKind::InvalidData,
+    format!("unexpected response {:?}", response),
+  ).into()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{MemoryStorage, OpenOptions};
+
+  fn start_server() -> SocketAddr {
+    let mut store = OpenOptions::new().ordered(true).open_storage(MemoryStorage::new()).unwrap();
+    store.load().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::new(store);
+    thread::spawn(move || server.serve(listener, |_, _| {}));
+    addr
+  }
+
+  #[test]
+  fn round_trip() {
+    let mut client = Client::connect(start_server()).unwrap();
+
+    assert_eq!(client.get(b"a").unwrap(), None);
+    client.insert(b"a", b"1").unwrap();
+    client.insert(b"b", b"2").unwrap();
+    client.insert(b"c", b"3").unwrap();
+    client.update(b"b", b"22").unwrap();
+    assert_eq!(client.get(b"b").unwrap(), Some(b"22".to_vec()));
+
+    client.delete(b"a").unwrap();
+    assert_eq!(client.get(b"a").unwrap(), None);
+
+    let pairs = client.scan(b"a", Some(b"c")).unwrap();
+    let pairs: Vec<_> = pairs.into_iter().map(|kv| (kv.key, kv.value)).collect();
+    assert_eq!(pairs, vec![(b"b".to_vec(), b"22".to_vec())]);
+    assert_eq!(client.scan(b"", None).unwrap().len(), 2);
+  }
+
+  #[test]
+  fn conflict() {
+    let mut client = Client::connect(start_server()).unwrap();
+
+    let version = client.insert_if_absent(b"k", b"1").unwrap();
+    let (value, current) = client.get_with_version(b"k").unwrap().unwrap();
+    assert_eq!((value, current), (b"1".to_vec(), version));
+
+    match client.insert_if_absent(b"k", b"2") {
+      Err(ActionKvError::Conflict(conflict)) => {
+        assert_eq!(conflict.key, b"k");
+        assert_eq!(conflict.expected, None);
+        assert_eq!(conflict.actual, Some(version));
+      },
+      other => panic!("expected a conflict, got {:?}", other),
+    }
+
+    let next = client.compare_and_swap(b"k", version, b"3").unwrap();
+    match client.compare_and_swap(b"k", version, b"4") {
+      Err(ActionKvError::Conflict(conflict)) => {
+        assert_eq!(conflict.expected, Some(version));
+        assert_eq!(conflict.actual, Some(next));
+      },
+      other => panic!("expected a conflict, got {:?}", other),
+    }
+    assert!(matches!(client.delete_if_version(b"k", version), Err(ActionKvError::Conflict(_))));
+    client.delete_if_version(b"k", next).unwrap();
+    assert_eq!(client.get(b"k").unwrap(), None);
+  }
+
+  #[test]
+  fn read_only_server_refuses_writes() {
+    let mut store = ActionKV::in_memory().unwrap();
+    store.load().unwrap();
+    let server = Server::new(store).read_only();
+
+    let response = server.execute(Request::Insert { key: b"a".to_vec(), value: b"1".to_vec() });
+    assert!(matches!(response, Response::Error(_)));
+  }
+
+  #[test]
+  fn announced_frame_length_is_not_trusted() {
+    let mut frame = Vec::new();
+    frame.write_u32::<LittleEndian>(MAX_FRAME_LEN).unwrap();
+    frame.extend_from_slice(b"short");
+
+    let err = read_frame::<_, Request>(&mut frame.as_slice()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+    let mut frame = Vec::new();
+    frame.write_u32::<LittleEndian>(MAX_FRAME_LEN + 1).unwrap();
+    let err = read_frame::<_, Request>(&mut frame.as_slice()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+  }
+}
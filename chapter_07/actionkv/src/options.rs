@@ -0,0 +1,278 @@
+//! Settings used when opening a store.
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::encryption::Cipher;
+use crate::{ActionKV, Compression, Result, Storage, KEY_LEN};
+
+/// The segment size used for a directory opened without
+/// `OpenOptions::segment_size()`.
+pub const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The memtable size used by the LSM engine without
+/// `OpenOptions::memtable_size()`.
+pub const DEFAULT_MEMTABLE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// How a store lays out its records. See `OpenOptions::engine()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Engine {
+  /// An append-only log with every key held in `index`, as in Bitcask.
+  /// Kept in a single file or a directory of segments.
+  #[default]
+  Log,
+
+  /// A log-structured merge tree, which keeps recent writes in memory
+  /// and the rest in sorted tables on disk. Always kept in a directory.
+  /// See the `lsm` module.
+  Lsm,
+}
+
+impl fmt::Display for Engine {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Engine::Log => write!(f, "log"),
+      Engine::Lsm => write!(f, "lsm"),
+    }
+  }
+}
+
+/// Parses `log` or `lsm`.
+impl FromStr for Engine {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "log" => Ok(Engine::Log),
+      "lsm" => Ok(Engine::Lsm),
+      _ => Err(format!("unknown engine {:?}", s)),
+    }
+  }
+}
+
+/// When appended records are forced out to the disk with `sync_data`.
+///
+/// Until a record is synced it may only live in the OS's page cache, and
+/// can be lost if the machine loses power even though the write call
+/// returned successfully.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+  /// Sync after every write. The safest and the slowest.
+  Always,
+
+  /// Sync from a background thread, once per interval, if anything has
+  /// been written since the last sync. See the `flusher` module.
+  Interval(Duration),
+
+  /// Sync after every N writes.
+  EveryWrites(u32),
+
+  /// Leave it to the OS.
+  #[default]
+  Never,
+}
+
+impl fmt::Display for SyncPolicy {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      SyncPolicy::Always => write!(f, "always"),
+      SyncPolicy::Interval(interval) => write!(f, "{}ms", interval.as_millis()),
+      SyncPolicy::EveryWrites(n) => write!(f, "{}writes", n),
+      SyncPolicy::Never => write!(f, "never"),
+    }
+  }
+}
+
+/// Parses `always`, `never`, an interval such as `100ms` or `2s`, or a
+/// write count such as `50writes`.
+impl FromStr for SyncPolicy {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || format!("invalid sync policy {:?}", s);
+
+    let policy = match s {
+      "always" => SyncPolicy::Always,
+      "never" => SyncPolicy::Never,
+      _ if s.ends_with("writes") => {
+        let n = s.trim_end_matches("writes").parse().map_err(|_| invalid())?;
+        SyncPolicy::EveryWrites(n)
+      },
+      _ if s.ends_with("ms") => {
+        let ms = s.trim_end_matches("ms").parse().map_err(|_| invalid())?;
+        SyncPolicy::Interval(Duration::from_millis(ms))
+      },
+      _ if s.ends_with('s') => {
+        let secs = s.trim_end_matches('s').parse().map_err(|_| invalid())?;
+        SyncPolicy::Interval(Duration::from_secs(secs))
+      },
+      _ => return Err(invalid()),
+    };
+
+    Ok(policy)
+  }
+}
+
+/// Options for opening an `ActionKV`, in the style of
+/// `std::fs::OpenOptions`. `ActionKV::open()` uses the defaults.
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+  pub(crate) sync: SyncPolicy,
+  pub(crate) ordered: bool,
+  pub(crate) segment_size: Option<u64>,
+  pub(crate) compact_after: usize,
+  pub(crate) compression: Compression,
+  pub(crate) cipher: Option<Cipher>,
+  pub(crate) bloom_filter: Option<f64>,
+  pub(crate) read_only: bool,
+  pub(crate) follow: bool,
+  pub(crate) engine: Option<Engine>,
+  pub(crate) memtable_size: Option<u64>,
+}
+
+impl OpenOptions {
+  pub fn new() -> Self {
+    OpenOptions::default()
+  }
+
+  pub fn sync(&mut self, policy: SyncPolicy) -> &mut Self {
+    self.sync = policy;
+    self
+  }
+
+  /// Keep the index sorted by key, which makes `ActionKV::scan()` and
+  /// `ActionKV::prefix()` cheaper at some cost to inserts.
+  pub fn ordered(&mut self, ordered: bool) -> &mut Self {
+    self.ordered = ordered;
+    self
+  }
+
+  /// Keep the store as a directory of segment files, starting a new
+  /// segment once the active one reaches `bytes`. A path that is already
+  /// a directory is opened this way even without this option, using
+  /// `DEFAULT_SEGMENT_SIZE`.
+  pub fn segment_size(&mut self, bytes: u64) -> &mut Self {
+    self.segment_size = Some(bytes);
+    self
+  }
+
+  /// Start a background compaction whenever a segment is sealed and at
+  /// least `segments` sealed segments have built up. 0, the default,
+  /// leaves compaction to `ActionKV::compact()` and
+  /// `ActionKV::start_compaction()`.
+  pub fn compact_after(&mut self, segments: usize) -> &mut Self {
+    self.compact_after = segments;
+    self
+  }
+
+  /// Compress the values of new records, unless a write asks otherwise
+  /// through `WriteOptions`. Records already in the store are read
+  /// whatever this is set to.
+  pub fn compression(&mut self, compression: Compression) -> &mut Self {
+    self.compression = compression;
+    self
+  }
+
+  /// Encrypt every record written with `key`, and refuse to read records
+  /// that do not open with it. See the `encryption` module for what is
+  /// and is not protected. The key cannot be changed later, so keep it
+  /// somewhere safe: a store is unreadable without it.
+  pub fn encryption_key(&mut self, key: &[u8; KEY_LEN]) -> &mut Self {
+    self.cipher = Some(Cipher::new(key));
+    self
+  }
+
+  /// Keep a bloom filter of the keys in each segment, so that lookups
+  /// that read the log skip segments that cannot hold the key. See the
+  /// `bloom` module. `false_positive_rate`, between 0 and 1, is how often
+  /// a filter may send a lookup to a segment for nothing; lower rates
+  /// take more memory. With the LSM engine, each table gets a filter
+  /// instead, which lets `get()` pass over it.
+  pub fn bloom_filter(&mut self, false_positive_rate: f64) -> &mut Self {
+    self.bloom_filter = Some(false_positive_rate);
+    self
+  }
+
+  /// Open an existing store for reading only, sharing its lock with
+  /// other readers instead of holding it exclusively. Nothing is written
+  /// to the store, not even a hint or bloom filter file, and writes fail
+  /// with `PermissionDenied`. See the `lock` module.
+  pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+    self.read_only = read_only;
+    self
+  }
+
+  /// Open an existing store for reading only, as `read_only()` does, but
+  /// without taking its lock at all, so that a writer can still open it.
+  /// This is for following a store while it is written, as `akv_disk
+  /// tail` does with `ActionKV::watch()`. Nothing stops the writer from
+  /// changing the store underneath: `get()` may miss later writes, and
+  /// opening a segmented store can fail if compaction removes a segment
+  /// while it is being opened.
+  pub fn follow(&mut self, follow: bool) -> &mut Self {
+    self.follow = follow;
+    self
+  }
+
+  /// Whether the store is opened for reading only, by `read_only()` or
+  /// `follow()`.
+  pub(crate) fn is_read_only(&self) -> bool {
+    self.read_only || self.follow
+  }
+
+  /// Lay out the store's records with `engine`, `Engine::Log` by
+  /// default. A directory that already holds an LSM store is opened with
+  /// `Engine::Lsm` even without this option.
+  pub fn engine(&mut self, engine: Engine) -> &mut Self {
+    self.engine = Some(engine);
+    self
+  }
+
+  /// With `Engine::Lsm`, flush the memtable to a table once its
+  /// write-ahead log reaches `bytes`. Defaults to
+  /// `DEFAULT_MEMTABLE_SIZE`.
+  pub fn memtable_size(&mut self, bytes: u64) -> &mut Self {
+    self.memtable_size = Some(bytes);
+    self
+  }
+
+  pub fn open(&self, path: &Path) -> Result<ActionKV> {
+    Ok(ActionKV::open_with(path, self)?)
+  }
+
+  /// Opens a store kept in `storage` rather than in a file. Such a store
+  /// is always a single segment, has no hint, quarantine or lock file,
+  /// and cannot be opened read-only, watched, replicated or with the LSM
+  /// engine.
+  pub fn open_storage<S: Storage + 'static>(&self, storage: S) -> Result<ActionKV> {
+    Ok(ActionKV::open_storage_with(Box::new(storage), self)?)
+  }
+}
+
+/// Options for a single insert through `ActionKV::insert_with()`.
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+  pub(crate) ttl: Option<Duration>,
+  pub(crate) compression: Option<Compression>,
+}
+
+impl WriteOptions {
+  pub fn new() -> Self {
+    WriteOptions::default()
+  }
+
+  /// See `ActionKV::insert_with_ttl()`.
+  pub fn ttl(&mut self, ttl: Duration) -> &mut Self {
+    self.ttl = Some(ttl);
+    self
+  }
+
+  /// Compress this value as given, instead of as the store was opened
+  /// with.
+  pub fn compression(&mut self, compression: Compression) -> &mut Self {
+    self.compression = Some(compression);
+    self
+  }
+}
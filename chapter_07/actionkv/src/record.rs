@@ -0,0 +1,180 @@
+//! The on-disk layout of an actionkv file.
+//!
+//! Files written by this version of the library start with a small header:
+//!
+//! ```text
+//! +------------------+---------+
+//! | "ACTKV\0\0\0"    | version |
+//! +------------------+---------+
+//!       8 bytes         u32
+//! ```
+//!
+//! followed by records of the form:
+//!
+//! ```text
+//! +----------+------+-------+---------+---------+-------------+------+-----+-------+
+//! | checksum | kind | flags | key_len | val_len | expires_at? | seq? | key | value |
+//! +----------+------+-------+---------+---------+-------------+------+-----+-------+
+//!    u32       u8     u8       u32       u32        u64          u64
+//! ```
+//!
+//! The checksum is a CRC32 of every byte that follows it in the record.
+//! `expires_at` is only present on records with the `EXPIRES` flag, and
+//! holds the time the record stops being visible, in milliseconds since
+//! the Unix epoch.
+//!
+//! `seq` is only present on records with the `SEQ` flag, which every
+//! insert and delete now has. It is one more than the `seq` of the
+//! insert or delete written before it, and a record keeps it when
+//! compaction copies it. See the `conditional` module.
+//!
+//! A record with the `LZ4` or `ZSTD` flag holds its value compressed, and
+//! `val_len` is the length of the compressed bytes. The checksum covers
+//! the value as stored. See the `compression` module.
+//!
+//! A record with the `ENCRYPTED` flag holds its key and value sealed with
+//! the store's key; see the `encryption` module.
+//!
+//! Records written by `ActionKV::write_batch()` carry the `BATCHED` flag
+//! and are followed by a `Commit` record whose value holds the offset of
+//! the first record in the batch and the number of records in it. Batched
+//! records without a matching commit are ignored when the file is read.
+//!
+//! `Meta` records may only appear at the start of a file, straight after
+//! the header. They describe the file itself rather than holding data;
+//! see the `segment` module.
+//!
+//! Files without the header are treated as version 1, which is the format
+//! used before the header existed: `checksum | key_len | val_len | key |
+//! value`, with the checksum covering only the key and value. Version 1 has
+//! no way to mark a deletion, so an empty value is read as a tombstone.
+
+use std::convert::TryInto;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const MAGIC: &[u8; 8] = b"ACTKV\0\0\0";
+pub const LEGACY_VERSION: u32 = 1;
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Length of the file header, in bytes.
+pub const FILE_HEADER_LEN: u64 = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+  Value,
+  Tombstone,
+  Commit,
+  Meta,
+}
+
+impl RecordKind {
+  pub fn to_byte(self) -> u8 {
+    match self {
+      RecordKind::Value => 0,
+      RecordKind::Tombstone => 1,
+      RecordKind::Commit => 2,
+      RecordKind::Meta => 3,
+    }
+  }
+
+  pub fn from_byte(byte: u8) -> io::Result<Self> {
+    match byte {
+      0 => Ok(RecordKind::Value),
+      1 => Ok(RecordKind::Tombstone),
+      2 => Ok(RecordKind::Commit),
+      3 => Ok(RecordKind::Meta),
+      _ => Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unknown record kind {}", byte),
+      )),
+    }
+  }
+}
+
+/// Set on records that only take effect once their batch commits.
+pub const BATCHED: u8 = 0b0000_0001;
+
+/// Set on records that carry an `expires_at` timestamp.
+pub const EXPIRES: u8 = 0b0000_0010;
+
+/// Set on records whose value is compressed with LZ4.
+pub const LZ4: u8 = 0b0000_0100;
+
+/// Set on records whose value is compressed with zstd.
+pub const ZSTD: u8 = 0b0000_1000;
+
+/// Bits of the `flags` byte that say how the value is compressed.
+pub const COMPRESSED: u8 = LZ4 | ZSTD;
+
+/// Set on records whose key and value are encrypted.
+pub const ENCRYPTED: u8 = 0b0001_0000;
+
+/// Set on records that carry a `seq` sequence number.
+pub const SEQ: u8 = 0b0010_0000;
+
+/// Bits of the `flags` byte that this version understands. Readers reject
+/// records with any other bits set.
+pub const KNOWN_FLAGS: u8 = BATCHED | EXPIRES | COMPRESSED | ENCRYPTED | SEQ;
+
+#[derive(Debug)]
+pub struct Record {
+  pub kind: RecordKind,
+  pub flags: u8,
+  pub key: Vec<u8>,
+  pub value: Vec<u8>,
+  pub expires_at: Option<u64>,
+  pub seq: Option<u64>,
+}
+
+impl Record {
+  pub fn is_batched(&self) -> bool {
+    self.flags & BATCHED != 0
+  }
+
+  /// Whether the record had expired by `now`, in milliseconds since the
+  /// Unix epoch.
+  pub fn is_expired(&self, now: u64) -> bool {
+    is_expired(self.expires_at, now)
+  }
+
+  /// The version of the key that the record wrote: its `seq`, or 0 if it
+  /// was written before records had one.
+  pub fn version(&self) -> u64 {
+    self.seq.unwrap_or(0)
+  }
+}
+
+pub fn is_expired(expires_at: Option<u64>, now: u64) -> bool {
+  matches!(expires_at, Some(expires_at) if expires_at <= now)
+}
+
+/// The current time, in milliseconds since the Unix epoch.
+pub fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|elapsed| elapsed.as_millis() as u64)
+    .unwrap_or(0)
+}
+
+/// The value of a `Commit` record: where its batch starts and how many
+/// records are in it.
+pub fn encode_commit(start: u64, count: u32) -> Vec<u8> {
+  let mut value = Vec::with_capacity(12);
+  value.extend_from_slice(&start.to_le_bytes());
+  value.extend_from_slice(&count.to_le_bytes());
+  value
+}
+
+pub fn decode_commit(value: &[u8]) -> io::Result<(u64, u32)> {
+  if value.len() != 12 {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      format!("commit record of {} bytes", value.len()),
+    ));
+  }
+
+  let start = u64::from_le_bytes(value[..8].try_into().unwrap());
+  let count = u32::from_le_bytes(value[8..].try_into().unwrap());
+  Ok((start, count))
+}
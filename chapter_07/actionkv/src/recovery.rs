@@ -0,0 +1,308 @@
+//! Detecting and repairing damaged files.
+//!
+//! A crash in the middle of `insert` can leave a partial record at the end
+//! of the file, and bad sectors or stray writes can leave records whose
+//! contents no longer match their checksum. `load()` refuses to open such
+//! a file and returns an `ActionKvError::Corruption`;
+//! `ActionKV::check()` describes the damage and `ActionKV::repair()`
+//! removes it.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use crate::lsm::WAL_ID;
+use crate::segment::{self, ReadAt};
+use crate::{ActionKV, Position, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Corruption {
+  /// The segment ends partway through the record that starts at
+  /// `offset`, usually because a write was interrupted.
+  TornTail { segment: u32, offset: u64, len: u64 },
+
+  /// The `len` bytes of the record at `offset` do not match the checksum
+  /// stored alongside them.
+  Checksum { segment: u32, offset: u64, len: u64, expected: u32, actual: u32 },
+
+  /// The table `segment` of a store that uses the LSM engine, `len`
+  /// bytes long, cannot be opened: its trailer or index is damaged.
+  Table { segment: u32, len: u64 },
+}
+
+impl Corruption {
+  pub fn segment(&self) -> u32 {
+    match self {
+      Corruption::TornTail { segment, .. } => *segment,
+      Corruption::Checksum { segment, .. } => *segment,
+      Corruption::Table { segment, .. } => *segment,
+    }
+  }
+
+  pub fn offset(&self) -> u64 {
+    match self {
+      Corruption::TornTail { offset, .. } => *offset,
+      Corruption::Checksum { offset, .. } => *offset,
+      Corruption::Table { .. } => 0,
+    }
+  }
+
+  pub fn position(&self) -> Position {
+    Position { segment: self.segment(), offset: self.offset() }
+  }
+
+  pub fn size(&self) -> u64 {
+    match self {
+      Corruption::TornTail { len, .. } => *len,
+      Corruption::Checksum { len, .. } => *len,
+      Corruption::Table { len, .. } => *len,
+    }
+  }
+
+  /// Returns the `Corruption` carried by `err`, if there is one.
+  pub fn from_io_error(err: &io::Error) -> Option<&Corruption> {
+    err.get_ref().and_then(|inner| inner.downcast_ref::<Corruption>())
+  }
+
+  pub(crate) fn into_io_error(self) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, self)
+  }
+}
+
+impl fmt::Display for Corruption {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    // Segment 0 is a store kept in a single file
+    let at = match self.segment() {
+      0 => format!("offset {}", self.offset()),
+      segment => format!("offset {} of segment {}", self.offset(), segment),
+    };
+
+    match self {
+      Corruption::TornTail { len, .. } => {
+        write!(f, "incomplete record of {} bytes at {}", len, at)
+      },
+      Corruption::Checksum { len, expected, actual, .. } => write!(
+        f,
+        "data corruption in {} byte record at {} ({:08x} != {:08x})",
+        len, at, actual, expected
+      ),
+      Corruption::Table { len, segment } => write!(f, "damaged table of {} bytes in segment {}", len, segment),
+    }
+  }
+}
+
+impl Error for Corruption {}
+
+/// What `ActionKV::check()` found, or what `ActionKV::repair()` removed.
+#[derive(Debug, Default)]
+pub struct RecoveryReport {
+  /// Number of records that were read back intact.
+  pub records: u64,
+
+  /// Every damaged region of the file, in file order.
+  pub problems: Vec<Corruption>,
+
+  /// Where `repair()` copied the bytes it removed from the file.
+  pub quarantine: Option<PathBuf>,
+}
+
+impl RecoveryReport {
+  pub fn is_clean(&self) -> bool {
+    self.problems.is_empty()
+  }
+
+  /// The total number of damaged bytes.
+  pub fn damaged_bytes(&self) -> u64 {
+    self.problems.iter().map(|problem| problem.size()).sum()
+  }
+}
+
+impl fmt::Display for RecoveryReport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "{} intact records", self.records)?;
+    for problem in &self.problems {
+      writeln!(f, "{}", problem)?;
+    }
+    if let Some(path) = &self.quarantine {
+      writeln!(f, "damaged bytes copied to {}", path.display())?;
+    }
+    Ok(())
+  }
+}
+
+impl ActionKV {
+  /// Reads every record in the store and reports any damage, without
+  /// changing it. With the LSM engine, that is the write-ahead log and
+  /// then every table.
+  pub fn check(&self) -> Result<RecoveryReport> {
+    let mut records = 0;
+    let problems = self.scan_recovering(|_, _| records += 1)?;
+
+    let mut report = RecoveryReport { records, problems, quarantine: None };
+    self.check_tables(&mut report)?;
+    Ok(report)
+  }
+
+  /// Removes damaged records from the store and rebuilds `index`.
+  ///
+  /// A torn tail is truncated away. A record with a bad checksum is
+  /// dropped along with everything up to the next record that matches its
+  /// checksum, rather than trusting its own length fields, and the records
+  /// after it are kept. The removed bytes are appended to a `.quarantine` file
+  /// next to the store (or a `quarantine` file inside a segmented one) so
+  /// that nothing is lost for good. A store opened on a `Storage` has
+  /// nowhere to put them, so they are dropped.
+  ///
+  /// With the LSM engine, the write-ahead log is repaired the same way,
+  /// and a damaged table is rebuilt from its intact records. See the
+  /// `lsm` module.
+  pub fn repair(&mut self) -> Result<RecoveryReport> {
+    self.check_writable()?;
+    self.finish_compaction(true)?;
+
+    let mut report = self.check()?;
+    if report.is_clean() {
+      return Ok(report);
+    }
+
+    self.remove_hint()?;
+
+    if self.has_path() {
+      let quarantine = self.store_path("quarantine");
+      self.quarantine(&report.problems, &quarantine)?;
+      report.quarantine = Some(quarantine);
+    }
+
+    // The tables of an LSM store are rebuilt below, and only its log is
+    // repaired here
+    let mut damaged: Vec<u32> = report
+      .problems
+      .iter()
+      .map(|problem| problem.segment())
+      .filter(|&id| self.lsm.is_none() || id == WAL_ID)
+      .collect();
+    damaged.dedup();
+
+    for id in damaged {
+      let problems: Vec<&Corruption> = report
+        .problems
+        .iter()
+        .filter(|problem| problem.segment() == id)
+        .collect();
+      let segment = segment::lookup_mut(&mut self.segments, id)?;
+
+      if let [Corruption::TornTail { offset, .. }] = problems.as_slice() {
+        segment.f.truncate(*offset)?;
+        segment.len = *offset;
+        continue;
+      }
+
+      // Damage in the middle of a segment shifts every record after it,
+      // so the intact records are copied into a fresh file in their
+      // original order. That keeps the history, unlike `compact()`.
+      let mut intact: Vec<u64> = Vec::new();
+      let start = segment.data_start();
+      segment.scan(start, true, |offset, _| intact.push(offset))?;
+
+      segment.rewrite(|segment, f, _| {
+        for offset in intact {
+          let record = segment.read_record(offset)?;
+
+          // Batches have already been resolved by the scan, so the
+          // records are written out individually
+          ActionKV::copy_record(f, &record)?;
+        }
+        Ok(())
+      })?;
+    }
+
+    self.repair_tables(&report.problems)?;
+
+    self.retarget_flusher()?;
+    self.index.clear();
+    self.loaded = false;
+    self.load()?;
+
+    Ok(report)
+  }
+
+  pub(crate) fn quarantine(&self, problems: &[Corruption], path: &Path) -> io::Result<()> {
+    let mut out = OpenOptions::new()
+      .append(true)
+      .create(true)
+      .open(path)?;
+
+    for problem in problems {
+      // A table that cannot be opened is copied whole
+      let damaged = match problem {
+        Corruption::Table { segment, .. } => fs::read(self.table_path(*segment))?,
+        _ => {
+          let segment = self.lookup_segment(problem.segment())?;
+          let mut damaged = Vec::new();
+          ReadAt::new(segment.f.as_ref(), problem.offset())
+            .take(problem.size())
+            .read_to_end(&mut damaged)?;
+          damaged
+        },
+      };
+      out.write_all(&damaged)?;
+    }
+
+    out.sync_all()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{MemoryStorage, OpenOptions, Storage};
+
+  /// Flips `bit` of the byte at `at` within the record of `b`, among the
+  /// records of `a`, `b` and `c`, and checks that only `b` is lost.
+  fn damage_b(at: u64, bit: u8) {
+    let mem = MemoryStorage::new();
+    let mut store = OpenOptions::new().open_storage(mem.clone()).unwrap();
+    store.load().unwrap();
+    store.insert(b"a", b"1").unwrap();
+    let b = mem.len().unwrap();
+    store.insert(b"b", b"2").unwrap();
+    let c = mem.len().unwrap();
+    store.insert(b"c", b"3").unwrap();
+    drop(store);
+
+    let mut bytes = mem.to_vec();
+    bytes[(b + at) as usize] ^= 1 << bit;
+    let mem = MemoryStorage::from_bytes(bytes);
+    let mut store = OpenOptions::new().open_storage(mem).unwrap();
+    assert!(store.load().is_err());
+
+    let report = store.check().unwrap();
+    assert_eq!(report.records, 2);
+    match report.problems.as_slice() {
+      [Corruption::Checksum { segment: 0, offset, len, .. }] => assert_eq!((*offset, *len), (b, c - b)),
+      other => panic!("expected one checksum error, got {:?}", other),
+    }
+
+    let report = store.repair().unwrap();
+    assert_eq!(report.damaged_bytes(), c - b);
+    assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(store.get(b"b").unwrap(), None);
+    assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+    assert!(store.check().unwrap().is_clean());
+  }
+
+  #[test]
+  fn length_past_the_end() {
+    // The top bit of `key_len`
+    damage_b(9, 7);
+  }
+
+  #[test]
+  fn length_into_the_next_record() {
+    // The bottom bit of `val_len`, which makes `b` end inside `c`
+    damage_b(10, 0);
+  }
+}
@@ -0,0 +1,404 @@
+//! Leader/follower replication over TCP.
+//!
+//! A follower keeps a copy of another store, the leader, by reading its
+//! log. It connects to the leader's `Server` and sends
+//! `Request::Replicate` with the last position it applied. From then on
+//! the connection only carries replication: the leader watches its log
+//! from that position (see `ActionKV::watch()`) and sends what it finds
+//! as `Response::Changes` frames, with an empty frame every
+//! `HEARTBEAT_INTERVAL` while nothing is written. The follower applies
+//! each frame to its own store as one batch, syncs it, and records the
+//! leader's position in a `replica` file next to its store (`FILE.replica`
+//! for a single file), so it can pick up where it left off after either
+//! side restarts.
+//!
+//! Each position comes with a CRC32 of the leader's log just before it.
+//! If the follower's position no longer matches the leader's log, because
+//! compaction or repair rewrote it or the follower was pointed at another
+//! leader, the leader sends `Response::Reset`, and the follower deletes
+//! everything before replaying the leader's log from the start.
+//!
+//! Changes travel in the clear, whether or not either store is encrypted.
+
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+
+use crate::net::{read_frame, write_frame, Request, Response};
+use crate::segment;
+use crate::watch::Change;
+use crate::{ActionKV, ActionKvError, ByteString, Handle, Position, Result, WriteBatch};
+
+/// How long the leader waits before telling an idle follower that it is
+/// still there.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The most changes the leader puts in one frame, unless a batch runs
+/// past it: frames only end between batches.
+const MAX_CHANGES: usize = 1000;
+
+const REPLICA_MAGIC: &[u8; 8] = b"AKVREPL\0";
+
+impl ActionKV {
+  /// Whether `position` is still a place in this store's log where
+  /// replication can resume, given the digest the follower was sent with
+  /// it.
+  fn can_resume(&self, position: Position, digest: u32) -> io::Result<bool> {
+    let segment = match segment::lookup(&self.segments, position.segment) {
+      Ok(segment) => segment,
+      Err(_) => return Ok(false),
+    };
+    let len = segment.f.len()?;
+    if position.offset < segment.data_start() || position.offset > len {
+      return Ok(false);
+    }
+
+    Ok(segment.tail_digest(position.offset)? == digest)
+  }
+}
+
+/// The leader's side of a replication connection: sends changes from
+/// `from` to `w` until the follower goes away.
+pub(crate) fn serve<W: Write>(store: &Handle, from: Option<(Position, u32)>, w: &mut W) -> io::Result<()> {
+  let mut from = match from {
+    Some((position, digest)) if store.read().can_resume(position, digest)? => Some(position),
+    _ => None,
+  };
+
+  loop {
+    let mut watch = {
+      let store = store.read();
+      let start = match from {
+        Some(position) => position,
+        None => {
+          write_frame(w, &Response::Reset)?;
+          store.first_position()
+        },
+      };
+      store.watch(b"", start)?
+    };
+    watch.poll_interval(Duration::from_millis(10));
+
+    loop {
+      let mut changes = Vec::new();
+      let deadline = Instant::now() + HEARTBEAT_INTERVAL;
+
+      let result = loop {
+        match watch.poll() {
+          Ok(Some(change)) => {
+            // Every change in a batch but the last resumes from the start
+            // of the batch. Cutting the frame anywhere else would have the
+            // follower apply the batch as two.
+            let ends_batch = change.resume > change.position;
+            changes.push(change);
+            if changes.len() >= MAX_CHANGES && ends_batch {
+              break Ok(());
+            }
+          },
+          Ok(None) if !changes.is_empty() || Instant::now() >= deadline => break Ok(()),
+          Ok(None) => thread::sleep(Duration::from_millis(10)),
+          Err(err) => break Err(err),
+        }
+      };
+
+      // Changes read before compaction caught up with the watch are
+      // still worth sending
+      let (position, digest) = watch.checkpoint_digest()?;
+      if !changes.is_empty() || result.is_ok() {
+        write_frame(w, &Response::Changes { changes, position, digest })?;
+      }
+
+      match result {
+        Ok(()) => continue,
+        Err(ActionKvError::Compacted(_)) => break,
+        Err(err) => return Err(err.into()),
+      }
+    }
+
+    // The follower fell behind a compaction. It hears about it from the
+    // `Reset` that starts the log over.
+    from = None;
+  }
+}
+
+/// Keeps a store up to date with a leader. See the module docs.
+#[derive(Debug, Clone)]
+pub struct Follower {
+  store: Handle,
+  leader: String,
+  state_path: PathBuf,
+  retry_interval: Duration,
+}
+
+impl Follower {
+  /// Follows the leader at `leader`, such as `127.0.0.1:4000`. Nothing
+  /// else should write to `store`; serve it with `Server::read_only()`.
+  pub fn new(store: Handle, leader: &str) -> Self {
+    let state_path = store.read().store_path("replica");
+    Follower {
+      store,
+      leader: leader.to_string(),
+      state_path,
+      retry_interval: Duration::from_secs(1),
+    }
+  }
+
+  /// How long to wait before reconnecting after the connection to the
+  /// leader fails.
+  pub fn retry_interval(&mut self, interval: Duration) -> &mut Self {
+    self.retry_interval = interval;
+    self
+  }
+
+  /// Follows the leader for as long as the process runs, reconnecting
+  /// whenever the connection fails. Each failure is passed to `on_error`
+  /// before waiting to reconnect.
+  pub fn run<F: FnMut(ActionKvError)>(&self, mut on_error: F) -> ! {
+    loop {
+      if let Err(err) = self.follow() {
+        on_error(err);
+      }
+      thread::sleep(self.retry_interval);
+    }
+  }
+
+  /// The address of the leader.
+  pub fn leader(&self) -> &str {
+    &self.leader
+  }
+
+  /// Connects to the leader and applies changes until the connection
+  /// fails.
+  pub fn follow(&self) -> Result<()> {
+    let stream = TcpStream::connect(&self.leader)?;
+    stream.set_nodelay(true)?;
+    // Heartbeats arrive every second, so silence means the leader is gone
+    stream.set_read_timeout(Some(HEARTBEAT_INTERVAL * 10))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    let from = self.read_state()?;
+    write_frame(&mut writer, &Request::Replicate { from })?;
+
+    loop {
+      match read_frame(&mut reader)? {
+        Some(Response::Changes { changes, position, digest }) => {
+          self.apply(changes)?;
+          self.write_state(position, digest)?;
+        },
+        Some(Response::Reset) => self.reset()?,
+        Some(Response::Error(message)) => return Err(io::Error::other(message).into()),
+        Some(response) => {
+          return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected response {:?}", response),
+          ).into())
+        },
+        None => {
+          return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "leader closed the connection",
+          ).into())
+        },
+      }
+    }
+  }
+
+  /// The leader's position that has been applied, if any.
+  pub fn position(&self) -> Result<Option<Position>> {
+    Ok(self.read_state()?.map(|(position, _)| position))
+  }
+
+  fn apply(&self, changes: Vec<Change>) -> io::Result<()> {
+    if changes.is_empty() {
+      return Ok(());
+    }
+
+    let mut batch = WriteBatch::new();
+    for change in changes {
+      match change.value {
+        Some(value) => batch.insert_expiring_at(&change.key, &value, change.expires_at),
+        None => batch.delete(&change.key),
+      };
+    }
+
+    // The position is only recorded once the changes are on disk, so a
+    // crash can at worst apply some of them twice
+    self.store.write_batch(&batch)?;
+    Ok(self.store.sync()?)
+  }
+
+  /// Deletes every key, ready to replay the leader's log from the start.
+  fn reset(&self) -> io::Result<()> {
+    let mut store = self.store.write();
+    let keys: Vec<ByteString> = store.index.keys().cloned().collect();
+
+    let mut batch = WriteBatch::new();
+    for key in &keys {
+      batch.delete(key);
+    }
+    store.write_batch(&batch)?;
+    store.sync()?;
+    drop(store);
+
+    self.remove_state()
+  }
+
+  fn read_state(&self) -> io::Result<Option<(Position, u32)>> {
+    let mut f = match File::open(&self.state_path) {
+      Ok(f) => BufReader::new(f),
+      Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(err) => return Err(err),
+    };
+
+    let mut body = [0u8; 24];
+    match f.read_exact(&mut body) {
+      Ok(()) => {},
+      Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+      Err(err) => return Err(err),
+    }
+    let saved_checksum = f.read_u32::<LittleEndian>().ok();
+    if &body[..8] != REPLICA_MAGIC || saved_checksum != Some(crc32::checksum_ieee(&body)) {
+      return Ok(None);
+    }
+
+    let mut fields = &body[8..];
+    let segment = fields.read_u32::<LittleEndian>()?;
+    let offset = fields.read_u64::<LittleEndian>()?;
+    let digest = fields.read_u32::<LittleEndian>()?;
+    Ok(Some((Position { segment, offset }, digest)))
+  }
+
+  fn write_state(&self, position: Position, digest: u32) -> io::Result<()> {
+    let mut body = Vec::with_capacity(24);
+    body.extend_from_slice(REPLICA_MAGIC);
+    body.write_u32::<LittleEndian>(position.segment)?;
+    body.write_u64::<LittleEndian>(position.offset)?;
+    body.write_u32::<LittleEndian>(digest)?;
+
+    let tmp_path = ActionKV::sibling_path(&self.state_path, "tmp");
+    {
+      let mut f = BufWriter::new(File::create(&tmp_path)?);
+      f.write_all(&body)?;
+      f.write_u32::<LittleEndian>(crc32::checksum_ieee(&body))?;
+      f.flush()?;
+      f.get_ref().sync_all()?;
+    }
+    fs::rename(&tmp_path, &self.state_path)?;
+    ActionKV::sync_parent_dir(&self.state_path)
+  }
+
+  fn remove_state(&self) -> io::Result<()> {
+    match fs::remove_file(&self.state_path) {
+      Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+      _ => Ok(()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::{SocketAddr, TcpListener};
+  use std::path::Path;
+
+  use super::*;
+  use crate::net::Server;
+
+  fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("actionkv-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn open(path: &Path) -> Handle {
+    let mut store = ActionKV::open(path).unwrap();
+    store.load().unwrap();
+    store.into_handle()
+  }
+
+  fn serve(store: Handle) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || Server::with_handle(store).serve(listener, |_, _| {}));
+    addr
+  }
+
+  /// Waits for `done` to hold, failing the test after a few seconds.
+  fn wait_for<F: FnMut() -> bool>(mut done: F) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !done() {
+      assert!(Instant::now() < deadline, "timed out");
+      thread::sleep(Duration::from_millis(10));
+    }
+  }
+
+  #[test]
+  fn follower_catches_up() {
+    let dir = temp_dir("replication");
+    let leader = open(&dir.join("leader.akv"));
+    leader.insert(b"a", b"1").unwrap();
+    leader.insert(b"b", b"2").unwrap();
+    let addr = serve(leader.clone());
+
+    let replica = open(&dir.join("follower.akv"));
+    let mut follower = Follower::new(replica.clone(), &addr.to_string());
+    follower.retry_interval(Duration::from_millis(10));
+    let following = follower.clone();
+    thread::spawn(move || following.run(|_| {}));
+
+    wait_for(|| replica.get(b"b").unwrap().is_some());
+    assert_eq!(replica.get(b"a").unwrap(), Some(b"1".to_vec()));
+
+    let mut batch = WriteBatch::new();
+    batch.insert(b"c", b"3").delete(b"a");
+    leader.write_batch(&batch).unwrap();
+    leader.update(b"b", b"22").unwrap();
+
+    wait_for(|| replica.get(b"b").unwrap() == Some(b"22".to_vec()));
+    assert_eq!(replica.get(b"a").unwrap(), None);
+    assert_eq!(replica.get(b"c").unwrap(), Some(b"3".to_vec()));
+    assert!(follower.position().unwrap().is_some());
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn batches_are_not_split_across_frames() {
+    let dir = temp_dir("replication-frames");
+    let leader = open(&dir.join("leader.akv"));
+    let mut batch = WriteBatch::new();
+    for i in 0..MAX_CHANGES + 500 {
+      batch.insert(format!("key{}", i).as_bytes(), b"value");
+    }
+    leader.insert(b"before", b"1").unwrap();
+    leader.write_batch(&batch).unwrap();
+    let addr = serve(leader);
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = BufWriter::new(stream);
+    write_frame(&mut writer, &Request::Replicate { from: None }).unwrap();
+    assert!(matches!(read_frame(&mut reader).unwrap(), Some(Response::Reset)));
+
+    let mut sizes = Vec::new();
+    while sizes.iter().sum::<usize>() < batch.len() + 1 {
+      match read_frame(&mut reader).unwrap() {
+        Some(Response::Changes { changes, .. }) if !changes.is_empty() => sizes.push(changes.len()),
+        Some(Response::Changes { .. }) => {},
+        other => panic!("unexpected response {:?}", other),
+      }
+    }
+    assert_eq!(sizes, vec![batch.len() + 1]);
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,782 @@
+//! The files that make up a store.
+//!
+//! A store opened on a file keeps every record in that one file. A store
+//! opened on a directory keeps a series of numbered segment files instead
+//! (`0000000001.akv`, `0000000002.akv`, ...). Records are only appended to
+//! the newest segment, the active one. Once it reaches
+//! `OpenOptions::segment_size()` it is sealed and a new segment is
+//! started, and sealed segments do not change again until compaction
+//! merges them.
+//!
+//! A segment written by compaction starts with a `Meta` record named
+//! `merged-from` that holds the id of the first segment merged into it.
+//! If the process stops before the merged segments have been deleted,
+//! they are deleted the next time the store is opened.
+//!
+//! A segment written by compaction or repair may also start with a
+//! `last-seq` record, holding the highest `seq` among the records it
+//! replaced, so that sequence numbers are not handed out again when the
+//! records that used them are dropped.
+
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::bloom::{self, BloomFilter};
+use crate::record::{self, Record, RecordKind, BATCHED, FILE_HEADER_LEN, FORMAT_VERSION, KNOWN_FLAGS, LEGACY_VERSION, MAGIC};
+use crate::storage::{FileStorage, Storage};
+use crate::{ActionKV, ActionKvError, Corruption};
+
+const SEGMENT_EXTENSION: &str = "akv";
+
+/// Key of the `Meta` record that starts a merged segment.
+pub(crate) const MERGED_FROM: &[u8] = b"merged-from";
+
+/// Key of the `Meta` record that holds the highest `seq` a rewritten
+/// segment has seen.
+pub(crate) const LAST_SEQ: &[u8] = b"last-seq";
+
+/// How many bytes at the end of a segment are covered by `tail_digest()`.
+const TAIL_LEN: u64 = 64;
+
+/// Where a record lives: the id of its segment and its offset within it.
+/// A store kept in a single file only has segment 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Position {
+  pub segment: u32,
+  pub offset: u64,
+}
+
+#[derive(Debug)]
+pub(crate) struct Segment {
+  pub id: u32,
+
+  /// Empty for a store opened on a `Storage`.
+  pub path: PathBuf,
+  pub f: Box<dyn Storage>,
+  pub version: u32,
+  pub len: u64,
+
+  /// The keys in the segment, if bloom filters are enabled. See the
+  /// `bloom` module.
+  pub bloom: Option<BloomFilter>,
+}
+
+impl Segment {
+  /// Opens the segment at `path`, creating it if it does not exist.
+  pub fn create(id: u32, path: &Path) -> io::Result<Self> {
+    Segment::open_file(id, path, true)
+  }
+
+  /// Opens the segment at `path`, which must already exist.
+  pub fn open(id: u32, path: &Path) -> io::Result<Self> {
+    Segment::open_file(id, path, false)
+  }
+
+  /// Opens the segment at `path` for reading only. Nothing is written
+  /// to it, not even the header of a file that is still being created.
+  pub fn open_read_only(id: u32, path: &Path) -> io::Result<Self> {
+    let f = File::open(path)?;
+    Segment::from_file(id, path, f, false)
+  }
+
+  fn open_file(id: u32, path: &Path, create: bool) -> io::Result<Self> {
+    let f = open_file(path, create)?;
+    Segment::from_file(id, path, f, true)
+  }
+
+  /// A segment read from `f`, which is open on `path`, and only written
+  /// to if `writable`.
+  pub fn from_file(id: u32, path: &Path, f: File, writable: bool) -> io::Result<Self> {
+    let mut segment = Segment::from_storage(id, Box::new(FileStorage::new(f)), writable)?;
+    segment.path = path.to_path_buf();
+    Ok(segment)
+  }
+
+  /// A segment with no file of its own, for `OpenOptions::open_storage()`.
+  pub fn with_storage(id: u32, f: Box<dyn Storage>) -> io::Result<Self> {
+    Segment::from_storage(id, f, true)
+  }
+
+  fn from_storage(id: u32, mut f: Box<dyn Storage>, writable: bool) -> io::Result<Self> {
+    let version = read_file_header(f.as_mut(), writable)?;
+    let len = f.len()?;
+
+    Ok(Segment { id, path: PathBuf::new(), f, version, len, bloom: None })
+  }
+
+  /// Whether the segment has a file of its own rather than being opened
+  /// on a `Storage`.
+  pub fn has_path(&self) -> bool {
+    !self.path.as_os_str().is_empty()
+  }
+
+  /// Another handle to the segment, which goes on reading the records it
+  /// holds now even if compaction replaces them. See `Storage::try_clone()`.
+  pub fn try_clone(&self) -> io::Result<Self> {
+    Ok(Segment {
+      id: self.id,
+      path: self.path.clone(),
+      f: self.f.try_clone()?,
+      version: self.version,
+      len: self.len,
+      bloom: self.bloom.clone(),
+    })
+  }
+
+  /// The offset of the first record in the segment.
+  pub fn data_start(&self) -> u64 {
+    match self.version {
+      LEGACY_VERSION => 0,
+      _ => FILE_HEADER_LEN,
+    }
+  }
+
+  /// Whether the segment holds any records yet.
+  pub fn is_empty(&self) -> bool {
+    self.len <= self.data_start()
+  }
+
+  pub fn position(&self, offset: u64) -> Position {
+    Position { segment: self.id, offset }
+  }
+
+  /// The current length of the file, which is where the next record
+  /// will go.
+  pub fn end(&mut self) -> io::Result<u64> {
+    self.len = self.f.len()?;
+    Ok(self.len)
+  }
+
+  /// Writes `buf` to the end of the segment and returns the offset it
+  /// was written at. If the write fails, whatever part of `buf` made it
+  /// to the segment is cut off again, so that the next record does not
+  /// land after a partial one that `load()` would stop at.
+  pub fn append(&mut self, buf: &[u8]) -> io::Result<u64> {
+    let offset = match self.f.append(buf) {
+      Ok(offset) => offset,
+      Err(err) => {
+        // The write's error says more than the truncate's would, and if
+        // the truncate fails too, `repair()` can still remove the bytes
+        let _ = self.f.truncate(self.len);
+        return Err(err);
+      },
+    };
+    self.len = offset + buf.len() as u64;
+    Ok(offset)
+  }
+
+  /// Reads the record at `offset`. Reads use positional I/O and leave
+  /// the file's cursor alone, so any number of threads may read from a
+  /// segment at once.
+  pub fn read_record(&self, offset: u64) -> io::Result<Record> {
+    let position = self.position(offset);
+    let mut f = BufReader::new(ReadAt::new(self.f.as_ref(), offset));
+
+    ActionKV::process_record(&mut f, self.version, position)
+  }
+
+  /// Calls `visit` with the offset of each record from `start` on, in
+  /// the order they were written. Without `recover`, stops at the first
+  /// damaged record; with it, steps over damaged records and returns a
+  /// description of each one instead. A damaged record ends where the next
+  /// record that matches its checksum starts, since its own lengths may be
+  /// what was damaged.
+  ///
+  /// A batch that was cut short before its commit record is returned as a
+  /// `TornTail` from the start of the batch, with or without `recover`.
+  /// Nothing in it was visible, so the caller may cut it off.
+  ///
+  /// Batched records are held back until their commit record is read,
+  /// and dropped if it never arrives. Commit and meta records themselves
+  /// are not passed to `visit`.
+  pub fn scan<F>(&self, start: u64, recover: bool, visit: F) -> io::Result<Vec<Corruption>>
+  where
+    F: FnMut(u64, Record),
+  {
+    self.scan_until(start, self.f.len()?, recover, visit)
+  }
+
+  /// `scan()`, stopping at `end` rather than the end of the segment, as
+  /// for the records of a table, which are followed by its index.
+  pub fn scan_until<F>(&self, start: u64, end: u64, recover: bool, mut visit: F) -> io::Result<Vec<Corruption>>
+  where
+    F: FnMut(u64, Record),
+  {
+    let id = self.id;
+    let version = self.version;
+    let mut problems = Vec::new();
+    let mut batch: Vec<(u64, Record)> = Vec::new();
+
+    let mut f = BufReader::new(ReadAt::new(self.f.as_ref(), start));
+    let mut offset = start;
+
+    while offset < end {
+      let position = Position { segment: id, offset };
+      let maybe_record = ActionKV::process_record(&mut f, version, position);
+      let problem = match maybe_record {
+        Ok(record) if record.is_batched() => {
+          batch.push((offset, record));
+          offset = f.stream_position()?;
+          continue;
+        },
+        Ok(record) if record.kind == RecordKind::Commit => {
+          // Anything before `batch_start` belongs to an earlier batch
+          // that was never committed
+          let (batch_start, count) = record::decode_commit(&record.value)?;
+          batch.retain(|(offset, _)| *offset >= batch_start);
+          if batch.len() == count as usize {
+            for (offset, record) in batch.drain(..) {
+              visit(offset, record);
+            }
+          }
+          batch.clear();
+          offset = f.stream_position()?;
+          continue;
+        },
+        Ok(record) if record.kind == RecordKind::Meta => {
+          offset = f.stream_position()?;
+          continue;
+        },
+        Ok(record) => {
+          batch.clear();
+          visit(offset, record);
+          offset = f.stream_position()?;
+          continue;
+        },
+        Err(err) => match (err.kind(), Corruption::from_io_error(&err)) {
+          // A damaged length can make a record seem to run past the end of
+          // the segment, so it is only a torn tail if nothing intact follows
+          (io::ErrorKind::UnexpectedEof, _) => match self.next_intact(offset + 1, end)? {
+            Some(next) => self.damaged(offset, next)?,
+            None => Corruption::TornTail { segment: id, offset, len: end - offset },
+          },
+          (_, Some(&Corruption::Checksum { len, expected, actual, .. })) => {
+            // The checksum does not vouch for the lengths that gave `len`,
+            // so the record after it is only looked for there first
+            let next = match self.is_intact_at(offset + len, end)? {
+              true => offset + len,
+              false => self.next_intact(offset + 1, end)?.unwrap_or(end),
+            };
+            Corruption::Checksum { segment: id, offset, len: next - offset, expected, actual }
+          },
+          _ => return Err(err),
+        },
+      };
+
+      // A batch goes to the OS in one write, so one that was cut short
+      // before its commit record was never written rather than damaged.
+      // The flags of the torn record say whether it was batched, unless
+      // the cut came before them.
+      let torn_batch = match problem {
+        Corruption::TornTail { .. } if version != LEGACY_VERSION => {
+          self.is_batched_at(offset)?.unwrap_or(!batch.is_empty())
+        },
+        _ => false,
+      };
+      if torn_batch {
+        let start = batch.first().map_or(offset, |(offset, _)| *offset);
+        problems.push(Corruption::TornTail { segment: id, offset: start, len: end - start });
+        break;
+      }
+
+      if !recover {
+        return Err(problem.into_io_error());
+      }
+
+      match problem {
+        Corruption::Checksum { offset: bad_offset, len, .. } => {
+          offset = f.seek(SeekFrom::Start(bad_offset + len))?;
+          problems.push(problem);
+        },
+        _ => {
+          problems.push(problem);
+          break;
+        },
+      }
+    }
+
+    Ok(problems)
+  }
+
+  /// Whether the record at `offset` has the `BATCHED` flag, or `None` if
+  /// the segment ends before its flags.
+  fn is_batched_at(&self, offset: u64) -> io::Result<Option<bool>> {
+    let mut header = [0u8; 6];
+    match ReadAt::new(self.f.as_ref(), offset).read_exact(&mut header) {
+      Ok(()) => Ok(Some(header[5] & BATCHED != 0)),
+      Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+      Err(err) => Err(err),
+    }
+  }
+
+  /// The offset of the first intact record at or after `from`, or `None`
+  /// if there is none before `end`.
+  fn next_intact(&self, from: u64, end: u64) -> io::Result<Option<u64>> {
+    for offset in from..end {
+      if self.is_intact_at(offset, end)? {
+        return Ok(Some(offset));
+      }
+    }
+    Ok(None)
+  }
+
+  /// Whether a record that matches its checksum starts at `offset`. The
+  /// header is checked first, so that most offsets are ruled out without
+  /// reading any further.
+  fn is_intact_at(&self, offset: u64, end: u64) -> io::Result<bool> {
+    let header_len = match self.version {
+      LEGACY_VERSION => 12,
+      _ => 14,
+    };
+    if offset + header_len > end {
+      return Ok(false);
+    }
+
+    let mut header = [0u8; 14];
+    let header = &mut header[..header_len as usize];
+    ReadAt::new(self.f.as_ref(), offset).read_exact(header)?;
+    if self.version != LEGACY_VERSION && (RecordKind::from_byte(header[4]).is_err() || header[5] & !KNOWN_FLAGS != 0) {
+      return Ok(false);
+    }
+    let mut lens = &header[header.len() - 8..];
+    let data_len = lens.read_u32::<LittleEndian>()? as u64 + lens.read_u32::<LittleEndian>()? as u64;
+    if offset + header_len + data_len > end {
+      return Ok(false);
+    }
+
+    let mut f = BufReader::new(ReadAt::new(self.f.as_ref(), offset));
+    Ok(ActionKV::process_record(&mut f, self.version, self.position(offset)).is_ok())
+  }
+
+  /// Describes the bytes from `offset` up to `next` as one damaged record,
+  /// whose lengths ran past the end of the segment.
+  fn damaged(&self, offset: u64, next: u64) -> io::Result<Corruption> {
+    let mut bytes = Vec::new();
+    ReadAt::new(self.f.as_ref(), offset).take(next - offset).read_to_end(&mut bytes)?;
+    let (saved, rest) = bytes.split_at(bytes.len().min(4));
+    let mut expected = [0u8; 4];
+    expected[..saved.len()].copy_from_slice(saved);
+    let (expected, actual) = (u32::from_le_bytes(expected), crc32::checksum_ieee(rest));
+    Ok(Corruption::Checksum { segment: self.id, offset, len: next - offset, expected, actual })
+  }
+
+  /// Calls `visit` with the offset of each record from `start` on, and
+  /// the offset just after it, up to the current end of the file. Unlike
+  /// `scan()`, batches are passed through as written. Stops quietly at a
+  /// record that is cut short, as it may still be being written.
+  pub fn read_from<F>(&self, start: u64, mut visit: F) -> io::Result<()>
+  where
+    F: FnMut(u64, u64, Record) -> io::Result<()>,
+  {
+    let mut f = BufReader::new(ReadAt::new(self.f.as_ref(), start));
+    let mut offset = start;
+
+    loop {
+      let position = self.position(offset);
+      let record = match ActionKV::process_record(&mut f, self.version, position) {
+        Ok(record) => record,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+        Err(err) => return Err(err),
+      };
+      let next = f.stream_position()?;
+      visit(offset, next, record)?;
+      offset = next;
+    }
+  }
+
+  /// The `Meta` records at the start of the segment.
+  pub fn meta(&self) -> io::Result<Vec<Record>> {
+    let start = self.data_start();
+    let mut found = Vec::new();
+
+    let position = self.position(start);
+    let mut f = BufReader::new(ReadAt::new(self.f.as_ref(), start));
+    let mut offset = start;
+
+    while offset < self.len {
+      // Damage is left for `check()` and `repair()` to find, so that a
+      // damaged segment can still be opened
+      let record = match ActionKV::process_record(&mut f, self.version, Position { offset, ..position }) {
+        Ok(record) if record.kind == RecordKind::Meta => record,
+        _ => break,
+      };
+      found.push(record);
+      offset = f.stream_position()?;
+    }
+
+    Ok(found)
+  }
+
+  /// The id of the first segment that was merged into this one, if this
+  /// segment was written by compaction.
+  pub fn merged_from(&self) -> io::Result<Option<u32>> {
+    let merged_from = self
+      .meta()?
+      .into_iter()
+      .find(|record| record.key == MERGED_FROM)
+      .and_then(|record| record.value.try_into().ok())
+      .map(u32::from_le_bytes);
+
+    Ok(merged_from)
+  }
+
+  /// The `seq` held by the segment's `last-seq` record, or 0 if it has
+  /// none.
+  pub fn last_seq(&self) -> io::Result<u64> {
+    let last_seq = self
+      .meta()?
+      .into_iter()
+      .find(|record| record.key == LAST_SEQ)
+      .and_then(|record| record.value.try_into().ok())
+      .map(u64::from_le_bytes);
+
+    Ok(last_seq.unwrap_or(0))
+  }
+
+  /// The highest `seq` in the segment, whether on a record or held by
+  /// its `last-seq` record. Damaged records are skipped, so that
+  /// `repair()` can rewrite a segment around them.
+  pub fn max_seq(&self) -> io::Result<u64> {
+    let mut max = self.last_seq()?;
+    self.scan(self.data_start(), true, |_, record| max = max.max(record.version()))?;
+    Ok(max)
+  }
+
+  /// A CRC32 of the last bytes of the segment before `len`, used to tell
+  /// whether a hint file still matches it.
+  pub fn tail_digest(&self, len: u64) -> io::Result<u32> {
+    let start = len.saturating_sub(TAIL_LEN);
+    let mut tail = Vec::with_capacity((len - start) as usize);
+
+    ReadAt::new(self.f.as_ref(), start).take(len - start).read_to_end(&mut tail)?;
+
+    Ok(crc32::checksum_ieee(&tail))
+  }
+
+  /// Replaces the segment with one produced by `fill`, which is handed
+  /// the old segment and the offset of the first record in the new one.
+  /// The new file is written next to the original, synced, then renamed
+  /// over it, so a failure part way through leaves the original
+  /// untouched. Any `Meta` records are carried over, with `last-seq`
+  /// brought up to date, and the new file always uses `FORMAT_VERSION`.
+  ///
+  /// A segment without a path is rebuilt in memory and then written over
+  /// its storage, which is only as safe as the storage is.
+  pub fn rewrite<F>(&mut self, fill: F) -> io::Result<()>
+  where
+    F: FnOnce(&mut Segment, &mut dyn Write, u64) -> io::Result<()>,
+  {
+    let meta = self.meta()?;
+    let last_seq = self.max_seq()?;
+    let mut header = Vec::new();
+    let mut start = write_file_header(&mut header)?;
+    for record in meta.iter().filter(|record| record.key != LAST_SEQ) {
+      start += ActionKV::copy_record(&mut header, record)?;
+    }
+    if last_seq > 0 {
+      start += write_last_seq(&mut header, last_seq)?;
+    }
+
+    if !self.has_path() {
+      let mut rewritten = header;
+      fill(self, &mut rewritten, start)?;
+      self.f.truncate(0)?;
+      self.f.append(&rewritten)?;
+      self.f.sync()?;
+      self.version = FORMAT_VERSION;
+      self.len = rewritten.len() as u64;
+      self.bloom = None;
+      return Ok(());
+    }
+
+    let tmp_path = ActionKV::sibling_path(&self.path, "compact");
+    let mut tmp = open_file(&tmp_path, true)?;
+    tmp.set_len(0)?;
+
+    {
+      let mut f = BufWriter::new(&mut tmp);
+      f.write_all(&header)?;
+      fill(self, &mut f, start)?;
+      f.flush()?;
+    }
+    tmp.sync_all()?;
+    drop(tmp);
+
+    fs::rename(&tmp_path, &self.path)?;
+    ActionKV::sync_parent_dir(&self.path)?;
+
+    *self = Segment::open(self.id, &self.path)?;
+    Ok(())
+  }
+}
+
+/// Opens the file at `path` for reading and appending.
+pub(crate) fn open_file(path: &Path, create: bool) -> io::Result<File> {
+  fs::OpenOptions::new()
+    .read(true)
+    .write(true)
+    .create(create)
+    .truncate(false)
+    .open(path)
+}
+
+/// Returns the format version of `f`, writing a fresh header if it is
+/// new and `writable`. Files that predate the header are reported as
+/// `LEGACY_VERSION`.
+fn read_file_header(f: &mut dyn Storage, writable: bool) -> io::Result<u32> {
+  let len = f.len()?;
+
+  let mut header = [0u8; FILE_HEADER_LEN as usize];
+  let prefix_len = len.min(MAGIC.len() as u64) as usize;
+  let mut reader = ReadAt::new(&*f, 0);
+  reader.read_exact(&mut header[..len.min(FILE_HEADER_LEN) as usize])?;
+  let (magic, version) = header.split_at(MAGIC.len());
+
+  // An empty file, or one whose header was cut short while it was being
+  // created, holds no records yet.
+  if len < FILE_HEADER_LEN && magic[..prefix_len] == MAGIC[..prefix_len] {
+    if !writable {
+      return Ok(FORMAT_VERSION);
+    }
+    let mut fresh = Vec::new();
+    write_file_header(&mut fresh)?;
+    f.truncate(0)?;
+    f.append(&fresh)?;
+    return Ok(FORMAT_VERSION);
+  }
+
+  if len < FILE_HEADER_LEN || magic != MAGIC {
+    return Ok(LEGACY_VERSION);
+  }
+
+  let version = (&version[..]).read_u32::<LittleEndian>()?;
+  if version > FORMAT_VERSION {
+    return Err(ActionKvError::UnsupportedVersion { found: version }.into());
+  }
+
+  Ok(version)
+}
+
+pub(crate) fn write_file_header<W: Write>(f: &mut W) -> io::Result<u64> {
+  f.write_all(MAGIC)?;
+  f.write_u32::<LittleEndian>(FORMAT_VERSION)?;
+  Ok(FILE_HEADER_LEN)
+}
+
+/// Writes a `last-seq` record holding `seq`.
+pub(crate) fn write_last_seq<W: Write + ?Sized>(f: &mut W, seq: u64) -> io::Result<u64> {
+  ActionKV::write_record(f, FORMAT_VERSION, RecordKind::Meta, 0, None, None, LAST_SEQ, &seq.to_le_bytes())
+}
+
+/// The path of segment `id` within `dir`.
+pub(crate) fn segment_path(dir: &Path, id: u32) -> PathBuf {
+  dir.join(format!("{:010}.{}", id, SEGMENT_EXTENSION))
+}
+
+pub(crate) fn segment_id(path: &Path) -> Option<u32> {
+  if path.extension()? != SEGMENT_EXTENSION {
+    return None;
+  }
+  path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Opens every segment in `dir`, oldest first, creating the directory
+/// and a first segment if need be. Segments that were already merged
+/// into a later one, and temporary files left by an interrupted
+/// compaction, are deleted.
+///
+/// With `read_only`, nothing is created or deleted: merged segments are
+/// just left out, and a directory without segments is an error.
+pub(crate) fn open_dir(dir: &Path, read_only: bool) -> io::Result<Vec<Segment>> {
+  if !read_only {
+    fs::create_dir_all(dir)?;
+  }
+
+  let mut ids = Vec::new();
+  let mut stale = Vec::new();
+  for entry in fs::read_dir(dir)? {
+    let path = entry?.path();
+    match segment_id(&path) {
+      Some(id) => ids.push(id),
+      None if path.extension().is_some_and(|ext| ext == "compact") => stale.push(path),
+      None => {},
+    }
+  }
+  ids.sort_unstable();
+
+  let mut segments = Vec::with_capacity(ids.len());
+  for id in ids {
+    let path = segment_path(dir, id);
+    let segment = match read_only {
+      true => Segment::open_read_only(id, &path)?,
+      false => Segment::open(id, &path)?,
+    };
+    if let Some(first) = segment.merged_from()? {
+      while segments.last().is_some_and(|older: &Segment| older.id >= first) {
+        stale.push(segments.pop().unwrap().path);
+      }
+    }
+    segments.push(segment);
+  }
+
+  if read_only {
+    if segments.is_empty() {
+      return Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} holds no segments", dir.display()),
+      ));
+    }
+    return Ok(segments);
+  }
+
+  if !stale.is_empty() {
+    for path in &stale {
+      fs::remove_file(path)?;
+      bloom::remove_bloom(path)?;
+    }
+    ActionKV::sync_parent_dir(&segment_path(dir, 0))?;
+  }
+
+  if segments.is_empty() {
+    let path = segment_path(dir, 1);
+    segments.push(Segment::create(1, &path)?);
+    ActionKV::sync_parent_dir(&path)?;
+  }
+
+  Ok(segments)
+}
+
+/// The segment with id `id`.
+pub(crate) fn lookup(segments: &[Segment], id: u32) -> io::Result<&Segment> {
+  let i = find(segments, id)?;
+  Ok(&segments[i])
+}
+
+pub(crate) fn lookup_mut(segments: &mut [Segment], id: u32) -> io::Result<&mut Segment> {
+  let i = find(segments, id)?;
+  Ok(&mut segments[i])
+}
+
+fn find(segments: &[Segment], id: u32) -> io::Result<usize> {
+  segments
+    .binary_search_by_key(&id, |segment| segment.id)
+    .map_err(|_| io::Error::new(io::ErrorKind::NotFound, format!("segment {} does not exist", id)))
+}
+
+/// Reads a segment's storage from a given offset, so that several
+/// threads can read the same segment at once.
+pub(crate) struct ReadAt<'a> {
+  f: &'a dyn Storage,
+  offset: u64,
+}
+
+impl<'a> ReadAt<'a> {
+  pub(crate) fn new(f: &'a dyn Storage, offset: u64) -> Self {
+    ReadAt { f, offset }
+  }
+}
+
+impl Read for ReadAt<'_> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let n = self.f.read_at(buf, self.offset)?;
+    self.offset += n as u64;
+    Ok(n)
+  }
+}
+
+impl Seek for ReadAt<'_> {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    let offset = match pos {
+      SeekFrom::Start(offset) => Some(offset),
+      SeekFrom::End(delta) => self.f.len()?.checked_add_signed(delta),
+      SeekFrom::Current(delta) => self.offset.checked_add_signed(delta),
+    };
+
+    match offset {
+      Some(offset) => {
+        self.offset = offset;
+        Ok(offset)
+      },
+      None => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid seek")),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use byteorder::{LittleEndian, WriteBytesExt};
+  use crc::crc32;
+
+  use crate::{FaultyStorage, MemoryStorage, OpenOptions, LEGACY_VERSION};
+
+  #[test]
+  fn failed_append_is_rolled_back() {
+    let mem = MemoryStorage::new();
+    let storage = FaultyStorage::new(mem.clone());
+    let faults = storage.faults();
+    let mut store = OpenOptions::new().open_storage(storage).unwrap();
+    store.load().unwrap();
+
+    store.insert(b"a", b"1").unwrap();
+    let len = mem.to_vec().len();
+
+    faults.short_write(0, 5);
+    assert!(store.insert(b"b", b"2").is_err());
+    assert_eq!(mem.to_vec().len(), len);
+
+    store.insert(b"c", b"3").unwrap();
+    drop(store);
+
+    let mut store = OpenOptions::new().open_storage(mem).unwrap();
+    store.load().unwrap();
+    assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(store.get(b"b").unwrap(), None);
+    assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+    assert!(store.check().unwrap().is_clean());
+  }
+
+  #[test]
+  fn tombstone_survives_reopen() {
+    let mem = MemoryStorage::new();
+    let mut store = OpenOptions::new().open_storage(mem.clone()).unwrap();
+    store.load().unwrap();
+    store.insert(b"empty", b"").unwrap();
+    store.insert(b"gone", b"1").unwrap();
+    store.delete(b"gone").unwrap();
+    drop(store);
+
+    let mut store = OpenOptions::new().open_storage(mem).unwrap();
+    store.load().unwrap();
+    assert_eq!(store.get(b"empty").unwrap(), Some(Vec::new()));
+    assert_eq!(store.get(b"gone").unwrap(), None);
+    assert!(!store.index.contains_key(b"gone"));
+    assert_eq!(store.index.len(), 1);
+  }
+
+  #[test]
+  fn version_1_files_still_load() {
+    let mut bytes = Vec::new();
+    for (key, value) in [(&b"a"[..], &b"1"[..]), (b"b", b"2"), (b"a", b"3"), (b"b", b"")] {
+      let data = [key, value].concat();
+      bytes.write_u32::<LittleEndian>(crc32::checksum_ieee(&data)).unwrap();
+      bytes.write_u32::<LittleEndian>(key.len() as u32).unwrap();
+      bytes.write_u32::<LittleEndian>(value.len() as u32).unwrap();
+      bytes.extend_from_slice(&data);
+    }
+
+    let mut store = OpenOptions::new().open_storage(MemoryStorage::from_bytes(bytes)).unwrap();
+    store.load().unwrap();
+    assert_eq!(store.version(), LEGACY_VERSION);
+    assert_eq!(store.get(b"a").unwrap(), Some(b"3".to_vec()));
+    // Version 1 wrote deletions as empty values
+    assert_eq!(store.get(b"b").unwrap(), None);
+    assert!(store.check().unwrap().is_clean());
+  }
+}
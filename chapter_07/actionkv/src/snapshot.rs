@@ -0,0 +1,233 @@
+//! Read-only views of a store as it was at a point in its log.
+//!
+//! A `Snapshot` keeps a copy of the index as it was when the snapshot was
+//! taken, along with its own handles to the store's files. Later writes
+//! only append, so they never show through, and compaction replaces or
+//! removes files rather than changing them, so the handles go on reading
+//! the records the snapshot points at. (Windows does not let an open file
+//! be replaced, so there compacting fails while a snapshot is alive.)
+//!
+//! The exception is `repair()`, which cuts a torn tail off the end of a
+//! file in place, and the snapshot's handles see the file shrink. A record
+//! in a torn tail was never complete, so the snapshot's index never points
+//! at one, but `Snapshot::get_as_of()` no longer finds anything there.
+
+use std::io;
+use std::ops::RangeBounds;
+
+use crate::bloom::BloomCounters;
+use crate::encryption::{self, Cipher};
+use crate::iter::Iter;
+use crate::record::{self, RecordKind};
+use crate::segment::{self, Segment};
+use crate::{ActionKV, ByteStr, ByteString, Index, KeyValuePair, Position, Result};
+
+/// One record of a key's history, from `ActionKV::get_as_of()`: an
+/// insert, or a delete if `value` is `None`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+  pub position: Position,
+  pub value: Option<ByteString>,
+
+  /// When the value expires, in milliseconds since the Unix epoch.
+  /// Versions that have since expired are returned all the same.
+  pub expires_at: Option<u64>,
+
+  /// The record's sequence number, or 0 if it was written before records
+  /// had one. See the `conditional` module.
+  pub seq: u64,
+}
+
+/// A read-only view of a store, pinned to the end of its log when
+/// `ActionKV::snapshot()` was called.
+#[derive(Debug)]
+pub struct Snapshot {
+  segments: Vec<Segment>,
+  cipher: Option<Cipher>,
+  index: Index,
+  end: Position,
+
+  /// Keys are treated as expired or not as of when the snapshot was
+  /// taken, so every read agrees.
+  now: u64,
+}
+
+impl ActionKV {
+  /// Takes a snapshot of the store as it is now. The index is copied, so
+  /// this takes time and memory in proportion to the number of keys.
+  /// Until `load()` has been called, the index is built by reading the
+  /// whole log instead.
+  pub fn snapshot(&self) -> Result<Snapshot> {
+    self.check_log_engine("snapshot()")?;
+    let segments = self
+      .segments
+      .iter()
+      .map(|segment| segment.try_clone())
+      .collect::<io::Result<Vec<_>>>()?;
+    let active = segments.last().expect("a store always has a segment");
+    let end = active.position(active.f.len()?);
+
+    let now = record::now();
+    let index = match self.loaded {
+      true => self.index.clone(),
+      false => {
+        let mut index = self.index.empty_like();
+        self.scan_log(|position, record| ActionKV::index_record(&mut index, position, record, now))?;
+        index
+      },
+    };
+
+    Ok(Snapshot { segments, cipher: self.cipher.clone(), index, end, now })
+  }
+
+  /// Every version of `key` written before `as_of`, oldest first. Use
+  /// `seek_to_end()` for the whole history. Like `find()`, this reads
+  /// the log, skipping segments ruled out by their bloom filter, and
+  /// compaction removes all but the latest version.
+  pub fn get_as_of(&self, key: &ByteStr, as_of: Position) -> Result<Vec<Version>> {
+    self.check_log_engine("get_as_of()")?;
+    Ok(versions(&self.segments, self.cipher.as_ref(), Some(&self.bloom_counters), key, as_of)?)
+  }
+}
+
+impl Snapshot {
+  /// The position the snapshot was taken at. Records from here on were
+  /// written after it.
+  pub fn position(&self) -> Position {
+    self.end
+  }
+
+  /// The number of keys in the snapshot, including any that have expired.
+  pub fn len(&self) -> usize {
+    self.index.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.index.is_empty()
+  }
+
+  pub fn get(&self, key: &ByteStr) -> Result<Option<ByteString>> {
+    let position = match self.index.get(key) {
+      None => return Ok(None),
+      Some(entry) if entry.is_expired(self.now) => return Ok(None),
+      Some(entry) => entry.position,
+    };
+
+    Ok(Some(self.get_at(position)?.value))
+  }
+
+  /// See `ActionKV::get_at()`. Positions from after the snapshot was
+  /// taken are read from the files as they are now.
+  pub fn get_at(&self, position: Position) -> Result<KeyValuePair> {
+    let record = segment::lookup(&self.segments, position.segment)?.read_record(position.offset)?;
+    Ok(encryption::unseal(self.cipher.as_ref(), position, record)?.into_pair()?)
+  }
+
+  /// See `ActionKV::get_as_of()`. Versions written after the snapshot was
+  /// taken are left out.
+  pub fn get_as_of(&self, key: &ByteStr, as_of: Position) -> Result<Vec<Version>> {
+    Ok(versions(&self.segments, self.cipher.as_ref(), None, key, as_of.min(self.end))?)
+  }
+
+  /// See `ActionKV::iter()`.
+  pub fn iter(&self) -> Iter<'_> {
+    Iter::new(&self.segments, self.cipher.as_ref(), self.index.iter(), self.now)
+  }
+
+  /// See `ActionKV::scan()`.
+  pub fn scan<'a, R>(&'a self, range: R) -> Iter<'a>
+  where
+    R: RangeBounds<ByteStr> + 'a,
+  {
+    Iter::new(&self.segments, self.cipher.as_ref(), self.index.range(range), self.now)
+  }
+
+  /// See `ActionKV::prefix()`.
+  pub fn prefix<'a>(&'a self, prefix: &'a ByteStr) -> Iter<'a> {
+    Iter::new(&self.segments, self.cipher.as_ref(), self.index.prefix(prefix), self.now)
+  }
+}
+
+/// Reads the versions of `key` from `segments`, counting bloom filter
+/// answers in `counters` if given.
+fn versions(
+  segments: &[Segment],
+  cipher: Option<&Cipher>,
+  counters: Option<&BloomCounters>,
+  key: &ByteStr,
+  as_of: Position,
+) -> io::Result<Vec<Version>> {
+  let mut found = Vec::new();
+  let mut failed = None;
+
+  for segment in segments.iter().filter(|segment| segment.id <= as_of.segment) {
+    let maybe = match counters {
+      Some(counters) => counters.check(segment, key),
+      None => segment.may_contain(key),
+    };
+    if !maybe {
+      continue;
+    }
+
+    let already_found = found.len();
+    let id = segment.id;
+    segment.scan(segment.data_start(), false, |offset, record| {
+      let position = Position { segment: id, offset };
+      if failed.is_some() || position >= as_of {
+        return;
+      }
+
+      let version = encryption::unseal(cipher, position, record).and_then(|record| {
+        if record.key != key {
+          return Ok(None);
+        }
+        let expires_at = record.expires_at;
+        let seq = record.version();
+        let deleted = record.kind == RecordKind::Tombstone;
+        let value = record.into_pair()?.value;
+        Ok(Some(Version { position, value: (!deleted).then_some(value), expires_at, seq }))
+      });
+      match version {
+        Ok(Some(version)) => found.push(version),
+        Ok(None) => {},
+        Err(err) => failed = Some(err),
+      }
+    })?;
+
+    if let Some(err) = failed {
+      return Err(err);
+    }
+    if let (Some(counters), true) = (counters, found.len() == already_found) {
+      counters.missed(segment);
+    }
+  }
+
+  Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{MemoryStorage, OpenOptions};
+
+  #[test]
+  fn snapshot_before_load() {
+    let mem = MemoryStorage::new();
+    let mut store = OpenOptions::new().open_storage(mem.clone()).unwrap();
+    store.load().unwrap();
+    store.insert(b"a", b"1").unwrap();
+    store.insert(b"b", b"2").unwrap();
+    store.delete(b"a").unwrap();
+    drop(store);
+
+    let mut store = OpenOptions::new().open_storage(mem).unwrap();
+    store.insert(b"c", b"3").unwrap();
+    let snapshot = store.snapshot().unwrap();
+    store.insert(b"b", b"22").unwrap();
+
+    assert_eq!(snapshot.get(b"a").unwrap(), None);
+    assert_eq!(snapshot.get(b"b").unwrap(), Some(b"2".to_vec()));
+    assert_eq!(snapshot.get(b"c").unwrap(), Some(b"3".to_vec()));
+    assert_eq!(snapshot.len(), 2);
+    assert_eq!(store.get(b"b").unwrap(), Some(b"22".to_vec()));
+  }
+}
@@ -0,0 +1,154 @@
+//! Figures that describe the state of a store.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::encryption;
+use crate::record::{self, RecordKind};
+use crate::{ActionKV, Result};
+
+/// What `ActionKV::stats()` found. Sizes are in bytes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+  /// Keys that have not been deleted or expired.
+  pub live_keys: u64,
+
+  /// Inserts and deletes in the log, live or not.
+  pub records: u64,
+
+  /// Deletes in the log. Each one stays until compaction.
+  pub tombstones: u64,
+
+  /// Space taken by the records that hold live keys.
+  pub live_bytes: u64,
+
+  /// Space taken by everything compaction would remove: overwritten,
+  /// deleted and expired records, tombstones, and batch bookkeeping.
+  pub dead_bytes: u64,
+
+  /// The longest live key and value, as stored before compression or
+  /// encryption.
+  pub largest_key: u64,
+  pub largest_value: u64,
+
+  pub segments: u64,
+
+  /// The size of every segment, headers included.
+  pub file_size: u64,
+}
+
+impl Stats {
+  /// The share of the log that compaction would remove, from 0 to 1.
+  pub fn fragmentation(&self) -> f64 {
+    match self.live_bytes + self.dead_bytes {
+      0 => 0.0,
+      total => self.dead_bytes as f64 / total as f64,
+    }
+  }
+}
+
+impl fmt::Display for Stats {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "live keys:      {}", self.live_keys)?;
+    writeln!(f, "records:        {}", self.records)?;
+    writeln!(f, "tombstones:     {}", self.tombstones)?;
+    writeln!(f, "live bytes:     {}", self.live_bytes)?;
+    writeln!(f, "dead bytes:     {}", self.dead_bytes)?;
+    writeln!(f, "largest key:    {}", self.largest_key)?;
+    writeln!(f, "largest value:  {}", self.largest_value)?;
+    writeln!(f, "segments:       {}", self.segments)?;
+    writeln!(f, "file size:      {}", self.file_size)?;
+    writeln!(f, "fragmentation:  {:.1}%", self.fragmentation() * 100.0)
+  }
+}
+
+impl ActionKV {
+  /// Reads the whole log to describe it. Which keys are live is taken
+  /// from `index`, so call `load()` first.
+  pub fn stats(&self) -> Result<Stats> {
+    self.check_log_engine("stats()")?;
+    let now = record::now();
+    let live: HashSet<_> = self
+      .index
+      .iter()
+      .filter(|(_, entry)| !entry.is_expired(now))
+      .map(|(_, entry)| entry.position)
+      .collect();
+
+    let mut stats = Stats {
+      live_keys: live.len() as u64,
+      segments: self.segments.len() as u64,
+      ..Stats::default()
+    };
+
+    for segment in &self.segments {
+      stats.file_size += segment.f.len()?;
+
+      segment.read_from(segment.data_start(), |at, next, record| {
+        let size = next - at;
+        match record.kind {
+          RecordKind::Value | RecordKind::Tombstone => stats.records += 1,
+          // Compaction carries meta records over, so they are not dead
+          RecordKind::Meta => return Ok(()),
+          RecordKind::Commit => {},
+        }
+        if record.kind == RecordKind::Tombstone {
+          stats.tombstones += 1;
+        }
+
+        let position = segment.position(at);
+        if !live.contains(&position) {
+          stats.dead_bytes += size;
+          return Ok(());
+        }
+
+        stats.live_bytes += size;
+        let kv = encryption::unseal(self.cipher.as_ref(), position, record)?.into_pair()?;
+        stats.largest_key = stats.largest_key.max(kv.key.len() as u64);
+        stats.largest_value = stats.largest_value.max(kv.value.len() as u64);
+        Ok(())
+      })?;
+    }
+
+    Ok(stats)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{MemoryStorage, OpenOptions, Storage};
+
+  /// checksum, kind, flags, key_len, val_len and seq
+  const HEADER: u64 = 4 + 1 + 1 + 4 + 4 + 8;
+
+  #[test]
+  fn dead_bytes_follow_deletes_and_compaction() {
+    let mem = MemoryStorage::new();
+    let mut store = OpenOptions::new().open_storage(mem.clone()).unwrap();
+    store.load().unwrap();
+    store.insert(b"a", b"1").unwrap();
+    store.insert(b"a", b"22").unwrap();
+    store.insert(b"b", b"333").unwrap();
+    store.delete(b"b").unwrap();
+
+    let stats = store.stats().unwrap();
+    assert_eq!(stats.live_keys, 1);
+    assert_eq!(stats.records, 4);
+    assert_eq!(stats.tombstones, 1);
+    assert_eq!(stats.live_bytes, HEADER + 3);
+    assert_eq!(stats.dead_bytes, (HEADER + 2) + (HEADER + 4) + (HEADER + 1));
+    assert_eq!((stats.largest_key, stats.largest_value), (1, 2));
+    assert_eq!(stats.file_size, mem.len().unwrap());
+    assert!(stats.fragmentation() > 0.7);
+
+    store.compact().unwrap();
+    let stats = store.stats().unwrap();
+    assert_eq!(stats.live_keys, 1);
+    assert_eq!(stats.records, 1);
+    assert_eq!(stats.tombstones, 0);
+    assert_eq!(stats.live_bytes, HEADER + 3);
+    assert_eq!(stats.dead_bytes, 0);
+    assert_eq!(stats.file_size, mem.len().unwrap());
+    assert_eq!(stats.fragmentation(), 0.0);
+  }
+}
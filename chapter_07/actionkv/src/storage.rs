@@ -0,0 +1,476 @@
+//! Where a segment's bytes live.
+//!
+//! A segment reads and writes through the `Storage` trait rather than a
+//! `File`, so a store can also be kept in memory, which spares tests from
+//! creating temporary files, or behind `FaultyStorage`, which breaks
+//! writes and reads on request so that recovery can be tested without
+//! waiting for real hardware to fail.
+//!
+//! A store opened on a `Storage` with `OpenOptions::open_storage()` has
+//! no path, so it is always a single segment, and the files that would
+//! sit next to it (the hint, the quarantine and any bloom filters) are
+//! not written. It is not locked, cannot be opened read-only, and cannot
+//! be watched or replicated either.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+/// The bytes of one segment: read from anywhere, written only at the end.
+pub trait Storage: fmt::Debug + Send + Sync {
+  /// Reads into `buf` from `offset`, returning how many bytes were read,
+  /// which is 0 at the end. Must not get in the way of other reads at the
+  /// same time.
+  fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+  /// Writes `buf` to the end, returning the offset it was written at.
+  fn append(&mut self, buf: &[u8]) -> io::Result<u64>;
+
+  /// Makes everything appended so far durable.
+  fn sync(&mut self) -> io::Result<()>;
+
+  /// The number of bytes stored.
+  fn len(&self) -> io::Result<u64>;
+
+  fn is_empty(&self) -> io::Result<bool> {
+    Ok(self.len()? == 0)
+  }
+
+  /// Cuts the storage short at `len` bytes, for `ActionKV::repair()`.
+  fn truncate(&mut self, len: u64) -> io::Result<()>;
+
+  /// Another handle that goes on reading the bytes as they are now, for
+  /// `ActionKV::snapshot()`. It may or may not see later appends, but
+  /// must not see the bytes replaced by compaction. Syncing it must make
+  /// what was appended through this handle durable, as a second handle
+  /// to a file does, since `SyncPolicy::Interval` syncs through one from
+  /// a background thread.
+  fn try_clone(&self) -> io::Result<Box<dyn Storage>>;
+}
+
+/// Lets a handle from `try_clone()` be wrapped again, as `FaultyStorage`
+/// does with its own.
+impl Storage for Box<dyn Storage> {
+  fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    (**self).read_at(buf, offset)
+  }
+
+  fn append(&mut self, buf: &[u8]) -> io::Result<u64> {
+    (**self).append(buf)
+  }
+
+  fn sync(&mut self) -> io::Result<()> {
+    (**self).sync()
+  }
+
+  fn len(&self) -> io::Result<u64> {
+    (**self).len()
+  }
+
+  fn truncate(&mut self, len: u64) -> io::Result<()> {
+    (**self).truncate(len)
+  }
+
+  fn try_clone(&self) -> io::Result<Box<dyn Storage>> {
+    (**self).try_clone()
+  }
+}
+
+/// A segment file.
+#[derive(Debug)]
+pub struct FileStorage {
+  f: File,
+}
+
+impl FileStorage {
+  pub fn new(f: File) -> Self {
+    FileStorage { f }
+  }
+}
+
+impl Storage for FileStorage {
+  fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    read_at(&self.f, buf, offset)
+  }
+
+  fn append(&mut self, buf: &[u8]) -> io::Result<u64> {
+    let offset = self.f.seek(SeekFrom::End(0))?;
+    self.f.write_all(buf)?;
+    Ok(offset)
+  }
+
+  fn sync(&mut self) -> io::Result<()> {
+    self.f.sync_data()
+  }
+
+  fn len(&self) -> io::Result<u64> {
+    Ok(self.f.metadata()?.len())
+  }
+
+  fn truncate(&mut self, len: u64) -> io::Result<()> {
+    self.f.set_len(len)?;
+    self.f.sync_all()
+  }
+
+  // Compaction renames a new file over the old one rather than changing
+  // it, so a second handle keeps the old bytes
+  fn try_clone(&self) -> io::Result<Box<dyn Storage>> {
+    Ok(Box::new(FileStorage { f: self.f.try_clone()? }))
+  }
+}
+
+#[cfg(unix)]
+fn read_at(f: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+  use std::os::unix::fs::FileExt;
+  f.read_at(buf, offset)
+}
+
+// `seek_read` moves the cursor on Windows, which is harmless because
+// appends always seek to the end first
+#[cfg(windows)]
+fn read_at(f: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+  use std::os::windows::fs::FileExt;
+  f.seek_read(buf, offset)
+}
+
+/// Bytes kept in memory, and lost when the last clone is dropped. Clones
+/// share the same bytes, so a test can keep one to look at what the store
+/// wrote, or to open the store again as if after a restart.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+  bytes: Arc<RwLock<Vec<u8>>>,
+}
+
+impl MemoryStorage {
+  pub fn new() -> Self {
+    MemoryStorage::default()
+  }
+
+  pub fn from_bytes(bytes: Vec<u8>) -> Self {
+    MemoryStorage { bytes: Arc::new(RwLock::new(bytes)) }
+  }
+
+  /// A copy of the bytes stored.
+  pub fn to_vec(&self) -> Vec<u8> {
+    self.bytes.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+  }
+}
+
+impl Storage for MemoryStorage {
+  fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let bytes = self.bytes.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let start = (offset as usize).min(bytes.len());
+    let n = buf.len().min(bytes.len() - start);
+    buf[..n].copy_from_slice(&bytes[start..start + n]);
+    Ok(n)
+  }
+
+  fn append(&mut self, buf: &[u8]) -> io::Result<u64> {
+    let mut bytes = self.bytes.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let offset = bytes.len() as u64;
+    bytes.extend_from_slice(buf);
+    Ok(offset)
+  }
+
+  fn sync(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+
+  fn len(&self) -> io::Result<u64> {
+    Ok(self.bytes.read().unwrap_or_else(|poisoned| poisoned.into_inner()).len() as u64)
+  }
+
+  fn truncate(&mut self, len: u64) -> io::Result<()> {
+    let mut bytes = self.bytes.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    bytes.truncate(len as usize);
+    Ok(())
+  }
+
+  // Compaction rewrites memory in place, so a snapshot needs a copy
+  fn try_clone(&self) -> io::Result<Box<dyn Storage>> {
+    Ok(Box::new(MemoryStorage::from_bytes(self.to_vec())))
+  }
+}
+
+/// Wraps another `Storage` and fails in the ways set up through its
+/// `Faults`, which can be changed after the store has been opened.
+#[derive(Debug)]
+pub struct FaultyStorage<S> {
+  inner: S,
+  faults: Faults,
+}
+
+/// The faults a `FaultyStorage` will inject. Clones share the same
+/// settings.
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+  state: Arc<Mutex<FaultState>>,
+}
+
+#[derive(Debug, Default)]
+struct FaultState {
+  /// Appends to write in full before the short write.
+  short_write_after: Option<usize>,
+  short_write_len: usize,
+  bit_flips: Vec<(u64, u8)>,
+  fail_sync: bool,
+  syncs: u64,
+}
+
+impl<S: Storage> FaultyStorage<S> {
+  pub fn new(inner: S) -> Self {
+    FaultyStorage { inner, faults: Faults::default() }
+  }
+
+  /// The handle for setting up faults.
+  pub fn faults(&self) -> Faults {
+    self.faults.clone()
+  }
+
+  pub fn into_inner(self) -> S {
+    self.inner
+  }
+}
+
+impl Faults {
+  fn state(&self) -> MutexGuard<'_, FaultState> {
+    self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+
+  /// After `appends` more appends go through, the next one writes only
+  /// its first `len` bytes and then fails, as if the process had stopped
+  /// part way through the write.
+  pub fn short_write(&self, appends: usize, len: usize) -> &Self {
+    let mut state = self.state();
+    state.short_write_after = Some(appends);
+    state.short_write_len = len;
+    self
+  }
+
+  /// Flips bit `bit` (0 to 7) of the byte at `offset` whenever it is read.
+  /// Flipping the same bit again puts it back.
+  pub fn flip_bit(&self, offset: u64, bit: u8) -> &Self {
+    let mut state = self.state();
+    match state.bit_flips.iter().position(|flip| *flip == (offset, bit)) {
+      Some(i) => {
+        state.bit_flips.remove(i);
+      },
+      None => state.bit_flips.push((offset, bit)),
+    }
+    self
+  }
+
+  /// Makes every sync fail until `clear()` is called.
+  pub fn fail_sync(&self) -> &Self {
+    self.state().fail_sync = true;
+    self
+  }
+
+  /// Removes every fault.
+  pub fn clear(&self) -> &Self {
+    let mut state = self.state();
+    *state = FaultState { syncs: state.syncs, ..FaultState::default() };
+    self
+  }
+
+  /// How many syncs have succeeded.
+  pub fn syncs(&self) -> u64 {
+    self.state().syncs
+  }
+}
+
+impl<S: Storage> Storage for FaultyStorage<S> {
+  fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let n = self.inner.read_at(buf, offset)?;
+    for &(at, bit) in &self.faults.state().bit_flips {
+      if at >= offset && at < offset + n as u64 {
+        buf[(at - offset) as usize] ^= 1 << (bit % 8);
+      }
+    }
+    Ok(n)
+  }
+
+  fn append(&mut self, buf: &[u8]) -> io::Result<u64> {
+    let short_len = {
+      let mut state = self.faults.state();
+      match state.short_write_after {
+        Some(0) => {
+          state.short_write_after = None;
+          Some(state.short_write_len.min(buf.len()))
+        },
+        Some(n) => {
+          state.short_write_after = Some(n - 1);
+          None
+        },
+        None => None,
+      }
+    };
+
+    match short_len {
+      Some(len) => {
+        self.inner.append(&buf[..len])?;
+        Err(io::Error::new(io::ErrorKind::WriteZero, "injected short write"))
+      },
+      None => self.inner.append(buf),
+    }
+  }
+
+  fn sync(&mut self) -> io::Result<()> {
+    if self.faults.state().fail_sync {
+      return Err(io::Error::other("injected sync failure"));
+    }
+    self.inner.sync()?;
+    self.faults.state().syncs += 1;
+    Ok(())
+  }
+
+  fn len(&self) -> io::Result<u64> {
+    self.inner.len()
+  }
+
+  fn truncate(&mut self, len: u64) -> io::Result<()> {
+    self.inner.truncate(len)
+  }
+
+  // The clone fails in the same ways
+  fn try_clone(&self) -> io::Result<Box<dyn Storage>> {
+    Ok(Box::new(FaultyStorage { inner: self.inner.try_clone()?, faults: self.faults.clone() }))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{ActionKV, ActionKvError, Corruption, OpenOptions};
+
+  fn open<S: Storage + 'static>(storage: S) -> ActionKV {
+    OpenOptions::new().open_storage(storage).unwrap()
+  }
+
+  /// A store holding `a`, `b` and `c`, and the offsets of their records.
+  fn abc() -> (MemoryStorage, [u64; 3]) {
+    let mem = MemoryStorage::new();
+    let mut store = open(mem.clone());
+    store.load().unwrap();
+
+    let mut offsets = [0; 3];
+    for (i, key) in [b"a", b"b", b"c"].into_iter().enumerate() {
+      offsets[i] = mem.len().unwrap();
+      store.insert(key, b"value").unwrap();
+    }
+    (mem, offsets)
+  }
+
+  fn assert_corrupt(result: crate::Result<()>, expected: &Corruption) {
+    match result {
+      Err(ActionKvError::Corruption(found)) => assert_eq!(&found, expected),
+      other => panic!("expected {:?}, got {:?}", expected, other),
+    }
+  }
+
+  #[test]
+  fn torn_tail() {
+    let (mem, [_, _, c]) = abc();
+    let mut bytes = mem.to_vec();
+    let end = bytes.len() as u64;
+    bytes.truncate(bytes.len() - 3);
+    let mem = MemoryStorage::from_bytes(bytes);
+
+    let mut store = open(mem.clone());
+    let torn = Corruption::TornTail { segment: 0, offset: c, len: end - 3 - c };
+    assert_corrupt(store.load(), &torn);
+
+    let report = store.repair().unwrap();
+    assert_eq!(report.records, 2);
+    assert_eq!(report.problems, vec![torn]);
+    assert_eq!(report.quarantine, None);
+    assert_eq!(mem.len().unwrap(), c);
+
+    assert_eq!(store.get(b"b").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(store.get(b"c").unwrap(), None);
+    let mut store = open(mem);
+    store.load().unwrap();
+    assert!(store.check().unwrap().is_clean());
+  }
+
+  #[test]
+  fn flipped_bit() {
+    let (mem, [a, b, c]) = abc();
+    let checksum = |problems: &[Corruption]| match problems {
+      [Corruption::Checksum { segment: 0, offset, len, .. }] => (*offset, *len),
+      other => panic!("expected one checksum error, got {:?}", other),
+    };
+
+    // A bit that flips as it is read: the stored bytes are fine
+    let faulty = FaultyStorage::new(mem.clone());
+    let faults = faulty.faults();
+    faults.flip_bit(c - 1, 3);
+    let mut store = open(faulty);
+    match store.load() {
+      Err(ActionKvError::Corruption(problem)) => assert_eq!(checksum(&[problem]), (b, c - b)),
+      other => panic!("expected a checksum error, got {:?}", other),
+    }
+    assert_eq!(checksum(&store.check().unwrap().problems), (b, c - b));
+    faults.clear();
+    store.load().unwrap();
+    assert_eq!(store.get(b"b").unwrap(), Some(b"value".to_vec()));
+
+    // A bit that is flipped on disk, which `repair()` has to remove
+    let original = mem.to_vec();
+    let mut bytes = original.clone();
+    bytes[c as usize - 1] ^= 1 << 3;
+    let mem = MemoryStorage::from_bytes(bytes);
+    let mut store = open(mem.clone());
+    assert!(matches!(store.load(), Err(ActionKvError::Corruption(Corruption::Checksum { .. }))));
+
+    let report = store.repair().unwrap();
+    assert_eq!(report.records, 2);
+    assert_eq!(checksum(&report.problems), (b, c - b));
+    assert_eq!(report.damaged_bytes(), c - b);
+
+    // The segment is rewritten without the damaged record, and the intact
+    // records are copied across byte for byte
+    let repaired = mem.to_vec();
+    let contains = |record: &[u8]| repaired.windows(record.len()).any(|w| w == record);
+    assert!(contains(&original[a as usize..b as usize]));
+    assert!(!contains(&original[b as usize..c as usize]));
+    assert!(repaired.ends_with(&original[c as usize..]));
+
+    assert_eq!(store.get(b"a").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(store.get(b"b").unwrap(), None);
+    assert_eq!(store.get(b"c").unwrap(), Some(b"value".to_vec()));
+    let mut store = open(mem);
+    store.load().unwrap();
+    assert!(store.check().unwrap().is_clean());
+  }
+
+  #[test]
+  fn failing_write() {
+    let (mem, _) = abc();
+    let len = mem.len().unwrap();
+
+    let faulty = FaultyStorage::new(mem.clone());
+    let faults = faulty.faults();
+    let mut store = open(faulty);
+    store.load().unwrap();
+
+    faults.short_write(0, 7);
+    match store.insert(b"d", b"value") {
+      Err(ActionKvError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::WriteZero),
+      other => panic!("expected a write error, got {:?}", other),
+    }
+    assert_eq!(mem.len().unwrap(), len);
+    assert_eq!(store.get(b"d").unwrap(), None);
+
+    let mut store = open(mem.clone());
+    store.load().unwrap();
+    let report = store.repair().unwrap();
+    assert!(report.is_clean());
+    assert_eq!(report.records, 3);
+    assert_eq!(mem.len().unwrap(), len);
+    assert_eq!(store.get(b"d").unwrap(), None);
+  }
+}
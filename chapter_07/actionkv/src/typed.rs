@@ -0,0 +1,533 @@
+//! Storing typed keys and values instead of bytes.
+//!
+//! A `TypedStore<K, V>` wraps an `ActionKV` and encodes every key and
+//! value it is given with a `Codec` before passing the bytes on, so that
+//! callers can write `store.put(&user_id, &user)` rather than encoding by
+//! hand. Three codecs are supported, as in the `serde-eg` example:
+//!
+//! - Bincode: compact, but only readable with the same types.
+//! - JSON: larger, but readable by other tools.
+//! - CBOR: a binary format that, like JSON, describes itself.
+//!
+//! The codec is not recorded in the store. Reopening a store with another
+//! codec, or with other types, makes its pairs fail to decode.
+//!
+//! `iter()` returns pairs in no particular order unless the store is
+//! ordered, and then in the order of their encoded keys, which is not
+//! necessarily the order of `K`. `range()` always sorts by `K`.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::ser::{self, Impossible, Serialize, Serializer};
+
+use crate::{ActionKV, ActionKvError, ByteString, Iter, KeyValuePair, Result};
+
+/// How a `TypedStore` turns keys and values into bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+  #[default]
+  Bincode,
+  Json,
+  Cbor,
+}
+
+impl Codec {
+  pub fn encode<T: Serialize>(&self, value: &T) -> Result<ByteString> {
+    let encoded = match self {
+      Codec::Bincode => bincode::serialize(value).map_err(|err| err.to_string()),
+      Codec::Json => serde_json::to_vec(value).map_err(|err| err.to_string()),
+      Codec::Cbor => serde_cbor::to_vec(value).map_err(|err| err.to_string()),
+    };
+    encoded.map_err(|message| self.error("encode", message))
+  }
+
+  pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+    let decoded = match self {
+      Codec::Bincode => bincode::deserialize(bytes).map_err(|err| err.to_string()),
+      Codec::Json => serde_json::from_slice(bytes).map_err(|err| err.to_string()),
+      Codec::Cbor => serde_cbor::from_slice(bytes).map_err(|err| err.to_string()),
+    };
+    decoded.map_err(|message| self.error("decode", message))
+  }
+
+  fn error(&self, action: &'static str, message: String) -> ActionKvError {
+    ActionKvError::Codec(CodecError { codec: *self, action, message })
+  }
+}
+
+impl fmt::Display for Codec {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Codec::Bincode => write!(f, "bincode"),
+      Codec::Json => write!(f, "json"),
+      Codec::Cbor => write!(f, "cbor"),
+    }
+  }
+}
+
+/// Parses `bincode`, `json` or `cbor`.
+impl FromStr for Codec {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "bincode" => Ok(Codec::Bincode),
+      "json" => Ok(Codec::Json),
+      "cbor" => Ok(Codec::Cbor),
+      _ => Err(format!("unknown codec {:?}", s)),
+    }
+  }
+}
+
+/// A key or value could not be encoded, or bytes read from the store
+/// could not be decoded. Returned as `ActionKvError::Codec`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodecError {
+  pub codec: Codec,
+
+  /// `"encode"` or `"decode"`.
+  pub action: &'static str,
+
+  pub message: String,
+}
+
+impl fmt::Display for CodecError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "unable to {} with {}: {}", self.action, self.codec, self.message)
+  }
+}
+
+impl CodecError {
+  pub(crate) fn into_io_error(self) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, self)
+  }
+}
+
+impl Error for CodecError {}
+
+/// An `ActionKV` that stores keys of type `K` and values of type `V`,
+/// encoded with a `Codec`. See the `typed` module.
+#[derive(Debug)]
+pub struct TypedStore<K, V> {
+  store: ActionKV,
+  codec: Codec,
+  types: PhantomData<fn(K) -> V>,
+}
+
+impl<K, V> TypedStore<K, V>
+where
+  K: Serialize + Ord,
+  V: Serialize + DeserializeOwned,
+{
+  /// `store` should already be loaded.
+  pub fn new(store: ActionKV, codec: Codec) -> Self {
+    TypedStore { store, codec, types: PhantomData }
+  }
+
+  pub fn codec(&self) -> Codec {
+    self.codec
+  }
+
+  /// The store underneath, for the operations that work on bytes.
+  pub fn store(&self) -> &ActionKV {
+    &self.store
+  }
+
+  pub fn store_mut(&mut self) -> &mut ActionKV {
+    &mut self.store
+  }
+
+  pub fn into_inner(self) -> ActionKV {
+    self.store
+  }
+
+  pub fn get(&self, key: &K) -> Result<Option<V>> {
+    let key = self.codec.encode(key)?;
+    match self.store.get(&key)? {
+      Some(value) => Ok(Some(self.codec.decode(&value)?)),
+      None => Ok(None),
+    }
+  }
+
+  pub fn contains_key(&self, key: &K) -> Result<bool> {
+    let key = self.codec.encode(key)?;
+    Ok(self.store.get(&key)?.is_some())
+  }
+
+  pub fn put(&mut self, key: &K, value: &V) -> Result<()> {
+    let key = self.codec.encode(key)?;
+    let value = self.codec.encode(value)?;
+    self.store.insert(&key, &value)
+  }
+
+  /// See `ActionKV::insert_with_ttl()`.
+  pub fn put_with_ttl(&mut self, key: &K, value: &V, ttl: Duration) -> Result<()> {
+    let key = self.codec.encode(key)?;
+    let value = self.codec.encode(value)?;
+    self.store.insert_with_ttl(&key, &value, ttl)
+  }
+
+  pub fn delete(&mut self, key: &K) -> Result<()> {
+    let key = self.codec.encode(key)?;
+    self.store.delete(&key)
+  }
+
+  pub fn close(self) -> Result<()> {
+    self.store.close()
+  }
+}
+
+impl<K, V> TypedStore<K, V>
+where
+  K: Serialize + DeserializeOwned + Ord,
+  V: Serialize + DeserializeOwned,
+{
+  /// Every live pair, in the same order as `ActionKV::iter()`, which is
+  /// not the order of `K`. A pair that does not decode is returned as an
+  /// error, and the rest carry on.
+  pub fn iter(&self) -> TypedIter<'_, K, V> {
+    TypedIter { inner: self.store.iter(), codec: self.codec, types: PhantomData }
+  }
+
+  /// The live pairs whose keys fall within `range`, sorted by `K`.
+  ///
+  /// Encoded keys do not usually sort like `K`: bincode writes integers
+  /// and string lengths little-endian, for one. So this decodes every pair
+  /// in the store, unless the store is ordered, the codec is bincode and
+  /// the bounds are made only of bytes, `bool`s and fixed-size groups of
+  /// them. Then the encoded bounds are passed to `ActionKV::scan()`.
+  pub fn range<R: RangeBounds<K>>(&self, range: R) -> Result<Vec<(K, V)>> {
+    if self.store.is_ordered() && self.codec == Codec::Bincode && sorts_like_bytes(&range) {
+      let start = self.encode_bound(range.start_bound())?;
+      let end = self.encode_bound(range.end_bound())?;
+      let bounds = (start.as_ref().map(Vec::as_slice), end.as_ref().map(Vec::as_slice));
+
+      return self
+        .store
+        .scan(bounds)
+        .map(|pair| pair.and_then(|kv| Ok((self.codec.decode(&kv.key)?, self.codec.decode(&kv.value)?))))
+        .collect();
+    }
+
+    let mut pairs = Vec::new();
+    for pair in self.iter() {
+      let (key, value) = pair?;
+      if range.contains(&key) {
+        pairs.push((key, value));
+      }
+    }
+    pairs.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(pairs)
+  }
+
+  fn encode_bound(&self, bound: Bound<&K>) -> Result<Bound<ByteString>> {
+    Ok(match bound {
+      Bound::Included(key) => Bound::Included(self.codec.encode(key)?),
+      Bound::Excluded(key) => Bound::Excluded(self.codec.encode(key)?),
+      Bound::Unbounded => Bound::Unbounded,
+    })
+  }
+}
+
+/// Iterates over a `TypedStore`. See `TypedStore::iter()`.
+pub struct TypedIter<'a, K, V> {
+  inner: Iter<'a>,
+  codec: Codec,
+  types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> Iterator for TypedIter<'_, K, V>
+where
+  K: DeserializeOwned,
+  V: DeserializeOwned,
+{
+  type Item = Result<(K, V)>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let decode = |kv: KeyValuePair| Ok((self.codec.decode(&kv.key)?, self.codec.decode(&kv.value)?));
+    Some(self.inner.next()?.and_then(decode))
+  }
+}
+
+/// Whether bincode encodes the bounds of `range` so that their bytes sort
+/// like the keys themselves. At least one bound has to be given, as the
+/// check looks at the values rather than the type.
+fn sorts_like_bytes<K: Serialize, R: RangeBounds<K>>(range: &R) -> bool {
+  let probe = |bound: Bound<&K>| match bound {
+    Bound::Included(key) | Bound::Excluded(key) => Some(key.serialize(OrderProbe).is_ok()),
+    Bound::Unbounded => None,
+  };
+  match (probe(range.start_bound()), probe(range.end_bound())) {
+    (None, None) => false,
+    (start, end) => start.unwrap_or(true) && end.unwrap_or(true),
+  }
+}
+
+/// A `Serializer` that fails on anything bincode does not write as fixed
+/// width, order-preserving bytes: it accepts `u8`s, `bool`s, units and
+/// tuples, arrays and structs of them.
+struct OrderProbe;
+
+#[derive(Debug)]
+struct Unordered;
+
+impl fmt::Display for Unordered {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "encoding does not sort like the value")
+  }
+}
+
+impl Error for Unordered {}
+
+impl ser::Error for Unordered {
+  fn custom<T: fmt::Display>(_: T) -> Self {
+    Unordered
+  }
+}
+
+impl Serializer for OrderProbe {
+  type Ok = ();
+  type Error = Unordered;
+  type SerializeSeq = Impossible<(), Unordered>;
+  type SerializeTuple = Self;
+  type SerializeTupleStruct = Self;
+  type SerializeTupleVariant = Impossible<(), Unordered>;
+  type SerializeMap = Impossible<(), Unordered>;
+  type SerializeStruct = Self;
+  type SerializeStructVariant = Impossible<(), Unordered>;
+
+  fn serialize_bool(self, _: bool) -> Result<(), Unordered> {
+    Ok(())
+  }
+
+  fn serialize_u8(self, _: u8) -> Result<(), Unordered> {
+    Ok(())
+  }
+
+  fn serialize_unit(self) -> Result<(), Unordered> {
+    Ok(())
+  }
+
+  fn serialize_unit_struct(self, _: &'static str) -> Result<(), Unordered> {
+    Ok(())
+  }
+
+  fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, value: &T) -> Result<(), Unordered> {
+    value.serialize(self)
+  }
+
+  fn serialize_tuple(self, _: usize) -> Result<Self, Unordered> {
+    Ok(self)
+  }
+
+  fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Self, Unordered> {
+    Ok(self)
+  }
+
+  fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self, Unordered> {
+    Ok(self)
+  }
+
+  // Integers are little-endian, and the rest carry a length or a variant
+  // index in front of them
+  fn serialize_i8(self, _: i8) -> Result<(), Unordered> {
+    Err(Unordered)
+  }
+
+  fn serialize_i16(self, _: i16) -> Result<(), Unordered> {
+    Err(Unordered)
+  }
+
+  fn serialize_i32(self, _: i32) -> Result<(), Unordered> {
+    Err(Unordered)
+  }
+
+  fn serialize_i64(self, _: i64) -> Result<(), Unordered> {
+    Err(Unordered)
+  }
+
+  fn serialize_u16(self, _: u16) -> Result<(), Unordered> {
+    Err(Unordered)
+  }
+
+  fn serialize_u32(self, _: u32) -> Result<(), Unordered> {
+    Err(Unordered)
+  }
+
+  fn serialize_u64(self, _: u64) -> Result<(), Unordered> {
+    Err(Unordered)
+  }
+
+  fn serialize_f32(self, _: f32) -> Result<(), Unordered> {
+    Err(Unordered)
+  }
+
+  fn serialize_f64(self, _: f64) -> Result<(), Unordered> {
+    Err(Unordered)
+  }
+
+  fn serialize_char(self, _: char) -> Result<(), Unordered> {
+    Err(Unordered)
+  }
+
+  fn serialize_str(self, _: &str) -> Result<(), Unordered> {
+    Err(Unordered)
+  }
+
+  fn serialize_bytes(self, _: &[u8]) -> Result<(), Unordered> {
+    Err(Unordered)
+  }
+
+  fn serialize_none(self) -> Result<(), Unordered> {
+    Err(Unordered)
+  }
+
+  fn serialize_some<T: Serialize + ?Sized>(self, _: &T) -> Result<(), Unordered> {
+    Err(Unordered)
+  }
+
+  fn serialize_unit_variant(self, _: &'static str, _: u32, _: &'static str) -> Result<(), Unordered> {
+    Err(Unordered)
+  }
+
+  fn serialize_newtype_variant<T: Serialize + ?Sized>(
+    self,
+    _: &'static str,
+    _: u32,
+    _: &'static str,
+    _: &T,
+  ) -> Result<(), Unordered> {
+    Err(Unordered)
+  }
+
+  fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, Unordered> {
+    Err(Unordered)
+  }
+
+  fn serialize_tuple_variant(
+    self,
+    _: &'static str,
+    _: u32,
+    _: &'static str,
+    _: usize,
+  ) -> Result<Self::SerializeTupleVariant, Unordered> {
+    Err(Unordered)
+  }
+
+  fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, Unordered> {
+    Err(Unordered)
+  }
+
+  fn serialize_struct_variant(
+    self,
+    _: &'static str,
+    _: u32,
+    _: &'static str,
+    _: usize,
+  ) -> Result<Self::SerializeStructVariant, Unordered> {
+    Err(Unordered)
+  }
+}
+
+impl ser::SerializeTuple for OrderProbe {
+  type Ok = ();
+  type Error = Unordered;
+
+  fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Unordered> {
+    value.serialize(OrderProbe)
+  }
+
+  fn end(self) -> Result<(), Unordered> {
+    Ok(())
+  }
+}
+
+impl ser::SerializeTupleStruct for OrderProbe {
+  type Ok = ();
+  type Error = Unordered;
+
+  fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Unordered> {
+    value.serialize(OrderProbe)
+  }
+
+  fn end(self) -> Result<(), Unordered> {
+    Ok(())
+  }
+}
+
+impl ser::SerializeStruct for OrderProbe {
+  type Ok = ();
+  type Error = Unordered;
+
+  fn serialize_field<T: Serialize + ?Sized>(&mut self, _: &'static str, value: &T) -> Result<(), Unordered> {
+    value.serialize(OrderProbe)
+  }
+
+  fn end(self) -> Result<(), Unordered> {
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{MemoryStorage, OpenOptions};
+
+  fn store<K: Serialize + Ord>(ordered: bool) -> TypedStore<K, String> {
+    let mut store = OpenOptions::new().ordered(ordered).open_storage(MemoryStorage::new()).unwrap();
+    store.load().unwrap();
+    TypedStore::new(store, Codec::Bincode)
+  }
+
+  #[test]
+  fn probe() {
+    assert!(sorts_like_bytes(&(([0u8, 1], true)..)));
+    assert!(sorts_like_bytes(&(..=(7u8, [1u8; 4]))));
+    assert!(!sorts_like_bytes::<u8, _>(&(..)));
+    assert!(!sorts_like_bytes(&(1u64..2)));
+    assert!(!sorts_like_bytes(&("a".to_string()..)));
+    assert!(!sorts_like_bytes(&(Some(1u8)..)));
+  }
+
+  #[test]
+  fn range_sorts_by_key() {
+    // Little-endian integers: 256 encodes below 1
+    for ordered in [false, true] {
+      let mut store = store::<u64>(ordered);
+      for key in [1u64, 255, 256, 70_000, 5] {
+        store.put(&key, &key.to_string()).unwrap();
+      }
+      let keys: Vec<u64> = store.range(2..=256).unwrap().into_iter().map(|(key, _)| key).collect();
+      assert_eq!(keys, vec![5, 255, 256]);
+    }
+  }
+
+  #[test]
+  fn range_scans_byte_keys() {
+    for ordered in [false, true] {
+      let mut store = store::<[u8; 2]>(ordered);
+      for key in [[2, 0], [0, 9], [1, 255], [1, 0], [3, 3]] {
+        store.put(&key, &format!("{:?}", key)).unwrap();
+      }
+
+      // Only read by the fallback, which decodes every pair
+      store.store_mut().insert(&[9], b"not a pair").unwrap();
+      if !ordered {
+        assert!(matches!(store.range([1, 0]..[3, 0]), Err(ActionKvError::Codec(_))));
+        store.store_mut().delete(&[9]).unwrap();
+      }
+
+      let pairs = store.range([1, 0]..[3, 0]).unwrap();
+      let keys: Vec<[u8; 2]> = pairs.iter().map(|(key, _)| *key).collect();
+      assert_eq!(keys, vec![[1, 0], [1, 255], [2, 0]]);
+      assert_eq!(pairs[1].1, "[1, 255]");
+    }
+  }
+}
@@ -0,0 +1,408 @@
+//! Following the log as it is written.
+//!
+//! A `Watch` reads the store's files on its own, from a given position
+//! onwards, and turns each insert or delete into a `Change`. When it
+//! catches up with the end of the log it waits for more to be appended,
+//! so it can run in another thread, or another process, from the one
+//! writing to the store.
+//!
+//! Compaction removes history that a watch may not have read yet. A watch
+//! that finds its place in the log has been compacted away returns a
+//! `Compacted` error; it has to be restarted from `first_position()`, and
+//! anything built from its changes rebuilt.
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::encryption::{self, Cipher};
+use crate::record::{self, Record, RecordKind};
+use crate::segment::{self, Segment};
+use crate::{ActionKV, ByteStr, ByteString, Position, Result};
+
+/// How often a watch that has caught up checks for new records, unless
+/// set with `Watch::poll_interval()`.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// An insert, or a delete if `value` is `None`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+  pub key: ByteString,
+  pub value: Option<ByteString>,
+
+  /// When the value expires, in milliseconds since the Unix epoch.
+  pub expires_at: Option<u64>,
+
+  /// Where the change was recorded.
+  pub position: Position,
+
+  /// Where to start a new watch so that it picks up after this change.
+  /// Changes from a batch all resume from the start of the batch, apart
+  /// from the last one.
+  pub resume: Position,
+}
+
+/// The records between a watch's position and the rest of the log were
+/// removed by compaction. Returned as `ActionKvError::Compacted`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compacted {
+  pub position: Position,
+}
+
+impl Compacted {
+  /// Returns the `Compacted` error carried by `err`, if there is one.
+  pub fn from_io_error(err: &io::Error) -> Option<&Compacted> {
+    err.get_ref().and_then(|inner| inner.downcast_ref::<Compacted>())
+  }
+
+  pub(crate) fn into_io_error(self) -> io::Error {
+    io::Error::other(self)
+  }
+}
+
+impl fmt::Display for Compacted {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "the log was compacted past offset {} of segment {}",
+      self.position.offset, self.position.segment
+    )
+  }
+}
+
+impl Error for Compacted {}
+
+/// A stream of changes to keys with a given prefix. See `ActionKV::watch()`.
+///
+/// As an `Iterator`, a watch blocks until the next change and never
+/// ends. `poll()` returns straight away instead.
+#[derive(Debug)]
+pub struct Watch {
+  /// The store's file or directory.
+  path: PathBuf,
+  segmented: bool,
+  cipher: Option<Cipher>,
+  prefix: ByteString,
+
+  segment: Segment,
+
+  /// The file `segment` was opened from, to tell whether it has since
+  /// been replaced.
+  opened: fs::Metadata,
+
+  /// Where the next unread record starts. Never inside a batch.
+  offset: u64,
+
+  ready: VecDeque<Change>,
+
+  /// Where to resume from to pick up after the last change returned.
+  resume: Position,
+
+  poll_interval: Duration,
+}
+
+impl ActionKV {
+  /// Watches for changes to keys that start with `prefix`, beginning
+  /// with the record at `from`. Use `first_position()` to replay the
+  /// whole log, or `seek_to_end()` to see only new changes.
+  ///
+  /// Batches are reported once they commit, and expired records are
+  /// reported like any other insert.
+  pub fn watch(&self, prefix: &ByteStr, from: Position) -> Result<Watch> {
+    self.check_log_engine("watch()")?;
+    let current = segment::lookup(&self.segments, from.segment)?;
+    if !current.has_path() {
+      return Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "a store opened on a Storage cannot be watched",
+      ).into());
+    }
+    let (segment, opened) = open_segment(current.id, &current.path)?;
+    let offset = from.offset.max(segment.data_start());
+    let resume = segment.position(offset);
+
+    Ok(Watch {
+      path: self.path.clone(),
+      segmented: self.is_segmented(),
+      cipher: self.cipher.clone(),
+      prefix: prefix.to_vec(),
+      segment,
+      opened,
+      offset,
+      ready: VecDeque::new(),
+      resume,
+      poll_interval: DEFAULT_POLL_INTERVAL,
+    })
+  }
+}
+
+impl Watch {
+  /// How long to wait between checks for new records once the watch has
+  /// caught up.
+  pub fn poll_interval(&mut self, interval: Duration) -> &mut Self {
+    self.poll_interval = interval;
+    self
+  }
+
+  /// Where a new watch should start to carry on from here.
+  pub fn checkpoint(&self) -> Position {
+    match self.ready.is_empty() {
+      true => self.segment.position(self.offset),
+      false => self.resume,
+    }
+  }
+
+  /// The next change, or `None` if there is none yet.
+  pub fn poll(&mut self) -> Result<Option<Change>> {
+    if self.ready.is_empty() {
+      self.read()?;
+    }
+
+    let change = self.ready.pop_front();
+    if let Some(change) = &change {
+      self.resume = change.resume;
+    }
+    Ok(change)
+  }
+
+  /// Reads whatever has been appended since the last call.
+  fn read(&mut self) -> io::Result<()> {
+    loop {
+      let found = self.read_segment()?;
+      if found > 0 || !self.next_segment()? {
+        return Ok(());
+      }
+    }
+  }
+
+  /// Reads the rest of the current segment, returning how many changes
+  /// it found.
+  fn read_segment(&mut self) -> io::Result<usize> {
+    let id = self.segment.id;
+    let cipher = self.cipher.as_ref();
+    let prefix = &self.prefix;
+    let ready = &mut self.ready;
+    let mut offset = self.offset;
+    let mut batch: Vec<(u64, Record)> = Vec::new();
+    let mut found = 0;
+
+    self.segment.read_from(self.offset, |at, next, record| {
+      if record.is_batched() {
+        batch.push((at, record));
+        return Ok(());
+      }
+
+      let committed = match record.kind {
+        RecordKind::Commit => {
+          // As in `Segment::scan()`, anything before `start` belongs to
+          // an earlier batch that was never committed
+          let (start, count) = record::decode_commit(&record.value)?;
+          batch.retain(|(at, _)| *at >= start);
+          match batch.len() == count as usize {
+            true => std::mem::take(&mut batch),
+            false => Vec::new(),
+          }
+        },
+        RecordKind::Meta => Vec::new(),
+        _ => vec![(at, record)],
+      };
+      batch.clear();
+
+      let resume = Position { segment: id, offset };
+      let last = committed.len();
+      for (i, (at, record)) in committed.into_iter().enumerate() {
+        let position = Position { segment: id, offset: at };
+        let record = encryption::unseal(cipher, position, record)?;
+        if !record.key.starts_with(prefix) {
+          continue;
+        }
+        let resume = match i + 1 == last {
+          true => Position { segment: id, offset: next },
+          false => resume,
+        };
+        ready.push_back(Change::new(record, position, resume)?);
+        found += 1;
+      }
+
+      offset = next;
+      Ok(())
+    })?;
+
+    self.offset = offset;
+    Ok(found)
+  }
+
+  /// Moves on to the next segment once the current one has been sealed
+  /// and read to its end. Returns whether it did.
+  fn next_segment(&mut self) -> io::Result<bool> {
+    if !self.segmented {
+      // Compacting or repairing a single file replaces it
+      return match is_replaced(&self.segment.path, &self.opened)? {
+        true => Err(Compacted { position: self.checkpoint() }.into_io_error()),
+        false => Ok(false),
+      };
+    }
+
+    let id = self.segment.id + 1;
+    let path = segment::segment_path(&self.path, id);
+    let (next, opened) = match open_segment(id, &path) {
+      Ok(next) => next,
+      Err(err) if err.kind() == io::ErrorKind::NotFound => {
+        // Segment ids only skip where compaction merged segments away
+        return match newer_segment_exists(&self.path, id)? {
+          true => Err(Compacted { position: self.checkpoint() }.into_io_error()),
+          false => Ok(false),
+        };
+      },
+      Err(err) => return Err(err),
+    };
+    if next.merged_from()?.is_some_and(|first| first < id) {
+      return Err(Compacted { position: self.checkpoint() }.into_io_error());
+    }
+
+    // Records may have been appended to the current segment between the
+    // last read and it being sealed
+    if self.read_segment()? > 0 {
+      return Ok(true);
+    }
+
+    self.offset = next.data_start();
+    self.resume = next.position(self.offset);
+    self.segment = next;
+    self.opened = opened;
+    Ok(true)
+  }
+
+  /// `checkpoint()`, along with a digest of the bytes before it. A
+  /// digest that no longer matches the log shows that the log was
+  /// rewritten since the checkpoint was taken.
+  pub(crate) fn checkpoint_digest(&self) -> io::Result<(Position, u32)> {
+    let checkpoint = self.checkpoint();
+    let digest = self.segment.tail_digest(checkpoint.offset)?;
+    Ok((checkpoint, digest))
+  }
+}
+
+impl Iterator for Watch {
+  type Item = Result<Change>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    loop {
+      match self.poll() {
+        Ok(Some(change)) => return Some(Ok(change)),
+        Ok(None) => thread::sleep(self.poll_interval),
+        Err(err) => return Some(Err(err)),
+      }
+    }
+  }
+}
+
+impl Change {
+  fn new(record: Record, position: Position, resume: Position) -> io::Result<Self> {
+    let expires_at = record.expires_at;
+    let deleted = record.kind == RecordKind::Tombstone;
+    let kv = record.into_pair()?;
+
+    Ok(Change {
+      key: kv.key,
+      value: (!deleted).then_some(kv.value),
+      expires_at,
+      position,
+      resume,
+    })
+  }
+}
+
+fn open_segment(id: u32, path: &Path) -> io::Result<(Segment, fs::Metadata)> {
+  let f = File::open(path)?;
+  let opened = f.metadata()?;
+  Ok((Segment::from_file(id, path, f, false)?, opened))
+}
+
+fn newer_segment_exists(dir: &Path, id: u32) -> io::Result<bool> {
+  for entry in fs::read_dir(dir)? {
+    if segment::segment_id(&entry?.path()).is_some_and(|other| other > id) {
+      return Ok(true);
+    }
+  }
+  Ok(false)
+}
+
+/// Whether the file at `path` is no longer the one that was `opened`.
+#[cfg(unix)]
+fn is_replaced(path: &Path, open: &fs::Metadata) -> io::Result<bool> {
+  use std::os::unix::fs::MetadataExt;
+
+  match fs::metadata(path) {
+    Ok(current) => Ok(current.dev() != open.dev() || current.ino() != open.ino()),
+    Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(true),
+    Err(err) => Err(err),
+  }
+}
+
+// Windows does not let an open file be replaced
+#[cfg(not(unix))]
+fn is_replaced(_path: &Path, _open: &fs::Metadata) -> io::Result<bool> {
+  Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use super::Watch;
+  use crate::{ByteStr, OpenOptions, WriteBatch};
+
+  fn drain(watch: &mut Watch) -> Vec<(String, Option<String>)> {
+    let mut changes = Vec::new();
+    while let Some(change) = watch.poll().unwrap() {
+      let text = |bytes: &ByteStr| String::from_utf8(bytes.to_vec()).unwrap();
+      changes.push((text(&change.key), change.value.as_deref().map(text)));
+    }
+    changes
+  }
+
+  fn change(key: &str, value: Option<&str>) -> (String, Option<String>) {
+    (key.to_string(), value.map(str::to_string))
+  }
+
+  #[test]
+  fn watch_sees_inserts_deletes_and_batches() {
+    let dir = std::env::temp_dir().join(format!("actionkv-watch-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("store");
+
+    let mut store = OpenOptions::new().open(&path).unwrap();
+    store.load().unwrap();
+    store.insert(b"user:a", b"1").unwrap();
+    store.insert(b"other:x", b"1").unwrap();
+    store.delete(b"user:a").unwrap();
+
+    let mut watch = store.watch(b"user:", store.first_position()).unwrap();
+    assert_eq!(drain(&mut watch), [change("user:a", Some("1")), change("user:a", None)]);
+
+    // Changes written after the watch started are picked up too, and a
+    // batch arrives whole once it commits
+    let mut batch = WriteBatch::new();
+    batch.insert(b"user:b", b"2").delete(b"other:x").insert(b"user:c", b"3");
+    store.write_batch(&batch).unwrap();
+    assert_eq!(drain(&mut watch), [change("user:b", Some("2")), change("user:c", Some("3"))]);
+
+    // A watch started from the checkpoint carries on where this one left off
+    store.delete(b"user:b").unwrap();
+    let mut resumed = store.watch(b"user:", watch.checkpoint()).unwrap();
+    assert_eq!(drain(&mut resumed), [change("user:b", None)]);
+    assert_eq!(drain(&mut watch), [change("user:b", None)]);
+
+    drop(store);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}